@@ -10,12 +10,49 @@ CREATE TABLE devices (
 -- Create index on device_id for fast lookups
 CREATE INDEX idx_devices_device_id ON devices(device_id);
 
+-- Notify the server when a device's configuration changes, however it was changed. The API sets
+-- washing.apply_configuration to 'immediately' or 'next_cycle' for the transaction; without it
+-- the change is applied immediately.
+CREATE FUNCTION notify_device_configuration() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('device_configuration', json_strip_nulls(json_build_object(
+        'device_id', RTRIM(NEW.device_id),
+        'apply', NULLIF(current_setting('washing.apply_configuration', true), '')
+    ))::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER device_configuration_changed
+    AFTER UPDATE OF configuration ON devices
+    FOR EACH ROW
+    WHEN (OLD.configuration IS DISTINCT FROM NEW.configuration)
+    EXECUTE FUNCTION notify_device_configuration();
+
+-- Create cycles table (one row per load of washing, opened when the predictor starts a new cycle)
+CREATE TABLE cycles (
+    id BIGSERIAL PRIMARY KEY,
+    device_id VARCHAR(8) NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    ended_at TIMESTAMPTZ,
+    end_reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_cycle_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create index on device_id and started_at for efficient queries
+CREATE INDEX idx_cycles_device_started_at ON cycles(device_id, started_at DESC);
+
 -- Create telemetry table
 CREATE TABLE telemetry (
     id BIGSERIAL PRIMARY KEY,
     timestamp TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     device_id VARCHAR(8) NOT NULL,
     payload JSONB NOT NULL,
+    cycle_id BIGINT REFERENCES cycles(id) ON DELETE SET NULL,
     CONSTRAINT fk_device
         FOREIGN KEY(device_id) 
         REFERENCES devices(device_id)
@@ -23,4 +60,125 @@ CREATE TABLE telemetry (
 );
 
 -- Create index on device_id and timestamp for efficient queries
-CREATE INDEX idx_telemetry_device_timestamp ON telemetry(device_id, timestamp DESC);
\ No newline at end of file
+CREATE INDEX idx_telemetry_device_timestamp ON telemetry(device_id, timestamp DESC);
+-- Create scheduled alerts table (the next pending completion alert of each device's cycle)
+CREATE TABLE scheduled_alerts (
+    device_id VARCHAR(8) PRIMARY KEY,
+    stage_name TEXT NOT NULL,
+    cycle_start TIMESTAMPTZ NOT NULL,
+    completion_time TIMESTAMPTZ NOT NULL,
+    completion_p10 TIMESTAMPTZ NOT NULL,
+    completion_p90 TIMESTAMPTZ NOT NULL,
+    model TEXT NOT NULL DEFAULT 'ekf',
+    fire_at TIMESTAMPTZ NOT NULL,
+    CONSTRAINT fk_scheduled_alert_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create index on fire_at so the scheduler can find due alerts quickly
+CREATE INDEX idx_scheduled_alerts_fire_at ON scheduled_alerts(fire_at);
+
+-- Create alert history table (every notification sent, with the model whose estimate drove it)
+CREATE TABLE alert_history (
+    id BIGSERIAL PRIMARY KEY,
+    device_id VARCHAR(8) NOT NULL,
+    stage_name TEXT NOT NULL,
+    title TEXT NOT NULL,
+    cycle_start TIMESTAMPTZ NOT NULL,
+    completion_time TIMESTAMPTZ NOT NULL,
+    completion_p10 TIMESTAMPTZ NOT NULL,
+    completion_p90 TIMESTAMPTZ NOT NULL,
+    model TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    CONSTRAINT fk_alert_history_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create index on device_id and sent_at for efficient queries
+CREATE INDEX idx_alert_history_device_sent_at ON alert_history(device_id, sent_at DESC);
+
+-- Create EKF state table (each device's filter, saved after every reading so a restart can carry on mid-cycle)
+CREATE TABLE ekf_states (
+    device_id VARCHAR(8) PRIMARY KEY,
+    state DOUBLE PRECISION[] NOT NULL,
+    covariance DOUBLE PRECISION[] NOT NULL,
+    start_time TIMESTAMPTZ NOT NULL,
+    last_received_time TIMESTAMPTZ NOT NULL,
+    update_count INTEGER NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_ekf_state_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create predictions table (every completion estimate, for looking back at how it changed during a cycle)
+CREATE TABLE predictions (
+    id BIGSERIAL PRIMARY KEY,
+    device_id VARCHAR(8) NOT NULL,
+    reading_time TIMESTAMPTZ NOT NULL,
+    resistance DOUBLE PRECISION NOT NULL,
+    cycle_start TIMESTAMPTZ NOT NULL,
+    event TEXT NOT NULL,
+    model TEXT NOT NULL DEFAULT 'ekf',
+    completion_time TIMESTAMPTZ NOT NULL,
+    completion_p10 TIMESTAMPTZ NOT NULL,
+    completion_p90 TIMESTAMPTZ NOT NULL,
+    state DOUBLE PRECISION[] NOT NULL,
+    covariance_diagonal DOUBLE PRECISION[] NOT NULL,
+    cycle_id BIGINT REFERENCES cycles(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_prediction_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create index on device_id and reading_time for efficient queries
+CREATE INDEX idx_predictions_device_reading_time ON predictions(device_id, reading_time DESC);
+
+-- Create cycle accuracy table (how far off the predictions of a finished cycle were)
+CREATE TABLE cycle_accuracy (
+    cycle_id BIGINT PRIMARY KEY REFERENCES cycles(id) ON DELETE CASCADE,
+    device_id VARCHAR(8) NOT NULL,
+    actual_completion_time TIMESTAMPTZ,
+    prediction_count INTEGER NOT NULL,
+    mae_minutes DOUBLE PRECISION,
+    bias_minutes DOUBLE PRECISION,
+    evaluated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_cycle_accuracy_device
+        FOREIGN KEY(device_id)
+        REFERENCES devices(device_id)
+        ON DELETE CASCADE
+);
+
+-- Create index on device_id for the per-device accuracy report
+CREATE INDEX idx_cycle_accuracy_device ON cycle_accuracy(device_id);
+
+-- Create cycle accuracy horizons table (the error of the predictions made 60, 30 and 10 minutes before the washing was dry)
+CREATE TABLE cycle_accuracy_horizons (
+    cycle_id BIGINT NOT NULL REFERENCES cycle_accuracy(cycle_id) ON DELETE CASCADE,
+    horizon_minutes INTEGER NOT NULL,
+    mae_minutes DOUBLE PRECISION NOT NULL,
+    bias_minutes DOUBLE PRECISION NOT NULL,
+    samples INTEGER NOT NULL,
+    PRIMARY KEY (cycle_id, horizon_minutes)
+);
+
+-- Create model presets table (named EKF parameter sets that devices can reference by name)
+CREATE TABLE model_presets (
+    name TEXT PRIMARY KEY,
+    description TEXT NOT NULL DEFAULT '',
+    parameters JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Built-in presets. Devices without a "preset" in their configuration use "default".
+INSERT INTO model_presets (name, description, parameters) VALUES
+    ('default', 'Mixed load of everyday clothes', '{"initial_state": [30000.0, 0.02, 0.1, 0.81, 1e-09, 29976.33], "initial_covariance": [10.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-10, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "process_noise_covariance": [0.01, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-12, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-08, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-07, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "measurement_noise_covariance": [1000000.0], "dt": 2.0}'),
+    ('towels', 'Towels and other heavy fabrics: holds more water and dries slowly', '{"initial_state": [30000.0, 0.04, 0.05, 0.81, 1e-09, 29976.33], "initial_covariance": [10.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 4e-10, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "process_noise_covariance": [0.01, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-12, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-08, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-07, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "measurement_noise_covariance": [1000000.0], "dt": 2.0}'),
+    ('light cotton', 'Shirts, sheets and other light cotton: holds less water and dries quickly', '{"initial_state": [30000.0, 0.015, 0.14, 0.81, 1e-09, 29976.33], "initial_covariance": [10.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-10, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-06, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "process_noise_covariance": [0.01, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-12, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-08, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1e-07, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0], "measurement_noise_covariance": [1000000.0], "dt": 2.0}');
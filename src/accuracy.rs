@@ -0,0 +1,622 @@
+//! Post-hoc evaluation of the completion predictions.
+//!
+//! While a load is drying we only have the filter's opinion of when it will be dry. Once the
+//! cycle is over the stored readings show when it actually was: the resistance levels off once
+//! the washing is dry, so the start of the plateau the cycle ends on (found with
+//! `trigger_algorithms::is_stable_resistance`) is taken as the actual completion time.
+//!
+//! Every prediction linked to the cycle is then compared against that time. The error is
+//! `predicted - actual` in minutes, so a positive bias means the washing was dry earlier than
+//! predicted. Besides the overall figures, the predictions made roughly 60, 30 and 10 minutes
+//! before the actual completion are summarised separately, since those are the ones the alerts
+//! are based on. The results are stored in `cycle_accuracy` and `cycle_accuracy_horizons`.
+//!
+//! A cycle is evaluated once it is closed and either a newer cycle has started or
+//! `SETTLE_HOURS` have passed, so the readings of the plateau after it dried are in.
+//!
+//! `compare_models` answers a different question: how each drying model would have done on a
+//! cycle. The cycle's stored readings are replayed through a throwaway predictor per model, with
+//! the device's parameters, and the replayed predictions are scored the same way.
+
+use crate::cycles::Cycle;
+use crate::drying_model::ModelKind;
+use crate::trigger_algorithms::is_stable_resistance;
+use crate::washing_predictor::{
+    DeviceRepository, EKFParameters, PostgresDeviceRepository, PredictionRecord, PredictorError, StoredFilterState,
+    telemetry_from_row, TelemetryData, WashingPredictor,
+};
+use chrono::{DateTime, Duration, Utc};
+use rocket_db_pools::sqlx::{self, PgPool, Row};
+use std::sync::Arc;
+
+/// How far before the actual completion the horizon metrics are taken, in minutes.
+pub const HORIZONS_MINUTES: [i64; 3] = [60, 30, 10];
+/// A prediction counts towards a horizon if it was made within this many minutes of it.
+const HORIZON_TOLERANCE_MINUTES: f64 = 5.0;
+/// Number of readings the plateau test looks at in one go.
+pub(crate) const PLATEAU_WINDOW: usize = 10;
+/// Largest relative change per reading that still counts as a plateau.
+pub(crate) const PLATEAU_THRESHOLD: f64 = 0.002;
+/// How long after a cycle closed before it is evaluated without a newer cycle.
+const SETTLE_HOURS: i64 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccuracyError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    Predictor(#[from] PredictorError),
+}
+
+/// A prediction made during a cycle, reduced to what the evaluation needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CyclePrediction {
+    pub reading_time: DateTime<Utc>,
+    pub completion_time: DateTime<Utc>,
+}
+
+/// Error summary of the predictions made around one horizon.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct HorizonError {
+    pub horizon_minutes: i64,
+    pub mae_minutes: f64,
+    pub bias_minutes: f64,
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct CycleEvaluation {
+    pub cycle_id: i64,
+    pub device_id: String,
+    /// `None` if the readings never levelled off, e.g. the washing was taken in while damp.
+    pub actual_completion_time: Option<DateTime<Utc>>,
+    /// Number of predictions made before the actual completion.
+    pub prediction_count: usize,
+    pub mae_minutes: Option<f64>,
+    pub bias_minutes: Option<f64>,
+    pub horizons: Vec<HorizonError>,
+}
+
+/// How one drying model would have done on a finished cycle, from replaying its readings.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ModelComparison {
+    pub model: ModelKind,
+    /// Number of replayed predictions made before the actual completion.
+    pub prediction_count: usize,
+    pub mae_minutes: Option<f64>,
+    pub bias_minutes: Option<f64>,
+    pub horizons: Vec<HorizonError>,
+}
+
+/// Finds when the washing actually became dry: the first reading of the plateau the readings
+/// end on. Returns `None` if the last `PLATEAU_WINDOW` readings are not stable.
+pub fn find_actual_completion(readings: &[TelemetryData]) -> Option<DateTime<Utc>> {
+    let resistances: Vec<f64> = readings.iter().map(|reading| reading.resistance).collect();
+    let windows: Vec<&[f64]> = resistances.windows(PLATEAU_WINDOW).collect();
+
+    // Walk back from the end while the windows are still stable.
+    let plateau_start = windows
+        .iter()
+        .rposition(|window| !is_stable_resistance(window, PLATEAU_THRESHOLD))
+        .map_or(0, |unstable| unstable + 1);
+    if plateau_start >= windows.len() {
+        return None;
+    }
+    Some(readings[plateau_start].timestamp)
+}
+
+fn minutes_between(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_milliseconds() as f64 / 60_000.0
+}
+
+/// Compares the predictions with the actual completion time. Predictions made after the
+/// washing was already dry are ignored.
+pub fn evaluate_predictions(
+    predictions: &[CyclePrediction],
+    actual: DateTime<Utc>,
+) -> (usize, Option<(f64, f64)>, Vec<HorizonError>) {
+    let samples: Vec<(f64, f64)> = predictions
+        .iter()
+        .filter(|prediction| prediction.reading_time <= actual)
+        .map(|prediction| {
+            let lead = minutes_between(prediction.reading_time, actual);
+            let error = minutes_between(actual, prediction.completion_time);
+            (lead, error)
+        })
+        .collect();
+
+    let summarise = |errors: &[f64]| -> Option<(f64, f64)> {
+        if errors.is_empty() {
+            return None;
+        }
+        let n = errors.len() as f64;
+        let mae = errors.iter().map(|error| error.abs()).sum::<f64>() / n;
+        let bias = errors.iter().sum::<f64>() / n;
+        Some((mae, bias))
+    };
+
+    let all: Vec<f64> = samples.iter().map(|(_, error)| *error).collect();
+    let horizons = HORIZONS_MINUTES
+        .iter()
+        .filter_map(|&horizon_minutes| {
+            let errors: Vec<f64> = samples
+                .iter()
+                .filter(|(lead, _)| (lead - horizon_minutes as f64).abs() <= HORIZON_TOLERANCE_MINUTES)
+                .map(|(_, error)| *error)
+                .collect();
+            summarise(&errors).map(|(mae_minutes, bias_minutes)| HorizonError {
+                horizon_minutes,
+                mae_minutes,
+                bias_minutes,
+                samples: errors.len(),
+            })
+        })
+        .collect();
+
+    (samples.len(), summarise(&all), horizons)
+}
+
+#[allow(async_fn_in_trait)]
+pub trait AccuracyRepository: Send + Sync {
+    /// Closed cycles without an evaluation that either have a newer cycle after them or were
+    /// closed before `settled_before`.
+    async fn get_finished_cycles(&self, settled_before: DateTime<Utc>) -> Result<Vec<Cycle>, AccuracyError>;
+    /// The cycle's resistance readings, oldest first.
+    async fn get_cycle_readings(&self, cycle_id: i64) -> Result<Vec<TelemetryData>, AccuracyError>;
+    /// The predictions made during the cycle, oldest first.
+    async fn get_cycle_predictions(&self, cycle_id: i64) -> Result<Vec<CyclePrediction>, AccuracyError>;
+    async fn save_evaluation(&self, evaluation: &CycleEvaluation) -> Result<(), AccuracyError>;
+    async fn get_cycle(&self, cycle_id: i64) -> Result<Option<Cycle>, AccuracyError>;
+    /// The parameters the device's predictor runs with, presets included.
+    async fn get_device_parameters(&self, device_id: &str) -> Result<EKFParameters, AccuracyError>;
+}
+
+pub struct PostgresAccuracyRepository {
+    pool: PgPool,
+    devices: PostgresDeviceRepository,
+}
+
+impl PostgresAccuracyRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            devices: PostgresDeviceRepository::new(pool.clone()),
+            pool,
+        }
+    }
+}
+
+impl AccuracyRepository for PostgresAccuracyRepository {
+    async fn get_cycle(&self, cycle_id: i64) -> Result<Option<Cycle>, AccuracyError> {
+        let row = sqlx::query(
+            "SELECT id, RTRIM(device_id) AS device_id, started_at, ended_at, end_reason FROM cycles WHERE id = $1",
+        )
+        .bind(cycle_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(Cycle::from_row).transpose()?)
+    }
+
+    async fn get_device_parameters(&self, device_id: &str) -> Result<EKFParameters, AccuracyError> {
+        Ok(self.devices.get_ekf_parameters(device_id).await?)
+    }
+
+    async fn get_finished_cycles(&self, settled_before: DateTime<Utc>) -> Result<Vec<Cycle>, AccuracyError> {
+        let rows = sqlx::query(
+            "SELECT c.id, RTRIM(c.device_id) AS device_id, c.started_at, c.ended_at, c.end_reason FROM cycles c
+            WHERE c.ended_at IS NOT NULL
+                AND NOT EXISTS (SELECT 1 FROM cycle_accuracy a WHERE a.cycle_id = c.id)
+                AND (c.ended_at <= $1
+                    OR EXISTS (SELECT 1 FROM cycles n WHERE n.device_id = c.device_id AND n.started_at > c.started_at))
+            ORDER BY c.ended_at ASC",
+        )
+        .bind(settled_before)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.iter().map(Cycle::from_row).collect::<Result<Vec<_>, _>>()?)
+    }
+
+    async fn get_cycle_readings(&self, cycle_id: i64) -> Result<Vec<TelemetryData>, AccuracyError> {
+        let rows = sqlx::query(
+            "SELECT timestamp, payload FROM telemetry
+            WHERE cycle_id = $1 AND payload ? 'resistance'",
+        )
+        .bind(cycle_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut readings = Vec::with_capacity(rows.len());
+        for row in &rows {
+            readings.extend(telemetry_from_row(row)?);
+        }
+        readings.sort_by_key(|reading| reading.timestamp);
+        Ok(readings)
+    }
+
+    async fn get_cycle_predictions(&self, cycle_id: i64) -> Result<Vec<CyclePrediction>, AccuracyError> {
+        let rows = sqlx::query(
+            "SELECT reading_time, completion_time FROM predictions
+            WHERE cycle_id = $1
+            ORDER BY reading_time ASC",
+        )
+        .bind(cycle_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(CyclePrediction {
+                    reading_time: row.try_get("reading_time")?,
+                    completion_time: row.try_get("completion_time")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn save_evaluation(&self, evaluation: &CycleEvaluation) -> Result<(), AccuracyError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO cycle_accuracy
+                (cycle_id, device_id, actual_completion_time, prediction_count, mae_minutes, bias_minutes)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (cycle_id) DO NOTHING",
+        )
+        .bind(evaluation.cycle_id)
+        .bind(&evaluation.device_id)
+        .bind(evaluation.actual_completion_time)
+        .bind(evaluation.prediction_count as i32)
+        .bind(evaluation.mae_minutes)
+        .bind(evaluation.bias_minutes)
+        .execute(&mut *tx)
+        .await?;
+
+        for horizon in &evaluation.horizons {
+            sqlx::query(
+                "INSERT INTO cycle_accuracy_horizons (cycle_id, horizon_minutes, mae_minutes, bias_minutes, samples)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (cycle_id, horizon_minutes) DO NOTHING",
+            )
+            .bind(evaluation.cycle_id)
+            .bind(horizon.horizon_minutes as i32)
+            .bind(horizon.mae_minutes)
+            .bind(horizon.bias_minutes)
+            .bind(horizon.samples as i32)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Stands in for the database while a cycle is replayed: every device gets the same parameters,
+/// there is nothing to restore from and nothing is written.
+struct ReplayRepository {
+    parameters: EKFParameters,
+}
+
+impl DeviceRepository for ReplayRepository {
+    async fn get_ekf_parameters(&self, _device_id: &str) -> Result<EKFParameters, PredictorError> {
+        Ok(self.parameters.clone())
+    }
+
+    async fn load_filter_state(&self, _device_id: &str) -> Result<Option<StoredFilterState>, PredictorError> {
+        Ok(None)
+    }
+
+    async fn save_filter_state(&self, _device_id: &str, _state: &StoredFilterState) -> Result<(), PredictorError> {
+        Ok(())
+    }
+
+    async fn delete_filter_state(&self, _device_id: &str) -> Result<(), PredictorError> {
+        Ok(())
+    }
+
+    async fn get_cycle_telemetry(&self, _device_id: &str, _before: DateTime<Utc>) -> Result<Vec<TelemetryData>, PredictorError> {
+        Ok(Vec::new())
+    }
+
+    async fn record_prediction(&self, _record: &PredictionRecord) -> Result<(), PredictorError> {
+        Ok(())
+    }
+}
+
+/// Feeds the readings through a fresh predictor running `parameters` and returns the predictions
+/// it made. Readings the model has no estimate for are skipped, as they would be live.
+pub async fn replay_predictions(
+    device_id: &str,
+    parameters: EKFParameters,
+    readings: &[TelemetryData],
+) -> Vec<CyclePrediction> {
+    let predictor = WashingPredictor::new(ReplayRepository { parameters });
+    let mut predictions = Vec::with_capacity(readings.len());
+    for reading in readings {
+        if let Ok(prediction) = predictor.predict_drying_time(device_id, reading.clone()).await {
+            predictions.push(CyclePrediction {
+                reading_time: reading.timestamp,
+                completion_time: prediction.estimate.completion_time,
+            });
+        }
+    }
+    predictions
+}
+
+pub struct AccuracyEvaluator<E: AccuracyRepository> {
+    repo: E,
+}
+
+impl<E: AccuracyRepository> AccuracyEvaluator<E> {
+    pub fn new(repo: E) -> Self {
+        AccuracyEvaluator { repo }
+    }
+
+    /// Works out the actual completion time of a cycle and the error of its predictions.
+    pub async fn evaluate_cycle(&self, cycle: &Cycle) -> Result<CycleEvaluation, AccuracyError> {
+        let readings = self.repo.get_cycle_readings(cycle.id).await?;
+        let actual_completion_time = find_actual_completion(&readings);
+
+        let mut evaluation = CycleEvaluation {
+            cycle_id: cycle.id,
+            device_id: cycle.device_id.clone(),
+            actual_completion_time,
+            prediction_count: 0,
+            mae_minutes: None,
+            bias_minutes: None,
+            horizons: Vec::new(),
+        };
+        if let Some(actual) = actual_completion_time {
+            let predictions = self.repo.get_cycle_predictions(cycle.id).await?;
+            let (count, overall, horizons) = evaluate_predictions(&predictions, actual);
+            evaluation.prediction_count = count;
+            evaluation.mae_minutes = overall.map(|(mae, _)| mae);
+            evaluation.bias_minutes = overall.map(|(_, bias)| bias);
+            evaluation.horizons = horizons;
+        }
+        Ok(evaluation)
+    }
+
+    /// Replays a cycle through each of `models` with the device's parameters and scores the
+    /// predictions against the actual completion time. Returns `None` for an unknown cycle and
+    /// an empty list if the readings never levelled off.
+    pub async fn compare_models(
+        &self,
+        cycle_id: i64,
+        models: &[ModelKind],
+    ) -> Result<Option<Vec<ModelComparison>>, AccuracyError> {
+        let Some(cycle) = self.repo.get_cycle(cycle_id).await? else {
+            return Ok(None);
+        };
+        let readings = self.repo.get_cycle_readings(cycle.id).await?;
+        let Some(actual) = find_actual_completion(&readings) else {
+            return Ok(Some(Vec::new()));
+        };
+        let parameters = self.repo.get_device_parameters(&cycle.device_id).await?;
+
+        let mut comparisons = Vec::with_capacity(models.len());
+        for &model in models {
+            let parameters = EKFParameters { model, ..parameters.clone() };
+            let predictions = replay_predictions(&cycle.device_id, parameters, &readings).await;
+            let (prediction_count, overall, horizons) = evaluate_predictions(&predictions, actual);
+            comparisons.push(ModelComparison {
+                model,
+                prediction_count,
+                mae_minutes: overall.map(|(mae, _)| mae),
+                bias_minutes: overall.map(|(_, bias)| bias),
+                horizons,
+            });
+        }
+        Ok(Some(comparisons))
+    }
+
+    /// Evaluates and stores every finished cycle that has not been evaluated yet. A cycle that
+    /// fails is logged and retried on the next run. Returns the number of cycles evaluated.
+    pub async fn evaluate_finished_cycles(&self, now: DateTime<Utc>) -> Result<usize, AccuracyError> {
+        let cycles = self.repo.get_finished_cycles(now - Duration::hours(SETTLE_HOURS)).await?;
+
+        let mut evaluated = 0;
+        for cycle in cycles {
+            let result = match self.evaluate_cycle(&cycle).await {
+                Ok(evaluation) => self.repo.save_evaluation(&evaluation).await.map(|_| evaluation),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(evaluation) => {
+                    evaluated += 1;
+                    match (evaluation.actual_completion_time, evaluation.mae_minutes) {
+                        (Some(actual), Some(mae)) => println!(
+                            "Evaluated cycle {} for device {}: dry at {}, MAE {:.1} min over {} predictions",
+                            cycle.id, cycle.device_id, actual.to_rfc3339(), mae, evaluation.prediction_count
+                        ),
+                        _ => println!(
+                            "Evaluated cycle {} for device {}: no dry plateau or predictions to compare",
+                            cycle.id, cycle.device_id
+                        ),
+                    }
+                }
+                Err(e) => eprintln!("[accuracy] Failed to evaluate cycle {} for device {}: {e}", cycle.id, cycle.device_id),
+            }
+        }
+        Ok(evaluated)
+    }
+
+    /// Runs the evaluation loop forever, looking for finished cycles every `period`.
+    pub async fn run(self: Arc<Self>, period: std::time::Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.evaluate_finished_cycles(Utc::now()).await {
+                eprintln!("[accuracy] Failed to evaluate finished cycles: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cycles::CycleEndReason;
+    use crate::washing_predictor::test_parameters;
+    use std::sync::Mutex;
+
+    /// Resistance that rises to half of `plateau`, then jumps to it and stays there, one reading
+    /// every 2 minutes.
+    fn drying_readings(start: DateTime<Utc>, rising: usize, flat: usize, plateau: f64) -> Vec<TelemetryData> {
+        (0..rising + flat)
+            .map(|i| {
+                let resistance = if i < rising { 0.5 * plateau * (i + 1) as f64 / rising as f64 } else { plateau };
+                TelemetryData { timestamp: start + Duration::minutes(2 * i as i64), resistance }
+            })
+            .collect()
+    }
+
+    struct MockAccuracyRepository {
+        cycles: Vec<Cycle>,
+        readings: Vec<TelemetryData>,
+        predictions: Vec<CyclePrediction>,
+        saved: Mutex<Vec<CycleEvaluation>>,
+    }
+
+    impl AccuracyRepository for MockAccuracyRepository {
+        async fn get_finished_cycles(&self, _settled_before: DateTime<Utc>) -> Result<Vec<Cycle>, AccuracyError> {
+            let saved = self.saved.lock().unwrap();
+            Ok(self
+                .cycles
+                .iter()
+                .filter(|cycle| !saved.iter().any(|evaluation| evaluation.cycle_id == cycle.id))
+                .cloned()
+                .collect())
+        }
+
+        async fn get_cycle_readings(&self, _cycle_id: i64) -> Result<Vec<TelemetryData>, AccuracyError> {
+            Ok(self.readings.clone())
+        }
+
+        async fn get_cycle_predictions(&self, _cycle_id: i64) -> Result<Vec<CyclePrediction>, AccuracyError> {
+            Ok(self.predictions.clone())
+        }
+
+        async fn save_evaluation(&self, evaluation: &CycleEvaluation) -> Result<(), AccuracyError> {
+            self.saved.lock().unwrap().push(evaluation.clone());
+            Ok(())
+        }
+
+        async fn get_cycle(&self, cycle_id: i64) -> Result<Option<Cycle>, AccuracyError> {
+            Ok(self.cycles.iter().find(|cycle| cycle.id == cycle_id).cloned())
+        }
+
+        async fn get_device_parameters(&self, _device_id: &str) -> Result<EKFParameters, AccuracyError> {
+            Ok(test_parameters(ModelKind::Ekf))
+        }
+    }
+
+    #[test]
+    fn test_actual_completion_is_start_of_final_plateau() {
+        let start = Utc::now();
+        let readings = drying_readings(start, 30, 20, 2.0e6);
+        assert_eq!(find_actual_completion(&readings), Some(start + Duration::minutes(60)));
+    }
+
+    #[test]
+    fn test_no_completion_without_plateau() {
+        let start = Utc::now();
+        // Still rising when the washing was taken in.
+        let readings = drying_readings(start, 40, 0, 2.0e6);
+        assert_eq!(find_actual_completion(&readings), None);
+        assert_eq!(find_actual_completion(&readings[..5]), None);
+    }
+
+    #[test]
+    fn test_horizon_errors() {
+        let actual = Utc::now();
+        let prediction = |lead: i64, error: i64| CyclePrediction {
+            reading_time: actual - Duration::minutes(lead),
+            completion_time: actual + Duration::minutes(error),
+        };
+        let predictions = [
+            prediction(62, 20),
+            prediction(58, -10),
+            prediction(30, 4),
+            prediction(10, -2),
+            prediction(45, 8),
+            // After the washing was dry, so not counted.
+            prediction(-5, 0),
+        ];
+
+        let (count, overall, horizons) = evaluate_predictions(&predictions, actual);
+        assert_eq!(count, 5);
+        let (mae, bias) = overall.unwrap();
+        assert!((mae - 44.0 / 5.0).abs() < 1e-9);
+        assert!((bias - 20.0 / 5.0).abs() < 1e-9);
+
+        assert_eq!(horizons.len(), 3);
+        assert_eq!(horizons[0], HorizonError { horizon_minutes: 60, mae_minutes: 15.0, bias_minutes: 5.0, samples: 2 });
+        assert_eq!(horizons[1], HorizonError { horizon_minutes: 30, mae_minutes: 4.0, bias_minutes: 4.0, samples: 1 });
+        assert_eq!(horizons[2], HorizonError { horizon_minutes: 10, mae_minutes: 2.0, bias_minutes: -2.0, samples: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_finished_cycles_are_evaluated_once() {
+        let start = Utc::now() - Duration::hours(4);
+        let actual = start + Duration::minutes(60);
+        let repo = MockAccuracyRepository {
+            cycles: vec![Cycle {
+                id: 7,
+                device_id: "wash-1".to_string(),
+                started_at: start,
+                ended_at: Some(start + Duration::minutes(90)),
+                end_reason: Some(CycleEndReason::Dry),
+            }],
+            readings: drying_readings(start, 30, 20, 2.0e6),
+            predictions: vec![CyclePrediction {
+                reading_time: actual - Duration::minutes(30),
+                completion_time: actual + Duration::minutes(6),
+            }],
+            saved: Mutex::new(Vec::new()),
+        };
+        let evaluator = AccuracyEvaluator::new(repo);
+
+        assert_eq!(evaluator.evaluate_finished_cycles(Utc::now()).await.unwrap(), 1);
+        assert_eq!(evaluator.evaluate_finished_cycles(Utc::now()).await.unwrap(), 0);
+
+        let saved = evaluator.repo.saved.lock().unwrap();
+        assert_eq!(saved[0].cycle_id, 7);
+        assert_eq!(saved[0].actual_completion_time, Some(actual));
+        assert_eq!(saved[0].bias_minutes, Some(6.0));
+        assert_eq!(saved[0].horizons.len(), 1);
+        assert_eq!(saved[0].horizons[0].horizon_minutes, 30);
+    }
+
+    #[tokio::test]
+    async fn test_compare_models_replays_each_model() {
+        let start = Utc::now() - Duration::hours(4);
+        let repo = MockAccuracyRepository {
+            cycles: vec![Cycle {
+                id: 7,
+                device_id: "wash-1".to_string(),
+                started_at: start,
+                ended_at: Some(start + Duration::minutes(90)),
+                end_reason: Some(CycleEndReason::Dry),
+            }],
+            readings: drying_readings(start, 30, 20, 2.0e6),
+            predictions: Vec::new(),
+            saved: Mutex::new(Vec::new()),
+        };
+        let evaluator = AccuracyEvaluator::new(repo);
+
+        assert_eq!(evaluator.compare_models(8, &ModelKind::ALL).await.unwrap(), None);
+
+        let comparisons = evaluator.compare_models(7, &ModelKind::ALL).await.unwrap().unwrap();
+        let models: Vec<ModelKind> = comparisons.iter().map(|comparison| comparison.model).collect();
+        assert_eq!(models, ModelKind::ALL);
+        let ekf = &comparisons[0];
+        assert!(ekf.prediction_count > 0 && ekf.mae_minutes.is_some(), "{ekf:?}");
+        // The plateau model only calls the washing dry once the plateau is a full window long.
+        let plateau = comparisons.iter().find(|comparison| comparison.model == ModelKind::Plateau).unwrap();
+        assert_eq!(plateau.prediction_count, 0);
+
+        // Nothing is stored by a comparison.
+        assert!(evaluator.repo.saved.lock().unwrap().is_empty());
+    }
+}
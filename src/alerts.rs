@@ -0,0 +1,920 @@
+//! Decides when completion alerts should be sent for a device.
+//!
+//! Every telemetry message produces a new completion estimate, so without any state the
+//! alerts would be sent for every message that arrives close to completion. The
+//! `AlertManager` remembers which alert stages have already fired for each device and only
+//! lets each stage through once per drying cycle. A cycle is identified by its start time as
+//! reported in the predictor's `Prediction`, so a new cycle (e.g. the >1e6 resistance jump
+//! reset) automatically re-arms every stage. Re-wetting (rain) sends its own urgent alert and
+//! also re-arms the stages, since the washing has to dry all over again.
+//!
+//! Stages are read from the `alert_stages` list in the device's `configuration` JSON:
+//!
+//! ```json
+//! "alert_stages": [
+//!     { "name": "30min", "title": "30 minutes left", "priority": "low", "lead_minutes": 30 },
+//!     { "name": "dry", "title": "Washing Complete :)", "priority": "high", "lead_minutes": 0 }
+//! ]
+//! ```
+//!
+//! Devices without `alert_stages` get a single "Washing Complete :)" stage 5 minutes out.
+//! The time of the most recent notification is written back to `devices.last_notification_at`.
+//! After a restart the stages already sent during the current cycle are read back from the
+//! alert history, so the remaining stages still fire and the sent ones are not repeated.
+//!
+//! The next pending stage of each cycle is also stored in the `scheduled_alerts` table.
+//! `run_scheduler` polls that table, so a stage still fires on time when the sensor goes quiet
+//! (e.g. the battery dies) or the server restarts between the last reading and completion.
+//!
+//! Every alert that is sent is recorded in the `alert_history` table together with the estimate
+//! it was sent on and the model that estimate came from (`CompletionEstimate::model`), so the
+//! alerts of an `ensemble` device can be traced back to the member that drove them.
+
+use crate::drying_model::ModelKind;
+use crate::notifier::{Notification, Notifier, NotifierError, Priority};
+use crate::washing_predictor::CompletionEstimate;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use rocket_db_pools::sqlx::{self, PgPool, Row};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Lead time of the stage used for devices that do not configure their own.
+const DEFAULT_COMPLETION_ALERT_LEAD_MINUTES: i64 = 5;
+/// Title of the alert sent when the washing gets wet again.
+const REWETTING_ALERT_TITLE: &str = "It's raining on your washing";
+/// Stage name the re-wetting alert is recorded under in the alert history.
+const REWETTING_STAGE_NAME: &str = "rewetting";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AlertError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("notification error: {0}")]
+    Notifier(#[from] NotifierError),
+
+    #[error("invalid alert stage configuration: {0}")]
+    InvalidConfiguration(String),
+}
+
+/// A single configured alert, fired when the predicted completion is `lead_minutes` away.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct AlertStage {
+    /// Identifies the stage within a cycle. Defaults to the title when omitted.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub title: String,
+    #[serde(default = "default_priority")]
+    pub priority: Priority,
+    pub lead_minutes: i64,
+}
+
+fn default_priority() -> Priority {
+    Priority::Default
+}
+
+impl AlertStage {
+    fn key(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.title)
+    }
+
+    /// How long before the predicted completion the stage fires. A lead time of 0 ("dry now")
+    /// is treated as one minute, so the alert is not held back by a few seconds of rounding.
+    fn lead(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.lead_minutes.max(1))
+    }
+
+    fn is_due(&self, remaining: chrono::Duration) -> bool {
+        remaining <= self.lead()
+    }
+}
+
+/// The stage list used when a device does not configure `alert_stages`.
+pub fn default_alert_stages() -> Vec<AlertStage> {
+    vec![AlertStage {
+        name: Some("complete".to_string()),
+        title: "Washing Complete :)".to_string(),
+        priority: Priority::Default,
+        lead_minutes: DEFAULT_COMPLETION_ALERT_LEAD_MINUTES,
+    }]
+}
+
+/// The next pending stage of a device's cycle, stored so that it fires at `fire_at` even if the
+/// device stops sending telemetry (or the server restarts) in the meantime.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledAlert {
+    pub device_id: String,
+    pub stage_name: String,
+    pub cycle_start: DateTime<Utc>,
+    pub estimate: CompletionEstimate,
+    pub fire_at: DateTime<Utc>,
+}
+
+/// An alert that was sent, with the estimate (and so the model) it was sent on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentAlert {
+    pub device_id: String,
+    /// The stage that fired, or "rewetting" for the re-wetting alert
+    pub stage_name: String,
+    pub title: String,
+    pub cycle_start: DateTime<Utc>,
+    pub estimate: CompletionEstimate,
+    pub sent_at: DateTime<Utc>,
+}
+
+// Storage for the notification state, kept behind a trait for the same reason as
+// `DeviceRepository`: the alert logic can then be tested without a database.
+#[allow(async_fn_in_trait)]
+pub trait AlertRepository: Send + Sync {
+    /// Returns the device's configured stages, or `None` if it does not configure any.
+    async fn get_alert_stages(&self, device_id: &str) -> Result<Option<Vec<AlertStage>>, AlertError>;
+    /// Names of the stages sent during the cycle that started at `cycle_start`, since the
+    /// cycle's latest re-wetting alert (which re-arms every stage).
+    async fn get_sent_stages(&self, device_id: &str, cycle_start: DateTime<Utc>) -> Result<Vec<String>, AlertError>;
+    async fn set_last_notification_at(&self, device_id: &str, at: DateTime<Utc>) -> Result<(), AlertError>;
+    /// Creates or replaces the device's scheduled alert (there is at most one per device).
+    async fn schedule_alert(&self, alert: &ScheduledAlert) -> Result<(), AlertError>;
+    async fn cancel_scheduled_alert(&self, device_id: &str) -> Result<(), AlertError>;
+    /// Removes and returns every scheduled alert whose `fire_at` has passed.
+    async fn take_due_alerts(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledAlert>, AlertError>;
+    /// Adds a sent alert to the device's alert history.
+    async fn record_alert(&self, alert: &SentAlert) -> Result<(), AlertError>;
+}
+
+/// Production implementation backed by the `devices` table.
+pub struct PostgresAlertRepository {
+    pool: PgPool,
+}
+
+impl PostgresAlertRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl AlertRepository for PostgresAlertRepository {
+    async fn get_alert_stages(&self, device_id: &str) -> Result<Option<Vec<AlertStage>>, AlertError> {
+        let row = sqlx::query("SELECT configuration->'alert_stages' AS alert_stages FROM devices WHERE device_id = $1")
+            .bind(device_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        let stages_json: Option<serde_json::Value> = match row {
+            Some(row) => row.try_get("alert_stages")?,
+            None => None,
+        };
+        match stages_json {
+            Some(json) => {
+                let stages = serde_json::from_value(json).map_err(|e| AlertError::InvalidConfiguration(e.to_string()))?;
+                Ok(Some(stages))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_sent_stages(&self, device_id: &str, cycle_start: DateTime<Utc>) -> Result<Vec<String>, AlertError> {
+        let rows = sqlx::query(
+            "SELECT stage_name FROM alert_history
+            WHERE device_id = $1 AND cycle_start = $2 AND stage_name <> $3
+            AND sent_at > COALESCE(
+                (SELECT MAX(sent_at) FROM alert_history WHERE device_id = $1 AND cycle_start = $2 AND stage_name = $3),
+                '-infinity'
+            )",
+        )
+        .bind(device_id)
+        .bind(cycle_start)
+        .bind(REWETTING_STAGE_NAME)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(|row| Ok(row.try_get("stage_name")?)).collect()
+    }
+
+    async fn set_last_notification_at(&self, device_id: &str, at: DateTime<Utc>) -> Result<(), AlertError> {
+        sqlx::query("UPDATE devices SET last_notification_at = $1 WHERE device_id = $2")
+            .bind(at)
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn schedule_alert(&self, alert: &ScheduledAlert) -> Result<(), AlertError> {
+        sqlx::query(
+            "INSERT INTO scheduled_alerts (device_id, stage_name, cycle_start, completion_time, completion_p10, completion_p90, model, fire_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (device_id) DO UPDATE SET
+                stage_name = EXCLUDED.stage_name,
+                cycle_start = EXCLUDED.cycle_start,
+                completion_time = EXCLUDED.completion_time,
+                completion_p10 = EXCLUDED.completion_p10,
+                completion_p90 = EXCLUDED.completion_p90,
+                model = EXCLUDED.model,
+                fire_at = EXCLUDED.fire_at",
+        )
+        .bind(&alert.device_id)
+        .bind(&alert.stage_name)
+        .bind(alert.cycle_start)
+        .bind(alert.estimate.completion_time)
+        .bind(alert.estimate.p10)
+        .bind(alert.estimate.p90)
+        .bind(alert.estimate.model.as_str())
+        .bind(alert.fire_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_scheduled_alert(&self, device_id: &str) -> Result<(), AlertError> {
+        sqlx::query("DELETE FROM scheduled_alerts WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_due_alerts(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledAlert>, AlertError> {
+        // DELETE ... RETURNING claims the jobs atomically, so a job is only ever fired once.
+        let rows = sqlx::query(
+            "DELETE FROM scheduled_alerts WHERE fire_at <= $1
+            RETURNING RTRIM(device_id) AS device_id, stage_name, cycle_start,
+                completion_time, completion_p10, completion_p90, model, fire_at",
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let model: String = row.try_get("model")?;
+                Ok(ScheduledAlert {
+                    device_id: row.try_get("device_id")?,
+                    stage_name: row.try_get("stage_name")?,
+                    cycle_start: row.try_get("cycle_start")?,
+                    estimate: CompletionEstimate {
+                        completion_time: row.try_get("completion_time")?,
+                        p10: row.try_get("completion_p10")?,
+                        p90: row.try_get("completion_p90")?,
+                        model: ModelKind::from_name(&model).unwrap_or_default(),
+                    },
+                    fire_at: row.try_get("fire_at")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn record_alert(&self, alert: &SentAlert) -> Result<(), AlertError> {
+        sqlx::query(
+            "INSERT INTO alert_history (device_id, stage_name, title, cycle_start, completion_time, completion_p10, completion_p90, model, sent_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(&alert.device_id)
+        .bind(&alert.stage_name)
+        .bind(&alert.title)
+        .bind(alert.cycle_start)
+        .bind(alert.estimate.completion_time)
+        .bind(alert.estimate.p10)
+        .bind(alert.estimate.p90)
+        .bind(alert.estimate.model.as_str())
+        .bind(alert.sent_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Per-device notification state for the current drying cycle.
+#[derive(Clone, Default)]
+struct AlertState {
+    cycle_start: Option<DateTime<Utc>>,
+    fired_stages: HashSet<String>,
+    pending_rewetting: Option<DateTime<Utc>>, // Start of a cycle whose re-wetting alert failed to send, retried with the next estimate
+}
+
+impl AlertState {
+    /// Moves the state on to the cycle that started at `cycle_start`, of which the stages named
+    /// in `sent` have already been sent (none for a new cycle; after a restart, the ones in the
+    /// alert history). Stages fire from the longest lead to the shortest, so every stage with at
+    /// least the lead of a sent one is done too: it was either sent or skipped in favour of a
+    /// more urgent one.
+    fn start_cycle(&mut self, cycle_start: DateTime<Utc>, stages: &[AlertStage], sent: &[String]) {
+        self.cycle_start = Some(cycle_start);
+        let shortest_sent = stages
+            .iter()
+            .filter(|stage| sent.iter().any(|name| name == stage.key()))
+            .map(|stage| stage.lead_minutes)
+            .min();
+        self.fired_stages = stages
+            .iter()
+            .filter(|stage| shortest_sent.is_some_and(|lead| stage.lead_minutes >= lead))
+            .map(|stage| stage.key().to_string())
+            .collect();
+    }
+}
+
+pub struct AlertManager<A: AlertRepository, N: Notifier> {
+    repo: A,
+    notifier: Arc<N>,
+    state: DashMap<String, AlertState>, // Notification state keyed by device ID
+}
+
+impl<A: AlertRepository, N: Notifier> AlertManager<A, N> {
+    pub fn new(repo: A, notifier: Arc<N>) -> Self {
+        AlertManager {
+            repo,
+            notifier,
+            state: DashMap::new(),
+        }
+    }
+
+    /// Fires the device's alert stages that have become due and have not yet fired during the
+    /// current cycle. If several stages become due at once (e.g. the first reading of a cycle is
+    /// already close to dry) only the most urgent one is sent and the others are marked as fired.
+    /// The next pending stage is then (re)scheduled so it fires even if no more telemetry arrives.
+    /// Returns the title of the alert that was sent, if any.
+    pub async fn check_completion(
+        &self,
+        device_id: &str,
+        cycle_start: DateTime<Utc>,
+        estimate: CompletionEstimate,
+        now: DateTime<Utc>,
+    ) -> Result<Option<String>, AlertError> {
+        let pending_rewetting = self.state.get(device_id).and_then(|state| state.pending_rewetting);
+        if pending_rewetting == Some(cycle_start) {
+            self.notify_rewetting(device_id, cycle_start, estimate, now).await?;
+        }
+
+        let stages = self.repo.get_alert_stages(device_id).await?.unwrap_or_else(default_alert_stages);
+        let remaining = estimate.completion_time.signed_duration_since(now);
+
+        let mut state = self.current_state(device_id);
+        state.pending_rewetting = None;
+        if state.cycle_start != Some(cycle_start) {
+            let sent = self.repo.get_sent_stages(device_id, cycle_start).await?;
+            state.start_cycle(cycle_start, &stages, &sent);
+        }
+
+        let due: Vec<&AlertStage> = stages
+            .iter()
+            .filter(|stage| stage.is_due(remaining))
+            .filter(|stage| !state.fired_stages.contains(stage.key()))
+            .collect();
+        let sent = match due.iter().min_by_key(|stage| stage.lead_minutes).copied() {
+            None => {
+                self.state.insert(device_id.to_string(), state);
+                None
+            }
+            Some(stage) => {
+                // Claim the stages before sending, so a second telemetry message processed
+                // concurrently does not send the same alert again.
+                let previous = state.clone();
+                for due_stage in &due {
+                    state.fired_stages.insert(due_stage.key().to_string());
+                }
+                self.state.insert(device_id.to_string(), state);
+
+                println!(
+                    "Alert: Device {} is predicted to be dry in {} minutes ({} estimate), sending \"{}\"",
+                    device_id,
+                    remaining.num_minutes(),
+                    estimate.model.as_str(),
+                    stage.title
+                );
+                let notification = Notification {
+                    device_id: device_id.to_string(),
+                    title: stage.title.clone(),
+                    message: format!("Device {} is predicted to be dry at {}", device_id, describe_completion(&estimate)),
+                    priority: stage.priority,
+                };
+                if let Err(e) = self.notifier.notify(&notification).await {
+                    // Release the claim so the next reading can try again.
+                    self.state.insert(device_id.to_string(), previous);
+                    return Err(e.into());
+                }
+
+                self.repo.set_last_notification_at(device_id, now).await?;
+                self.repo
+                    .record_alert(&SentAlert {
+                        device_id: device_id.to_string(),
+                        stage_name: stage.key().to_string(),
+                        title: stage.title.clone(),
+                        cycle_start,
+                        estimate,
+                        sent_at: now,
+                    })
+                    .await?;
+                Some(stage.title.clone())
+            }
+        };
+
+        self.reschedule(device_id, &stages, cycle_start, estimate).await?;
+        Ok(sent)
+    }
+
+    /// Schedules the earliest stage that has not fired yet, or cancels the schedule if every
+    /// stage of the cycle has fired.
+    async fn reschedule(
+        &self,
+        device_id: &str,
+        stages: &[AlertStage],
+        cycle_start: DateTime<Utc>,
+        estimate: CompletionEstimate,
+    ) -> Result<(), AlertError> {
+        let fired = self
+            .state
+            .get(device_id)
+            .map(|state| state.fired_stages.clone())
+            .unwrap_or_default();
+        let next = stages
+            .iter()
+            .filter(|stage| !fired.contains(stage.key()))
+            .max_by_key(|stage| stage.lead_minutes);
+
+        match next {
+            Some(stage) => {
+                let alert = ScheduledAlert {
+                    device_id: device_id.to_string(),
+                    stage_name: stage.key().to_string(),
+                    cycle_start,
+                    estimate,
+                    fire_at: estimate.completion_time - stage.lead(),
+                };
+                self.repo.schedule_alert(&alert).await
+            }
+            None => self.repo.cancel_scheduled_alert(device_id).await,
+        }
+    }
+
+    /// Fires every scheduled alert that has become due. A job whose alert cannot be sent is put
+    /// back so the next run retries it. Returns the number of alerts sent.
+    pub async fn fire_due_alerts(&self, now: DateTime<Utc>) -> Result<usize, AlertError> {
+        let mut sent = 0;
+        for job in self.repo.take_due_alerts(now).await? {
+            println!("Firing scheduled \"{}\" alert for device {}", job.stage_name, job.device_id);
+            match self.check_completion(&job.device_id, job.cycle_start, job.estimate, now).await {
+                Ok(Some(_)) => sent += 1,
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Failed to fire scheduled alert for device {}: {}", job.device_id, e);
+                    self.repo.schedule_alert(&job).await?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Runs the scheduler loop forever, firing due alerts every `period`.
+    pub async fn run_scheduler(self: Arc<Self>, period: std::time::Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.fire_due_alerts(Utc::now()).await {
+                eprintln!("[scheduler] Failed to fire scheduled alerts: {e}");
+            }
+        }
+    }
+
+    /// Sends the "raining on your washing" alert and re-arms the completion stages of the cycle.
+    /// If the alert cannot be sent it is retried by the next `check_completion` of the cycle.
+    pub async fn notify_rewetting(
+        &self,
+        device_id: &str,
+        cycle_start: DateTime<Utc>,
+        estimate: CompletionEstimate,
+        now: DateTime<Utc>,
+    ) -> Result<(), AlertError> {
+        let previous = self.current_state(device_id);
+        let state = AlertState {
+            cycle_start: Some(cycle_start),
+            fired_stages: HashSet::new(),
+            pending_rewetting: None,
+        };
+        self.state.insert(device_id.to_string(), state);
+
+        println!("Alert: Device {} has been re-wetted", device_id);
+        let notification = Notification {
+            device_id: device_id.to_string(),
+            title: REWETTING_ALERT_TITLE.to_string(),
+            message: format!(
+                "Device {} detected the washing getting wet again. It is now predicted to be dry at {}",
+                device_id,
+                describe_completion(&estimate)
+            ),
+            priority: Priority::High,
+        };
+        if let Err(e) = self.notifier.notify(&notification).await {
+            // Release the claim, and keep the alert pending so the next estimate retries it.
+            let pending = AlertState { pending_rewetting: Some(cycle_start), ..previous };
+            self.state.insert(device_id.to_string(), pending);
+            return Err(e.into());
+        }
+        self.repo.set_last_notification_at(device_id, now).await?;
+        self.repo
+            .record_alert(&SentAlert {
+                device_id: device_id.to_string(),
+                stage_name: REWETTING_STAGE_NAME.to_string(),
+                title: notification.title,
+                cycle_start,
+                estimate,
+                sent_at: now,
+            })
+            .await?;
+
+        let stages = self.repo.get_alert_stages(device_id).await?.unwrap_or_else(default_alert_stages);
+        self.reschedule(device_id, &stages, cycle_start, estimate).await
+    }
+
+    fn current_state(&self, device_id: &str) -> AlertState {
+        self.state.get(device_id).map(|state| state.clone()).unwrap_or_default()
+    }
+}
+
+/// Completion time for alert text, e.g. "2024-06-01T14:05:00+00:00 (most likely between
+/// 13:50 and 14:30 UTC)". The band is left out when the filter has no spread to report.
+fn describe_completion(estimate: &CompletionEstimate) -> String {
+    if estimate.p10 == estimate.p90 {
+        return estimate.completion_time.to_rfc3339();
+    }
+    format!(
+        "{} (most likely between {} and {} UTC)",
+        estimate.completion_time.to_rfc3339(),
+        estimate.p10.format("%H:%M"),
+        estimate.p90.format("%H:%M")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockAlertRepository {
+        stages: Option<Vec<AlertStage>>,
+        last_notification_at: Mutex<Option<DateTime<Utc>>>,
+        scheduled: Mutex<Vec<ScheduledAlert>>,
+        history: Mutex<Vec<SentAlert>>,
+    }
+
+    impl AlertRepository for MockAlertRepository {
+        async fn get_alert_stages(&self, _device_id: &str) -> Result<Option<Vec<AlertStage>>, AlertError> {
+            Ok(self.stages.clone())
+        }
+
+        async fn get_sent_stages(&self, _device_id: &str, cycle_start: DateTime<Utc>) -> Result<Vec<String>, AlertError> {
+            let history = self.history.lock().unwrap();
+            let cycle: Vec<&SentAlert> = history.iter().filter(|alert| alert.cycle_start == cycle_start).collect();
+            let since = cycle.iter().rposition(|alert| alert.stage_name == REWETTING_STAGE_NAME).map_or(0, |i| i + 1);
+            Ok(cycle[since..].iter().map(|alert| alert.stage_name.clone()).collect())
+        }
+
+        async fn set_last_notification_at(&self, _device_id: &str, at: DateTime<Utc>) -> Result<(), AlertError> {
+            *self.last_notification_at.lock().unwrap() = Some(at);
+            Ok(())
+        }
+
+        async fn schedule_alert(&self, alert: &ScheduledAlert) -> Result<(), AlertError> {
+            let mut scheduled = self.scheduled.lock().unwrap();
+            scheduled.retain(|job| job.device_id != alert.device_id);
+            scheduled.push(alert.clone());
+            Ok(())
+        }
+
+        async fn cancel_scheduled_alert(&self, device_id: &str) -> Result<(), AlertError> {
+            self.scheduled.lock().unwrap().retain(|job| job.device_id != device_id);
+            Ok(())
+        }
+
+        async fn take_due_alerts(&self, now: DateTime<Utc>) -> Result<Vec<ScheduledAlert>, AlertError> {
+            let mut scheduled = self.scheduled.lock().unwrap();
+            let (due, pending) = scheduled.drain(..).partition(|job| job.fire_at <= now);
+            *scheduled = pending;
+            Ok(due)
+        }
+
+        async fn record_alert(&self, alert: &SentAlert) -> Result<(), AlertError> {
+            self.history.lock().unwrap().push(alert.clone());
+            Ok(())
+        }
+    }
+
+    /// An estimate without any uncertainty.
+    fn exact(completion_time: DateTime<Utc>) -> CompletionEstimate {
+        CompletionEstimate { completion_time, p10: completion_time, p90: completion_time, model: ModelKind::Ekf }
+    }
+
+    #[derive(Default)]
+    struct MockNotifier {
+        sent: Mutex<Vec<Notification>>,
+        failures: Mutex<usize>, // Number of upcoming notifications that fail
+    }
+
+    impl Notifier for MockNotifier {
+        async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(NotifierError::Configuration("unreachable".to_string()));
+            }
+            self.sent.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_one_alert_per_cycle() {
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let cycle_start = Utc::now() - chrono::Duration::hours(2);
+
+        for i in 0..5 {
+            let now = cycle_start + chrono::Duration::minutes(100 + i);
+            let completion = now + chrono::Duration::minutes(2);
+            alerts.check_completion("wash-1", cycle_start, exact(completion), now).await.unwrap();
+        }
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+        assert!(alerts.repo.last_notification_at.lock().unwrap().is_some());
+
+        // A new cycle re-arms the alert.
+        let new_cycle_start = Utc::now();
+        let sent = alerts
+            .check_completion("wash-1", new_cycle_start, exact(new_cycle_start), new_cycle_start)
+            .await
+            .unwrap();
+        assert_eq!(sent.as_deref(), Some("Washing Complete :)"));
+        assert_eq!(notifier.sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_alert_when_completion_is_far_away() {
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let now = Utc::now();
+
+        let sent = alerts
+            .check_completion("wash-1", now, exact(now + chrono::Duration::minutes(30)), now)
+            .await
+            .unwrap();
+        assert!(sent.is_none());
+        assert!(notifier.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stored_notification_suppresses_alert_after_restart() {
+        let cycle_start = Utc::now() - chrono::Duration::hours(2);
+        let now = Utc::now();
+        let first = AlertManager::new(MockAlertRepository::default(), Arc::new(MockNotifier::default()));
+        first.check_completion("wash-1", cycle_start, exact(now), now).await.unwrap();
+
+        // A new manager has no state in memory, only the alert history.
+        let repo = MockAlertRepository {
+            history: Mutex::new(first.repo.history.lock().unwrap().clone()),
+            ..Default::default()
+        };
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(repo, notifier.clone());
+        let sent = alerts.check_completion("wash-1", cycle_start, exact(now), now).await.unwrap();
+        assert!(sent.is_none());
+        assert!(notifier.sent.lock().unwrap().is_empty());
+    }
+
+    fn three_stages() -> Vec<AlertStage> {
+        serde_json::from_value(serde_json::json!([
+            { "name": "30min", "title": "30 minutes left", "priority": "low", "lead_minutes": 30 },
+            { "name": "10min", "title": "10 minutes left", "lead_minutes": 10 },
+            { "name": "dry", "title": "Dry now", "priority": "high", "lead_minutes": 0 }
+        ]))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_later_stages_fire_after_restart() {
+        let cycle_start = Utc::now() - chrono::Duration::hours(1);
+        let completion = Utc::now() + chrono::Duration::minutes(25);
+        let first_repo = MockAlertRepository { stages: Some(three_stages()), ..Default::default() };
+        let first = AlertManager::new(first_repo, Arc::new(MockNotifier::default()));
+        let sent = first.check_completion("wash-1", cycle_start, exact(completion), Utc::now()).await.unwrap();
+        assert_eq!(sent.as_deref(), Some("30 minutes left"));
+
+        // The server restarts partway through the cycle.
+        let repo = MockAlertRepository {
+            stages: Some(three_stages()),
+            history: Mutex::new(first.repo.history.lock().unwrap().clone()),
+            ..Default::default()
+        };
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(repo, notifier.clone());
+
+        let ten_minutes_out = completion - chrono::Duration::minutes(8);
+        let sent = alerts.check_completion("wash-1", cycle_start, exact(completion), ten_minutes_out).await.unwrap();
+        assert_eq!(sent.as_deref(), Some("10 minutes left"));
+        let sent = alerts.check_completion("wash-1", cycle_start, exact(completion), completion).await.unwrap();
+        assert_eq!(sent.as_deref(), Some("Dry now"));
+
+        let titles: Vec<String> = notifier.sent.lock().unwrap().iter().map(|n| n.title.clone()).collect();
+        assert_eq!(titles, vec!["10 minutes left", "Dry now"]);
+    }
+
+    #[tokio::test]
+    async fn test_each_stage_fires_once_per_cycle() {
+        let repo = MockAlertRepository { stages: Some(three_stages()), ..Default::default() };
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(repo, notifier.clone());
+        let cycle_start = Utc::now() - chrono::Duration::hours(3);
+        let completion = cycle_start + chrono::Duration::minutes(120);
+
+        // A reading every two minutes from an hour out until ten minutes after completion.
+        for minutes in (60..=130).step_by(2) {
+            let now = cycle_start + chrono::Duration::minutes(minutes);
+            alerts.check_completion("wash-1", cycle_start, exact(completion), now).await.unwrap();
+        }
+
+        let sent = notifier.sent.lock().unwrap();
+        let titles: Vec<&str> = sent.iter().map(|n| n.title.as_str()).collect();
+        assert_eq!(titles, vec!["30 minutes left", "10 minutes left", "Dry now"]);
+        assert_eq!(sent[0].priority, Priority::Low);
+        assert_eq!(sent[1].priority, Priority::Default);
+        assert_eq!(sent[2].priority, Priority::High);
+    }
+
+    #[tokio::test]
+    async fn test_rewetting_alert_rearms_stages() {
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let cycle_start = Utc::now() - chrono::Duration::hours(2);
+        let now = Utc::now();
+
+        alerts.check_completion("wash-1", cycle_start, exact(now), now).await.unwrap();
+        alerts
+            .notify_rewetting("wash-1", cycle_start, exact(now + chrono::Duration::minutes(90)), now)
+            .await
+            .unwrap();
+
+        let later = now + chrono::Duration::minutes(88);
+        let sent = alerts
+            .check_completion("wash-1", cycle_start, exact(now + chrono::Duration::minutes(90)), later)
+            .await
+            .unwrap();
+        assert_eq!(sent.as_deref(), Some("Washing Complete :)"));
+
+        let sent = notifier.sent.lock().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[1].title, "It's raining on your washing");
+        assert_eq!(sent[1].priority, Priority::High);
+    }
+
+    #[tokio::test]
+    async fn test_failed_rewetting_alert_is_retried() {
+        let notifier = Arc::new(MockNotifier { failures: Mutex::new(1), ..Default::default() });
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let cycle_start = Utc::now() - chrono::Duration::hours(2);
+        let now = Utc::now();
+        let completion = now + chrono::Duration::minutes(90);
+
+        assert!(alerts.notify_rewetting("wash-1", cycle_start, exact(completion), now).await.is_err());
+        assert!(notifier.sent.lock().unwrap().is_empty());
+
+        // The next reading of the cycle sends it after all.
+        let later = now + chrono::Duration::minutes(2);
+        alerts.check_completion("wash-1", cycle_start, exact(completion), later).await.unwrap();
+        let sent = notifier.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].title, REWETTING_ALERT_TITLE);
+        assert_eq!(alerts.repo.history.lock().unwrap()[0].stage_name, REWETTING_STAGE_NAME);
+    }
+
+    #[tokio::test]
+    async fn test_only_most_urgent_of_simultaneous_stages_is_sent() {
+        let repo = MockAlertRepository { stages: Some(three_stages()), ..Default::default() };
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(repo, notifier.clone());
+        let now = Utc::now();
+
+        // The first estimate of the cycle is already 8 minutes out, so "30 minutes left" is skipped.
+        let sent = alerts
+            .check_completion("wash-1", now, exact(now + chrono::Duration::minutes(8)), now)
+            .await
+            .unwrap();
+        assert_eq!(sent.as_deref(), Some("10 minutes left"));
+
+        let later = now + chrono::Duration::minutes(2);
+        let sent = alerts
+            .check_completion("wash-1", now, exact(now + chrono::Duration::minutes(8)), later)
+            .await
+            .unwrap();
+        assert!(sent.is_none());
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_next_stage_is_scheduled() {
+        let repo = MockAlertRepository { stages: Some(three_stages()), ..Default::default() };
+        let alerts = AlertManager::new(repo, Arc::new(MockNotifier::default()));
+        let now = Utc::now();
+        let completion = now + chrono::Duration::minutes(60);
+
+        alerts.check_completion("wash-1", now, exact(completion), now).await.unwrap();
+        {
+            let scheduled = alerts.repo.scheduled.lock().unwrap();
+            assert_eq!(scheduled.len(), 1);
+            assert_eq!(scheduled[0].stage_name, "30min");
+            assert_eq!(scheduled[0].fire_at, completion - chrono::Duration::minutes(30));
+        }
+
+        // Once the 30 minute stage has fired the schedule moves on to the 10 minute stage.
+        let later = now + chrono::Duration::minutes(35);
+        alerts.check_completion("wash-1", now, exact(completion), later).await.unwrap();
+        let scheduled = alerts.repo.scheduled.lock().unwrap();
+        assert_eq!(scheduled[0].stage_name, "10min");
+        assert_eq!(scheduled[0].fire_at, completion - chrono::Duration::minutes(10));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_alert_fires_without_telemetry() {
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let now = Utc::now();
+        let completion = now + chrono::Duration::minutes(60);
+
+        // The last reading the sensor sends before it goes quiet.
+        alerts.check_completion("wash-1", now, exact(completion), now).await.unwrap();
+
+        assert_eq!(alerts.fire_due_alerts(now + chrono::Duration::minutes(30)).await.unwrap(), 0);
+        assert!(notifier.sent.lock().unwrap().is_empty());
+
+        let fired = alerts.fire_due_alerts(completion - chrono::Duration::minutes(5)).await.unwrap();
+        assert_eq!(fired, 1);
+        assert_eq!(notifier.sent.lock().unwrap()[0].title, "Washing Complete :)");
+        // Every stage has fired, so nothing is left scheduled.
+        assert!(alerts.repo.scheduled.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sent_alerts_record_driving_model() {
+        let alerts = AlertManager::new(MockAlertRepository::default(), Arc::new(MockNotifier::default()));
+        let now = Utc::now();
+        let completion = now + chrono::Duration::minutes(60);
+        let estimate = CompletionEstimate { model: ModelKind::CurveFit, ..exact(completion) };
+
+        // The schedule keeps the model, so an alert fired without telemetry still records it.
+        alerts.check_completion("wash-1", now, estimate, now).await.unwrap();
+        assert!(alerts.repo.history.lock().unwrap().is_empty());
+        alerts.fire_due_alerts(completion).await.unwrap();
+
+        let rewetted = CompletionEstimate { model: ModelKind::Plateau, ..exact(completion) };
+        alerts.notify_rewetting("wash-1", now, rewetted, completion).await.unwrap();
+
+        let history = alerts.repo.history.lock().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].stage_name, "complete");
+        assert_eq!(history[0].estimate.model, ModelKind::CurveFit);
+        assert_eq!(history[0].sent_at, completion);
+        assert_eq!(history[1].stage_name, REWETTING_STAGE_NAME);
+        assert_eq!(history[1].title, REWETTING_ALERT_TITLE);
+        assert_eq!(history[1].estimate.model, ModelKind::Plateau);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_alert_fires_after_restart() {
+        let cycle_start = Utc::now() - chrono::Duration::hours(1);
+        let now = Utc::now();
+        let completion = now + chrono::Duration::minutes(25);
+        let first_repo = MockAlertRepository { stages: Some(three_stages()), ..Default::default() };
+        let first = AlertManager::new(first_repo, Arc::new(MockNotifier::default()));
+        first.check_completion("wash-1", cycle_start, exact(completion), now).await.unwrap();
+
+        // Only the database survives the restart: the history and the scheduled "10min" stage.
+        let repo = MockAlertRepository {
+            stages: Some(three_stages()),
+            scheduled: Mutex::new(first.repo.scheduled.lock().unwrap().clone()),
+            history: Mutex::new(first.repo.history.lock().unwrap().clone()),
+            ..Default::default()
+        };
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(repo, notifier.clone());
+
+        let fired = alerts.fire_due_alerts(completion - chrono::Duration::minutes(10)).await.unwrap();
+        assert_eq!(fired, 1);
+        assert_eq!(notifier.sent.lock().unwrap()[0].title, "10 minutes left");
+        let scheduled = alerts.repo.scheduled.lock().unwrap();
+        assert_eq!(scheduled[0].stage_name, "dry");
+    }
+
+    #[tokio::test]
+    async fn test_alert_text_includes_completion_band() {
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = AlertManager::new(MockAlertRepository::default(), notifier.clone());
+        let now = "2024-06-01T14:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let estimate = CompletionEstimate {
+            completion_time: now + chrono::Duration::minutes(3),
+            p10: now + chrono::Duration::minutes(1),
+            p90: now + chrono::Duration::minutes(12),
+            model: ModelKind::Ekf,
+        };
+
+        alerts.check_completion("wash-1", now, estimate, now).await.unwrap();
+        let sent = notifier.sent.lock().unwrap();
+        assert!(sent[0].message.ends_with("(most likely between 14:01 and 14:12 UTC)"), "{}", sent[0].message);
+    }
+}
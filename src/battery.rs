@@ -0,0 +1,194 @@
+//! Battery voltage tracking for the line monitors.
+//!
+//! The sensors periodically send a telemetry message with their battery voltage instead of a
+//! resistance reading, e.g. `{ "battery_voltage": 3.71, "timestamp": "..." }`. This module keeps
+//! the latest voltage per device, estimates the remaining charge and sends a low battery alert.
+//!
+//! The alert uses hysteresis: it is sent once when the voltage falls below
+//! `LOW_BATTERY_VOLTAGE` and is only re-armed after the voltage has recovered above
+//! `RECOVERED_BATTERY_VOLTAGE` (i.e. the battery was charged or replaced), so a voltage that
+//! hovers around the threshold does not cause a stream of alerts.
+
+use crate::notifier::{Notification, Notifier, NotifierError, Priority};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::sync::Arc;
+
+/// Below this voltage the battery is reported as low (roughly 10% for a single Li-ion cell).
+const LOW_BATTERY_VOLTAGE: f64 = 3.5;
+/// The low battery alert is re-armed once the voltage is back above this.
+const RECOVERED_BATTERY_VOLTAGE: f64 = 3.7;
+
+/// Approximate discharge curve of a single Li-ion cell: (voltage, percentage), highest first.
+const DISCHARGE_CURVE: [(f64, f64); 11] = [
+    (4.20, 100.0),
+    (4.10, 90.0),
+    (4.00, 80.0),
+    (3.90, 65.0),
+    (3.80, 50.0),
+    (3.70, 35.0),
+    (3.60, 20.0),
+    (3.50, 10.0),
+    (3.40, 5.0),
+    (3.30, 2.0),
+    (3.00, 0.0),
+];
+
+/// Extracts the battery voltage from a telemetry payload, if it has one.
+pub fn parse_voltage(payload: &serde_json::Value) -> Option<f64> {
+    payload["battery_voltage"]
+        .as_f64()
+        .or_else(|| payload["voltage"].as_f64())
+}
+
+/// Estimates the remaining charge by linear interpolation along `DISCHARGE_CURVE`.
+pub fn estimate_percentage(voltage: f64) -> f64 {
+    let (max_voltage, max_percentage) = DISCHARGE_CURVE[0];
+    if voltage >= max_voltage {
+        return max_percentage;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let (upper_v, upper_p) = pair[0];
+        let (lower_v, lower_p) = pair[1];
+        if voltage >= lower_v {
+            return lower_p + (voltage - lower_v) / (upper_v - lower_v) * (upper_p - lower_p);
+        }
+    }
+    0.0
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct BatteryState {
+    pub voltage: f64,
+    pub percentage: f64,
+    pub timestamp: DateTime<Utc>,
+    pub low: bool,
+}
+
+impl BatteryState {
+    pub fn new(voltage: f64, timestamp: DateTime<Utc>) -> Self {
+        BatteryState {
+            voltage,
+            percentage: estimate_percentage(voltage),
+            timestamp,
+            low: voltage < LOW_BATTERY_VOLTAGE,
+        }
+    }
+}
+
+pub struct BatteryMonitor<N: Notifier> {
+    notifier: Arc<N>,
+    state: DashMap<String, BatteryState>, // Latest battery state keyed by device ID
+}
+
+impl<N: Notifier> BatteryMonitor<N> {
+    pub fn new(notifier: Arc<N>) -> Self {
+        BatteryMonitor {
+            notifier,
+            state: DashMap::new(),
+        }
+    }
+
+    pub fn get_state(&self, device_id: &str) -> Option<BatteryState> {
+        self.state.get(device_id).map(|state| *state)
+    }
+
+    /// Records a voltage reading and sends the low battery alert if the voltage has just
+    /// crossed below the threshold. Returns true if an alert was sent.
+    pub async fn record_voltage(
+        &self,
+        device_id: &str,
+        voltage: f64,
+        timestamp: DateTime<Utc>,
+    ) -> Result<bool, NotifierError> {
+        let was_low = self.state.get(device_id).is_some_and(|state| state.low);
+        let low = if was_low {
+            voltage < RECOVERED_BATTERY_VOLTAGE
+        } else {
+            voltage < LOW_BATTERY_VOLTAGE
+        };
+
+        let state = BatteryState { low, ..BatteryState::new(voltage, timestamp) };
+        self.state.insert(device_id.to_string(), state);
+        println!(
+            "Battery for device {}: {:.2} V (~{:.0}%){}",
+            device_id,
+            voltage,
+            state.percentage,
+            if low { " LOW" } else { "" }
+        );
+
+        if !low || was_low {
+            return Ok(false);
+        }
+
+        let notification = Notification {
+            device_id: device_id.to_string(),
+            title: "Washing line monitor battery low".to_string(),
+            message: format!(
+                "Device {} battery is at {:.2} V (about {:.0}%). Please charge or replace it.",
+                device_id, voltage, state.percentage
+            ),
+            priority: Priority::Default,
+        };
+        if let Err(e) = self.notifier.notify(&notification).await {
+            // Forget the low flag so the next reading retries the alert.
+            self.state.insert(device_id.to_string(), BatteryState { low: false, ..state });
+            return Err(e);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockNotifier {
+        sent: Mutex<Vec<Notification>>,
+    }
+
+    impl Notifier for MockNotifier {
+        async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+            self.sent.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_estimate_percentage() {
+        assert_eq!(estimate_percentage(4.3), 100.0);
+        assert_eq!(estimate_percentage(3.8), 50.0);
+        assert!((estimate_percentage(3.75) - 42.5).abs() < 1e-9);
+        assert_eq!(estimate_percentage(2.9), 0.0);
+    }
+
+    #[test]
+    fn test_parse_voltage() {
+        assert_eq!(parse_voltage(&serde_json::json!({ "battery_voltage": 3.7 })), Some(3.7));
+        assert_eq!(parse_voltage(&serde_json::json!({ "voltage": 3.6 })), Some(3.6));
+        assert_eq!(parse_voltage(&serde_json::json!({ "resistance": 30000.0 })), None);
+    }
+
+    #[tokio::test]
+    async fn test_low_battery_alert_hysteresis() {
+        let notifier = Arc::new(MockNotifier::default());
+        let monitor = BatteryMonitor::new(notifier.clone());
+        let now = Utc::now();
+
+        // Hovering around the low threshold only alerts once.
+        for voltage in [3.8, 3.45, 3.52, 3.48, 3.6] {
+            monitor.record_voltage("wash-1", voltage, now).await.unwrap();
+        }
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+        assert!(monitor.get_state("wash-1").unwrap().low);
+
+        // Charging the battery re-arms the alert.
+        monitor.record_voltage("wash-1", 4.1, now).await.unwrap();
+        assert!(!monitor.get_state("wash-1").unwrap().low);
+        assert!(monitor.record_voltage("wash-1", 3.4, now).await.unwrap());
+        assert_eq!(notifier.sent.lock().unwrap().len(), 2);
+    }
+}
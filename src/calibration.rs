@@ -0,0 +1,592 @@
+//! Fitting the drying model to a device's stored cycles.
+//!
+//! The EKF parameters in a device's `configuration` JSON are typed in by hand. The calibration
+//! fits the constant part of the model, `[M0, k, tau, M_c, R_offset]`, to the device's most
+//! recent finished cycles and proposes an updated `EKFParameters` from it.
+//!
+//! Without noise the model has a closed form, `t` minutes after the first reading of a cycle:
+//!
+//!   R(t) = (M0 * e^{-k t} - M_c)^{-tau} + R_offset
+//!
+//! with the same clamping as `MoistureSensorModel::state_transition`. One set of constants is
+//! fitted to all cycles at once by Levenberg-Marquardt least squares (the maximum-likelihood
+//! fit under Gaussian measurement noise), starting from the device's current parameters.
+//! `M0`, `k`, `tau` and `M_c` are fitted in log space so they stay positive and are on a
+//! similar scale. Readings after the washing was dry (the final plateau, see
+//! `accuracy::find_actual_completion`) are left out, as the model does not describe the sensor
+//! saturating.
+//!
+//! The noise levels come from the fit as well:
+//!   - the measurement noise is the residual variance of the fit,
+//!   - with two or more cycles, each cycle is also fitted on its own (starting from the shared
+//!     fit). The initial covariance of the constants is their variance across those fits, and
+//!     the process noise of `k` and `tau` is set so that their random walk spreads by that
+//!     much over an average cycle.
+//!
+//! The proposal is only applied if it fits the cycles better than the current parameters.
+
+use crate::accuracy::find_actual_completion;
+use crate::washing_predictor::{
+    telemetry_from_row, DeviceRepository, EKFParameters, PostgresDeviceRepository, PredictorError, TelemetryData,
+};
+use rocket_db_pools::sqlx::{self, PgPool, Row};
+
+/// Number of most recent finished cycles the calibration looks at.
+const CALIBRATION_CYCLES: i64 = 10;
+/// Cycles with fewer readings (before the washing was dry) are skipped.
+const MIN_CYCLE_READINGS: usize = 15;
+const MAX_ITERATIONS: usize = 200;
+/// Index of the constants `[M0, k, tau, M_c, R_offset]` in the EKF state vector.
+const CONSTANTS_OFFSET: usize = 1;
+const STATE_DIM: usize = 6;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CalibrationError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("device not found: {0}")]
+    DeviceNotFound(String),
+
+    #[error("invalid EKF parameters for device {device_id}: {message}")]
+    InvalidParameters { device_id: String, message: String },
+
+    #[error("device {device_id} has no finished cycle with at least {MIN_CYCLE_READINGS} readings to calibrate from")]
+    NotEnoughData { device_id: String },
+}
+
+/// The constant part of the drying model.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct ModelParameters {
+    pub m0: f64,
+    pub k: f64,
+    pub tau: f64,
+    pub m_c: f64,
+    pub r_offset: f64,
+}
+
+impl ModelParameters {
+    pub(crate) fn from_state(state: &[f64]) -> Self {
+        ModelParameters {
+            m0: state[1],
+            k: state[2],
+            tau: state[3],
+            m_c: state[4],
+            r_offset: state[5],
+        }
+    }
+
+    /// Modelled resistance `minutes` after the start of the cycle.
+    pub fn resistance(&self, minutes: f64) -> f64 {
+        let m = (self.m0 * (-self.k * minutes).exp()).clamp(1e-9, f64::INFINITY);
+        let base = (m - self.m_c).clamp(1e-9, f64::INFINITY);
+        base.powf(-self.tau) + self.r_offset
+    }
+
+    /// The parameters the fit works on: logs of the positive constants, `R_offset` as is.
+    pub(crate) fn to_vector(self) -> [f64; 5] {
+        let log = |value: f64| value.max(1e-15).ln();
+        [log(self.m0), log(self.k), log(self.tau), log(self.m_c), self.r_offset]
+    }
+
+    pub(crate) fn from_vector(p: &[f64; 5]) -> Self {
+        ModelParameters {
+            m0: p[0].exp(),
+            k: p[1].exp(),
+            tau: p[2].exp(),
+            m_c: p[3].exp(),
+            r_offset: p[4],
+        }
+    }
+
+    fn as_array(&self) -> [f64; 5] {
+        [self.m0, self.k, self.tau, self.m_c, self.r_offset]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fit {
+    pub(crate) parameters: ModelParameters,
+    pub(crate) residual_variance: f64,
+}
+
+/// Readings of one cycle as (minutes since the first reading, resistance).
+pub(crate) type Samples = Vec<(f64, f64)>;
+
+fn to_samples(readings: &[TelemetryData]) -> Samples {
+    let Some(first) = readings.first() else {
+        return Vec::new();
+    };
+    readings
+        .iter()
+        .map(|reading| {
+            let minutes = (reading.timestamp - first.timestamp).num_milliseconds() as f64 / 60_000.0;
+            (minutes, reading.resistance)
+        })
+        .collect()
+}
+
+fn residuals(parameters: &ModelParameters, cycles: &[Samples]) -> Vec<f64> {
+    cycles
+        .iter()
+        .flatten()
+        .map(|(minutes, resistance)| parameters.resistance(*minutes) - resistance)
+        .collect()
+}
+
+fn sum_of_squares(parameters: &ModelParameters, cycles: &[Samples]) -> f64 {
+    let sum: f64 = residuals(parameters, cycles).iter().map(|residual| residual.powi(2)).sum();
+    if sum.is_finite() { sum } else { f64::INFINITY }
+}
+
+/// Root mean square error of the model over all cycles.
+fn rmse(parameters: &ModelParameters, cycles: &[Samples]) -> f64 {
+    let count: usize = cycles.iter().map(Vec::len).sum();
+    (sum_of_squares(parameters, cycles) / count.max(1) as f64).sqrt()
+}
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting.
+fn solve(mut a: [[f64; 5]; 5], mut b: [f64; 5]) -> Option<[f64; 5]> {
+    for col in 0..5 {
+        let pivot = (col..5).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-300 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..5 {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (value, pivot_value) in a[row].iter_mut().zip(pivot_row).skip(col) {
+                *value -= factor * pivot_value;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; 5];
+    for row in (0..5).rev() {
+        let sum: f64 = (row + 1..5).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    x.iter().all(|value| value.is_finite()).then_some(x)
+}
+
+/// `J^T J` and `J^T r` of the residuals at the fit vector `p`, with a central difference
+/// Jacobian `J`.
+fn normal_equations(p: &[f64; 5], cycles: &[Samples]) -> ([[f64; 5]; 5], [f64; 5]) {
+    let count: usize = cycles.iter().map(Vec::len).sum();
+    let residuals = |p: &[f64; 5]| residuals(&ModelParameters::from_vector(p), cycles);
+
+    let r = residuals(p);
+    let mut jacobian = vec![[0.0; 5]; count];
+    for j in 0..5 {
+        let h = 1e-6 * p[j].abs().max(1.0);
+        let mut plus = *p;
+        let mut minus = *p;
+        plus[j] += h;
+        minus[j] -= h;
+        for (row, (up, down)) in jacobian.iter_mut().zip(residuals(&plus).into_iter().zip(residuals(&minus))) {
+            row[j] = (up - down) / (2.0 * h);
+        }
+    }
+
+    let mut jtj = [[0.0; 5]; 5];
+    let mut jtr = [0.0; 5];
+    for (row, residual) in jacobian.iter().zip(&r) {
+        for i in 0..5 {
+            jtr[i] += row[i] * residual;
+            for k in 0..5 {
+                jtj[i][k] += row[i] * row[k];
+            }
+        }
+    }
+    (jtj, jtr)
+}
+
+/// Levenberg-Marquardt least squares fit of one set of constants to the cycles, with a central
+/// difference Jacobian. Only steps that lower the cost are taken, so the fit is never worse
+/// than `start`.
+pub(crate) fn fit(cycles: &[Samples], start: ModelParameters) -> Option<Fit> {
+    let count: usize = cycles.iter().map(Vec::len).sum();
+
+    let mut p = start.to_vector();
+    let mut cost = sum_of_squares(&ModelParameters::from_vector(&p), cycles);
+    if !cost.is_finite() {
+        return None;
+    }
+    let mut lambda = 1e-3;
+
+    for _ in 0..MAX_ITERATIONS {
+        let (jtj, jtr) = normal_equations(&p, cycles);
+
+        // Increase the damping until a step lowers the cost.
+        let mut improved = None;
+        while lambda < 1e12 {
+            let mut damped = jtj;
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += lambda * jtj[i][i].max(1e-12);
+            }
+            let step = solve(damped, jtr.map(|value| -value));
+            if let Some(step) = step {
+                let candidate: [f64; 5] = std::array::from_fn(|i| p[i] + step[i]);
+                let candidate_cost = sum_of_squares(&ModelParameters::from_vector(&candidate), cycles);
+                if candidate_cost < cost {
+                    lambda = (lambda / 10.0).max(1e-12);
+                    improved = Some((candidate, candidate_cost));
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+
+        let Some((candidate, candidate_cost)) = improved else {
+            break;
+        };
+        let converged = (cost - candidate_cost) <= 1e-10 * cost;
+        p = candidate;
+        cost = candidate_cost;
+        if converged {
+            break;
+        }
+    }
+
+    let degrees_of_freedom = count.saturating_sub(5).max(1);
+    Some(Fit {
+        parameters: ModelParameters::from_vector(&p),
+        residual_variance: cost / degrees_of_freedom as f64,
+    })
+}
+
+/// Covariance of the fit vector (`ModelParameters::to_vector`) of `fit`, from the residual
+/// variance and the curvature of the cost: `s^2 (J^T J)^-1`. `None` if the cycles do not pin
+/// down all five constants.
+pub(crate) fn parameter_covariance(cycles: &[Samples], fit: &Fit) -> Option<[[f64; 5]; 5]> {
+    let (jtj, _) = normal_equations(&fit.parameters.to_vector(), cycles);
+    let mut covariance = [[0.0; 5]; 5];
+    for column in 0..5 {
+        let mut unit = [0.0; 5];
+        unit[column] = 1.0;
+        let solution = solve(jtj, unit)?;
+        for (row, value) in solution.into_iter().enumerate() {
+            covariance[row][column] = fit.residual_variance * value;
+        }
+    }
+    Some(covariance)
+}
+
+fn sample_variance(values: &[f64]) -> f64 {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1.0)
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CalibrationResult {
+    pub device_id: String,
+    pub cycles_used: usize,
+    pub readings_used: usize,
+    /// RMSE of the current parameters over the cycles used, in ohms.
+    pub rmse_before: f64,
+    /// RMSE of the proposed parameters over the same cycles, in ohms.
+    pub rmse_after: f64,
+    pub fitted: ModelParameters,
+    pub current: EKFParameters,
+    pub proposed: EKFParameters,
+    pub applied: bool,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait CalibrationRepository: Send + Sync {
+    async fn get_ekf_parameters(&self, device_id: &str) -> Result<EKFParameters, CalibrationError>;
+    /// The resistance readings of up to `limit` of the device's most recent finished cycles,
+    /// each oldest first.
+    async fn get_finished_cycle_readings(&self, device_id: &str, limit: i64) -> Result<Vec<Vec<TelemetryData>>, CalibrationError>;
+    async fn save_ekf_parameters(&self, device_id: &str, parameters: &EKFParameters) -> Result<(), CalibrationError>;
+}
+
+pub struct PostgresCalibrationRepository {
+    pool: PgPool,
+    // Resolves the parameters the same way the predictor does, presets included.
+    devices: PostgresDeviceRepository,
+}
+
+impl PostgresCalibrationRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            devices: PostgresDeviceRepository::new(pool.clone()),
+            pool,
+        }
+    }
+}
+
+impl CalibrationRepository for PostgresCalibrationRepository {
+    async fn get_ekf_parameters(&self, device_id: &str) -> Result<EKFParameters, CalibrationError> {
+        self.devices.get_ekf_parameters(device_id).await.map_err(|e| match e {
+            PredictorError::DeviceNotFound(_) => CalibrationError::DeviceNotFound(device_id.to_string()),
+            other => CalibrationError::InvalidParameters {
+                device_id: device_id.to_string(),
+                message: other.to_string(),
+            },
+        })
+    }
+
+    async fn get_finished_cycle_readings(&self, device_id: &str, limit: i64) -> Result<Vec<Vec<TelemetryData>>, CalibrationError> {
+        let rows = sqlx::query(
+            "SELECT t.cycle_id, t.timestamp, t.payload FROM telemetry t
+            WHERE t.cycle_id IN (
+                SELECT id FROM cycles
+                WHERE device_id = $1 AND ended_at IS NOT NULL
+                ORDER BY started_at DESC
+                LIMIT $2
+            )
+            AND t.payload ? 'resistance'",
+        )
+        .bind(device_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut cycles: std::collections::BTreeMap<i64, Vec<TelemetryData>> = std::collections::BTreeMap::new();
+        for row in rows {
+            let cycle_id: i64 = row.try_get("cycle_id")?;
+            if let Some(reading) = telemetry_from_row(&row)? {
+                cycles.entry(cycle_id).or_default().push(reading);
+            }
+        }
+
+        Ok(cycles
+            .into_values()
+            .map(|mut readings| {
+                readings.sort_by_key(|reading| reading.timestamp);
+                readings
+            })
+            .collect())
+    }
+
+    async fn save_ekf_parameters(&self, device_id: &str, parameters: &EKFParameters) -> Result<(), CalibrationError> {
+        let value = serde_json::to_value(parameters).map_err(|e| CalibrationError::InvalidParameters {
+            device_id: device_id.to_string(),
+            message: e.to_string(),
+        })?;
+        sqlx::query("UPDATE devices SET configuration = jsonb_set(configuration, '{configuration}', $1) WHERE device_id = $2")
+            .bind(value)
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct Calibrator<C: CalibrationRepository> {
+    repo: C,
+}
+
+impl<C: CalibrationRepository> Calibrator<C> {
+    pub fn new(repo: C) -> Self {
+        Calibrator { repo }
+    }
+
+    /// Fits the model to the device's recent finished cycles and proposes new EKF parameters.
+    /// With `apply` the proposal is saved to the device's configuration, as long as it fits
+    /// the cycles better than the current parameters.
+    pub async fn calibrate(&self, device_id: &str, apply: bool) -> Result<CalibrationResult, CalibrationError> {
+        let current = self.repo.get_ekf_parameters(device_id).await?;
+        if current.initial_state.len() != STATE_DIM
+            || current.initial_covariance.len() != STATE_DIM * STATE_DIM
+            || current.process_noise_covariance.len() != STATE_DIM * STATE_DIM
+        {
+            return Err(CalibrationError::InvalidParameters {
+                device_id: device_id.to_string(),
+                message: "expected a 6 element state and 6x6 covariances".to_string(),
+            });
+        }
+        let start = ModelParameters::from_state(&current.initial_state);
+
+        let cycles: Vec<Samples> = self
+            .repo
+            .get_finished_cycle_readings(device_id, CALIBRATION_CYCLES)
+            .await?
+            .into_iter()
+            .map(|readings| {
+                // Leave out the plateau after the washing was dry.
+                let dry_at = find_actual_completion(&readings);
+                let drying: Vec<TelemetryData> = readings
+                    .into_iter()
+                    .filter(|reading| dry_at.is_none_or(|dry_at| reading.timestamp <= dry_at))
+                    .collect();
+                to_samples(&drying)
+            })
+            .filter(|samples| samples.len() >= MIN_CYCLE_READINGS)
+            .collect();
+
+        if cycles.is_empty() {
+            return Err(CalibrationError::NotEnoughData { device_id: device_id.to_string() });
+        }
+        let shared = fit(&cycles, start).ok_or_else(|| CalibrationError::InvalidParameters {
+            device_id: device_id.to_string(),
+            message: "the current parameters do not produce a finite resistance".to_string(),
+        })?;
+        let fitted = shared.parameters;
+        let measurement_noise = shared.residual_variance;
+
+        let mut proposed = current.clone();
+        proposed.initial_state = vec![fitted.resistance(0.0), fitted.m0, fitted.k, fitted.tau, fitted.m_c, fitted.r_offset];
+        proposed.measurement_noise_covariance = vec![measurement_noise];
+        proposed.initial_covariance[0] = measurement_noise;
+        if cycles.len() >= 2 {
+            let per_cycle: Vec<[f64; 5]> = cycles
+                .iter()
+                .filter_map(|samples| fit(std::slice::from_ref(samples), fitted))
+                .map(|fit| fit.parameters.as_array())
+                .collect();
+            let mean_minutes = cycles.iter().map(|samples| samples.last().map_or(0.0, |(minutes, _)| *minutes)).sum::<f64>()
+                / cycles.len() as f64;
+            let steps = (mean_minutes / current.dt).max(1.0);
+            for i in 0..5 {
+                let index = CONSTANTS_OFFSET + i;
+                let variance = sample_variance(&per_cycle.iter().map(|values| values[i]).collect::<Vec<_>>());
+                proposed.initial_covariance[index * STATE_DIM + index] = variance;
+                // k and tau drift during a cycle, the other constants are fixed per load.
+                if index == 2 || index == 3 {
+                    proposed.process_noise_covariance[index * STATE_DIM + index] = variance / steps;
+                }
+            }
+        }
+
+        let rmse_before = rmse(&start, &cycles);
+        let rmse_after = rmse(&fitted, &cycles);
+        let applied = apply && rmse_after < rmse_before;
+        if applied {
+            self.repo.save_ekf_parameters(device_id, &proposed).await?;
+        }
+        println!(
+            "Calibrated device {} from {} cycles: RMSE {:.0} -> {:.0} ohm{}",
+            device_id,
+            cycles.len(),
+            rmse_before,
+            rmse_after,
+            if applied { ", applied" } else { "" }
+        );
+
+        Ok(CalibrationResult {
+            device_id: device_id.to_string(),
+            cycles_used: cycles.len(),
+            readings_used: cycles.iter().map(Vec::len).sum(),
+            rmse_before,
+            rmse_after,
+            fitted,
+            current,
+            proposed,
+            applied,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::washing_predictor::test_parameters;
+    use chrono::{DateTime, Duration, Utc};
+    use std::sync::Mutex;
+
+    /// A cycle following `model` with a small deterministic wobble, one reading every 2 minutes.
+    fn cycle(model: ModelParameters, start: DateTime<Utc>, readings: usize) -> Vec<TelemetryData> {
+        (0..readings)
+            .map(|i| {
+                let minutes = 2.0 * i as f64;
+                let wobble = if i % 2 == 0 { 50.0 } else { -50.0 };
+                TelemetryData {
+                    timestamp: start + Duration::minutes(2 * i as i64),
+                    resistance: model.resistance(minutes) + wobble,
+                }
+            })
+            .collect()
+    }
+
+    struct MockCalibrationRepository {
+        cycles: Vec<Vec<TelemetryData>>,
+        saved: Mutex<Option<EKFParameters>>,
+    }
+
+    impl CalibrationRepository for MockCalibrationRepository {
+        async fn get_ekf_parameters(&self, _device_id: &str) -> Result<EKFParameters, CalibrationError> {
+            Ok(test_parameters(Default::default()))
+        }
+
+        async fn get_finished_cycle_readings(&self, _device_id: &str, _limit: i64) -> Result<Vec<Vec<TelemetryData>>, CalibrationError> {
+            Ok(self.cycles.clone())
+        }
+
+        async fn save_ekf_parameters(&self, _device_id: &str, parameters: &EKFParameters) -> Result<(), CalibrationError> {
+            *self.saved.lock().unwrap() = Some(parameters.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_solve() {
+        let mut a = [[0.0; 5]; 5];
+        for (i, row) in a.iter_mut().enumerate() {
+            row[i] = (i + 1) as f64;
+            row[(i + 1) % 5] = 1.0;
+        }
+        let x = [1.0, -2.0, 3.0, 0.5, 4.0];
+        let b: [f64; 5] = std::array::from_fn(|i| (0..5).map(|k| a[i][k] * x[k]).sum());
+        let solved = solve(a, b).unwrap();
+        for i in 0..5 {
+            assert!((solved[i] - x[i]).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_fit_follows_the_readings() {
+        // The real load dries faster and settles higher than the configured model.
+        let truth = ModelParameters { m0: 0.02, k: 0.12, tau: 0.81, m_c: 1e-9, r_offset: 35000.0 };
+        let start = ModelParameters::from_state(&test_parameters(Default::default()).initial_state);
+        let cycles = vec![to_samples(&cycle(truth, Utc::now(), 50))];
+
+        let fit = fit(&cycles, start).unwrap();
+        let before = rmse(&start, &cycles);
+        let after = rmse(&fit.parameters, &cycles);
+        assert!(after < before / 10.0, "RMSE {before} -> {after}");
+        assert!(after < 500.0, "RMSE after the fit is {after}");
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_proposes_and_applies() {
+        let truth = ModelParameters { m0: 0.02, k: 0.12, tau: 0.81, m_c: 1e-9, r_offset: 35000.0 };
+        let start = Utc::now() - Duration::days(2);
+        let repo = MockCalibrationRepository {
+            cycles: vec![
+                cycle(truth, start, 50),
+                cycle(ModelParameters { k: 0.11, ..truth }, start + Duration::days(1), 55),
+                // Too short to fit.
+                cycle(truth, start + Duration::hours(30), 5),
+            ],
+            saved: Mutex::new(None),
+        };
+        let calibrator = Calibrator::new(repo);
+
+        let proposal = calibrator.calibrate("wash-1", false).await.unwrap();
+        assert_eq!(proposal.cycles_used, 2);
+        assert_eq!(proposal.readings_used, 105);
+        assert!(proposal.rmse_after < proposal.rmse_before);
+        assert!(!proposal.applied);
+        assert!(calibrator.repo.saved.lock().unwrap().is_none());
+        assert_eq!(proposal.proposed.initial_state.len(), 6);
+        assert_eq!(proposal.proposed.measurement_noise_covariance.len(), 1);
+        assert_eq!(proposal.proposed.dt, 2.0);
+
+        let applied = calibrator.calibrate("wash-1", true).await.unwrap();
+        assert!(applied.applied);
+        let saved = calibrator.repo.saved.lock().unwrap().clone().unwrap();
+        assert_eq!(saved.initial_state, applied.proposed.initial_state);
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_without_cycles() {
+        let calibrator = Calibrator::new(MockCalibrationRepository { cycles: Vec::new(), saved: Mutex::new(None) });
+        let result = calibrator.calibrate("wash-1", true).await;
+        assert!(matches!(result, Err(CalibrationError::NotEnoughData { .. })));
+    }
+}
@@ -0,0 +1,333 @@
+//! Batch curve fit of the drying model over a sliding window of the current cycle.
+//!
+//! Instead of updating a state recursively, `CurveFitModel` keeps the cycle's readings from the
+//! last `WINDOW_MINUTES` and refits the closed form of the process model after every reading:
+//!
+//!   R(t) = (M0 * e^{-k t} - M_c)^{-tau} + R_offset
+//!
+//! with `t` in minutes since the first reading of the cycle, using the same Levenberg-Marquardt
+//! fit as the calibration (`calibration::fit`). Each fit starts from the previous one, and the
+//! first from the configured initial state. The washing is dry when the moisture reaches M_c:
+//!
+//!   t_dry = ln(M0 / M_c) / k
+//!
+//! The uncertainty of the fitted constants (`calibration::parameter_covariance`) is carried to
+//! the remaining time, the state and the forecast to first order. Since every estimate comes
+//! straight from a curve through the recent readings, it is easy to check by hand and makes a
+//! sanity check on the recursive filters.
+
+use crate::calibration::{fit, parameter_covariance, ModelParameters, Samples};
+use crate::drying_model::{
+    forecast_point, forecast_steps, implied_moisture, DryingModel, ModelKind, RemainingTime, Z_90,
+};
+use crate::washing_predictor::{EKFParameters, Forecast, PredictorError};
+use chrono::{DateTime, Utc};
+
+/// Readings older than this, relative to the latest one, are left out of the fit.
+pub const WINDOW_MINUTES: f64 = 120.0;
+/// The curve is not fitted until the window holds this many readings.
+pub const MIN_FIT_READINGS: usize = 10;
+
+/// Number of entries in the state vector `[R, M, k, tau, M_c, R_offset]`.
+const STATE_DIM: usize = 6;
+
+/// The fitted constants as a fit vector, with its covariance.
+#[derive(Debug, Clone, Copy)]
+struct WindowFit {
+    vector: [f64; 5],
+    covariance: [[f64; 5]; 5],
+}
+
+/// The state `[R, M, k, tau, M_c, R_offset]` implied by the fit vector `p`, `minutes` after the
+/// start of the cycle.
+fn state_at(p: &[f64; 5], minutes: f64) -> [f64; STATE_DIM] {
+    let constants = ModelParameters::from_vector(p);
+    let m = (constants.m0 * (-constants.k * minutes).exp()).clamp(1e-9, f64::INFINITY);
+    [
+        constants.resistance(minutes),
+        m,
+        constants.k,
+        constants.tau,
+        constants.m_c,
+        constants.r_offset,
+    ]
+}
+
+/// Whether fitted constants are within the ranges `EKFParameters::validate` accepts for the
+/// initial state. A short or flat window can be fitted just as well by a degenerate curve (e.g.
+/// tau near zero gives a constant), which says nothing about when the washing will be dry.
+fn is_plausible(constants: &ModelParameters) -> bool {
+    let in_range = |value: f64, max: f64| value > 0.0 && value <= max;
+    in_range(constants.m0, 10.0)
+        && in_range(constants.k, 10.0)
+        && in_range(constants.tau, 10.0)
+        && constants.m_c >= 0.0
+        && constants.m_c < constants.m0
+        && constants.r_offset >= 0.0
+}
+
+/// Minutes from the start of the cycle until the moisture of the fit vector `p` reaches M_c.
+fn drying_minutes(p: &[f64; 5]) -> f64 {
+    let constants = ModelParameters::from_vector(p);
+    (constants.m0 / constants.m_c).ln() / constants.k
+}
+
+/// Central difference derivatives of `f` with respect to each entry of the fit vector `p`.
+fn gradient<const N: usize>(p: &[f64; 5], f: impl Fn(&[f64; 5]) -> [f64; N]) -> [[f64; 5]; N] {
+    let mut gradient = [[0.0; 5]; N];
+    for j in 0..5 {
+        let h = 1e-6 * p[j].abs().max(1.0);
+        let mut plus = *p;
+        let mut minus = *p;
+        plus[j] += h;
+        minus[j] -= h;
+        let (up, down) = (f(&plus), f(&minus));
+        for (row, (up, down)) in gradient.iter_mut().zip(up.into_iter().zip(down)) {
+            row[j] = (up - down) / (2.0 * h);
+        }
+    }
+    gradient
+}
+
+/// `G C G^T`, flattened row-major: the covariance of quantities with gradient `G` with
+/// respect to a fit vector with covariance `C`.
+fn transform_covariance<const N: usize>(g: &[[f64; 5]; N], c: &[[f64; 5]; 5]) -> Vec<f64> {
+    let mut result = vec![0.0; N * N];
+    for i in 0..N {
+        for j in 0..N {
+            let mut sum = 0.0;
+            for a in 0..5 {
+                for b in 0..5 {
+                    sum += g[i][a] * c[a][b] * g[j][b];
+                }
+            }
+            // A constant held fixed by the fit has no variance, not NaN.
+            result[i * N + j] = if sum.is_finite() { sum } else { 0.0 };
+        }
+    }
+    result
+}
+
+/// Refits the closed-form drying curve to a sliding window of the cycle's readings.
+pub struct CurveFitModel {
+    parameters: EKFParameters, // Parameters the model was built from, used as the first starting point
+    start: ModelParameters, // Starting point of the next fit: the last fit, or the initial state
+    samples: Samples, // Readings within the window as (minutes since the cycle started, resistance)
+    elapsed_minutes: f64, // Minutes since the cycle started, as of the last reading
+    fit: Option<WindowFit>,
+}
+
+impl CurveFitModel {
+    pub fn new(parameters: &EKFParameters) -> Result<Self, String> {
+        if parameters.initial_state.len() != STATE_DIM {
+            return Err(format!("initial_state must have {STATE_DIM} elements"));
+        }
+        Ok(CurveFitModel {
+            parameters: parameters.clone(),
+            start: ModelParameters::from_state(&parameters.initial_state),
+            samples: Vec::new(),
+            elapsed_minutes: 0.0,
+            fit: None,
+        })
+    }
+
+    /// Fits the curve to the current window from both the previous fit and the configured
+    /// initial state, and keeps the better plausible fit. If neither is plausible the previous
+    /// fit stands.
+    fn refit(&mut self) {
+        if self.samples.len() < MIN_FIT_READINGS {
+            return;
+        }
+        let window = [self.samples.clone()];
+        let initial = ModelParameters::from_state(&self.parameters.initial_state);
+        let Some(result) = [self.start, initial]
+            .into_iter()
+            .filter_map(|start| fit(&window, start))
+            .filter(|result| is_plausible(&result.parameters))
+            .min_by(|a, b| a.residual_variance.total_cmp(&b.residual_variance))
+        else {
+            return;
+        };
+        // Without enough curvature to pin down every constant there is no band, but the fit stands.
+        let covariance = parameter_covariance(&window, &result).unwrap_or([[0.0; 5]; 5]);
+        self.start = result.parameters;
+        self.fit = Some(WindowFit {
+            vector: result.parameters.to_vector(),
+            covariance,
+        });
+    }
+}
+
+impl DryingModel for CurveFitModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::CurveFit
+    }
+
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        self.elapsed_minutes += (elapsed.num_milliseconds() as f64 / 60_000.0).max(0.0);
+        let innovation = match (&self.fit, self.samples.last()) {
+            (Some(fit), _) => resistance - ModelParameters::from_vector(&fit.vector).resistance(self.elapsed_minutes),
+            (None, Some((_, last))) => resistance - last,
+            (None, None) => 0.0,
+        };
+
+        self.samples.push((self.elapsed_minutes, resistance));
+        let oldest = self.elapsed_minutes - WINDOW_MINUTES;
+        self.samples.retain(|(minutes, _)| *minutes >= oldest);
+        self.refit();
+        Ok(innovation)
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        match &self.fit {
+            Some(fit) => Some(ModelParameters::from_vector(&fit.vector).resistance(self.elapsed_minutes)),
+            None => self.samples.last().map(|(_, resistance)| *resistance),
+        }
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        self.expected_resistance().unwrap_or(0.0) - self.start.r_offset
+    }
+
+    /// `t_dry` of the fit minus the time since the cycle started, with the band from the
+    /// uncertainty of the fitted constants.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        let Some(fit) = &self.fit else {
+            return Err(PredictorError::NoEstimate);
+        };
+        let remaining = drying_minutes(&fit.vector) - self.elapsed_minutes;
+        if remaining.is_nan() {
+            return Err(PredictorError::InvalidPrediction);
+        }
+        let minutes = remaining.max(0.0);
+
+        let g = gradient(&fit.vector, |p| [drying_minutes(p)]);
+        let variance = transform_covariance(&g, &fit.covariance)[0];
+        let spread = if variance > 0.0 { Z_90 * variance.sqrt() } else { 0.0 };
+        Ok(RemainingTime {
+            minutes,
+            p10: (remaining - spread).max(0.0),
+            p90: (remaining + spread).max(0.0),
+        })
+    }
+
+    /// Starts the window again from the re-wetted reading, as the closed form cannot describe
+    /// the jump. The fitted k, tau, M_c and R_offset are kept, and M0 becomes the moisture the
+    /// reading implies, so there is an estimate straight away.
+    fn rewet(&mut self, resistance: f64) {
+        let state = self.state();
+        let current = if state.is_empty() { self.parameters.initial_state.clone() } else { state };
+        self.start.m0 = implied_moisture(&current, resistance, self.parameters.initial_state[1]);
+        self.samples = vec![(0.0, resistance)];
+        self.elapsed_minutes = 0.0;
+        if let Some(fit) = &mut self.fit {
+            fit.vector = self.start.to_vector();
+        }
+    }
+
+    fn state(&self) -> Vec<f64> {
+        match &self.fit {
+            Some(fit) => state_at(&fit.vector, self.elapsed_minutes).to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        match &self.fit {
+            Some(fit) => {
+                let g = gradient(&fit.vector, |p| state_at(p, self.elapsed_minutes));
+                transform_covariance(&g, &fit.covariance)
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The fit is rebuilt from the readings, so they are replayed.
+    fn restore(&mut self, _state: &[f64], _covariance: &[f64]) -> bool {
+        false
+    }
+
+    /// Follows the fitted curve forward, with the bands from the uncertainty of the constants.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast> {
+        let fit = self.fit.as_ref()?;
+        let (steps, step, _) = forecast_steps(&self.parameters, horizon_minutes);
+
+        let points = (0..=steps)
+            .map(|i| {
+                let minutes = step * i as f64;
+                let at = self.elapsed_minutes + minutes;
+                let g = gradient(&fit.vector, |p| state_at(p, at));
+                forecast_point(from, minutes, &state_at(&fit.vector, at), &transform_covariance(&g, &fit.covariance))
+            })
+            .collect();
+
+        Some(Forecast {
+            from,
+            step_minutes: step,
+            points,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::washing_predictor::test_parameters;
+
+    /// Readings `readings` of a cycle following `truth` with a small deterministic wobble, one
+    /// every 2 minutes.
+    fn feed(model: &mut CurveFitModel, truth: &ModelParameters, readings: std::ops::Range<usize>) {
+        for i in readings {
+            let elapsed = if i == 0 { chrono::Duration::zero() } else { chrono::Duration::minutes(2) };
+            let wobble = 20.0 * (i as f64 * 1.7).sin();
+            model.ingest(elapsed, truth.resistance(2.0 * i as f64) + wobble).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_curve_fit_recovers_completion_time() {
+        let parameters = test_parameters(ModelKind::CurveFit);
+        let mut model = CurveFitModel::new(&parameters).unwrap();
+        // Drier and faster than the configured starting point.
+        let truth = ModelParameters { m0: 0.015, k: 0.05, tau: 0.9, m_c: 1e-4, r_offset: 29000.0 };
+
+        feed(&mut model, &truth, 0..MIN_FIT_READINGS - 1);
+        assert!(matches!(model.remaining(), Err(PredictorError::NoEstimate)));
+        assert!(model.state().is_empty());
+
+        feed(&mut model, &truth, MIN_FIT_READINGS - 1..40);
+        let elapsed = model.elapsed_minutes;
+        let true_remaining = (truth.m0 / truth.m_c).ln() / truth.k - elapsed;
+        let remaining = model.remaining().unwrap();
+        assert!((remaining.minutes - true_remaining).abs() < 5.0, "{remaining:?} vs {true_remaining}");
+        assert!(remaining.p10 <= remaining.minutes && remaining.minutes <= remaining.p90);
+        assert_eq!(model.covariance().len(), STATE_DIM * STATE_DIM);
+
+        let forecast = model.forecast(Utc::now(), 30.0).unwrap();
+        assert!(forecast.points.last().unwrap().resistance > forecast.points[0].resistance);
+    }
+
+    #[test]
+    fn test_window_drops_old_readings() {
+        let mut model = CurveFitModel::new(&test_parameters(ModelKind::CurveFit)).unwrap();
+        let truth = ModelParameters::from_state(&test_parameters(ModelKind::CurveFit).initial_state);
+        feed(&mut model, &truth, 0..100);
+
+        // One reading every 2 minutes, so the window holds the last 120 minutes.
+        assert_eq!(model.samples.len(), (WINDOW_MINUTES / 2.0) as usize + 1);
+        assert_eq!(model.samples[0].0, model.elapsed_minutes - WINDOW_MINUTES);
+    }
+
+    #[test]
+    fn test_rewet_restarts_the_window() {
+        let mut model = CurveFitModel::new(&test_parameters(ModelKind::CurveFit)).unwrap();
+        let truth = ModelParameters::from_state(&test_parameters(ModelKind::CurveFit).initial_state);
+        feed(&mut model, &truth, 0..30);
+        let before = model.remaining().unwrap().minutes;
+
+        model.rewet(30000.0);
+        assert_eq!(model.samples.len(), 1);
+        assert_eq!(model.expected_resistance().map(f64::round), Some(30000.0));
+        assert!(model.remaining().unwrap().minutes > before);
+    }
+}
@@ -0,0 +1,362 @@
+//! Drying cycles ("loads of washing") as first class records.
+//!
+//! The predictor only knows about a cycle implicitly: its filter is reset when the resistance
+//! jumps, and the time of that reset is reported as `cycle_start`. The `CycleTracker` turns
+//! that into rows in the `cycles` table:
+//!
+//!   - a cycle is opened when the predictor starts a new cycle (wet laundry was hung, which
+//!     shows up as a large resistance drop, or the first reading of a device),
+//!   - it is closed as `dry` once the model considers the washing dry (`DryingModel::is_dry`),
+//!   - it is closed as `collected` if a new load is hung before the previous one dried.
+//!
+//! Every telemetry row and prediction is linked to the cycle it belongs to through `cycle_id`.
+//! Readings that arrive after a cycle was closed as dry stay linked to it until the next load.
+
+use crate::washing_predictor::PredictionDetails;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use rocket_db_pools::sqlx::{self, PgPool, Row};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CycleError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CycleEndReason {
+    /// The filter considered the washing dry.
+    Dry,
+    /// A new load was hung before this one dried, so it must have been taken in.
+    Collected,
+}
+
+impl CycleEndReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CycleEndReason::Dry => "dry",
+            CycleEndReason::Collected => "collected",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "dry" => Some(CycleEndReason::Dry),
+            "collected" => Some(CycleEndReason::Collected),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Cycle {
+    pub id: i64,
+    pub device_id: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub end_reason: Option<CycleEndReason>,
+}
+
+impl Cycle {
+    pub fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        let end_reason: Option<String> = row.try_get("end_reason")?;
+        Ok(Cycle {
+            id: row.try_get("id")?,
+            device_id: row.try_get("device_id")?,
+            started_at: row.try_get("started_at")?,
+            ended_at: row.try_get("ended_at")?,
+            end_reason: end_reason.as_deref().and_then(CycleEndReason::parse),
+        })
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait CycleRepository: Send + Sync {
+    /// The device's most recent cycle, open or closed.
+    async fn get_latest_cycle(&self, device_id: &str) -> Result<Option<Cycle>, CycleError>;
+    async fn open_cycle(&self, device_id: &str, started_at: DateTime<Utc>) -> Result<Cycle, CycleError>;
+    async fn close_cycle(&self, cycle_id: i64, ended_at: DateTime<Utc>, reason: CycleEndReason) -> Result<(), CycleError>;
+    /// Links a telemetry row (if known) and the prediction made from the reading at
+    /// `reading_time` to the cycle.
+    async fn link_reading(
+        &self,
+        cycle_id: i64,
+        device_id: &str,
+        telemetry_id: Option<i64>,
+        reading_time: DateTime<Utc>,
+    ) -> Result<(), CycleError>;
+}
+
+pub struct PostgresCycleRepository {
+    pool: PgPool,
+}
+
+impl PostgresCycleRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl CycleRepository for PostgresCycleRepository {
+    async fn get_latest_cycle(&self, device_id: &str) -> Result<Option<Cycle>, CycleError> {
+        let row = sqlx::query(
+            "SELECT id, RTRIM(device_id) AS device_id, started_at, ended_at, end_reason FROM cycles
+            WHERE device_id = $1
+            ORDER BY started_at DESC
+            LIMIT 1",
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(Cycle::from_row).transpose()?)
+    }
+
+    async fn open_cycle(&self, device_id: &str, started_at: DateTime<Utc>) -> Result<Cycle, CycleError> {
+        let row = sqlx::query(
+            "INSERT INTO cycles (device_id, started_at) VALUES ($1, $2)
+            RETURNING id, RTRIM(device_id) AS device_id, started_at, ended_at, end_reason",
+        )
+        .bind(device_id)
+        .bind(started_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Cycle::from_row(&row)?)
+    }
+
+    async fn close_cycle(&self, cycle_id: i64, ended_at: DateTime<Utc>, reason: CycleEndReason) -> Result<(), CycleError> {
+        sqlx::query("UPDATE cycles SET ended_at = $1, end_reason = $2 WHERE id = $3 AND ended_at IS NULL")
+            .bind(ended_at)
+            .bind(reason.as_str())
+            .bind(cycle_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn link_reading(
+        &self,
+        cycle_id: i64,
+        device_id: &str,
+        telemetry_id: Option<i64>,
+        reading_time: DateTime<Utc>,
+    ) -> Result<(), CycleError> {
+        if let Some(telemetry_id) = telemetry_id {
+            sqlx::query("UPDATE telemetry SET cycle_id = $1 WHERE id = $2")
+                .bind(cycle_id)
+                .bind(telemetry_id)
+                .execute(&self.pool)
+                .await?;
+        }
+        sqlx::query("UPDATE predictions SET cycle_id = $1 WHERE device_id = $2 AND reading_time = $3")
+            .bind(cycle_id)
+            .bind(device_id)
+            .bind(reading_time)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct CycleTracker<C: CycleRepository> {
+    repo: C,
+    latest: DashMap<String, Cycle>, // Most recent cycle keyed by device ID
+}
+
+impl<C: CycleRepository> CycleTracker<C> {
+    pub fn new(repo: C) -> Self {
+        CycleTracker {
+            repo,
+            latest: DashMap::new(),
+        }
+    }
+
+    /// Assigns a resistance reading to a cycle, opening and closing cycles as the predictor's
+    /// view of the load changes. `details` is the predictor's filter state after the reading.
+    /// Returns the cycle the reading belongs to.
+    pub async fn observe(
+        &self,
+        device_id: &str,
+        telemetry_id: Option<i64>,
+        reading_time: DateTime<Utc>,
+        details: &PredictionDetails,
+    ) -> Result<Cycle, CycleError> {
+        let latest = match self.latest.get(device_id).map(|cycle| cycle.clone()) {
+            Some(cycle) => Some(cycle),
+            None => self.repo.get_latest_cycle(device_id).await?,
+        };
+
+        let mut cycle = match latest {
+            Some(cycle) if cycle.started_at == details.cycle_start => cycle,
+            previous => {
+                if let Some(previous) = previous.filter(|previous| previous.ended_at.is_none()) {
+                    println!("Closing cycle {} for device {}: a new load was hung", previous.id, device_id);
+                    self.repo
+                        .close_cycle(previous.id, details.cycle_start, CycleEndReason::Collected)
+                        .await?;
+                }
+                let cycle = self.repo.open_cycle(device_id, details.cycle_start).await?;
+                println!("Opened cycle {} for device {} at {}", cycle.id, device_id, cycle.started_at);
+                cycle
+            }
+        };
+
+        if cycle.ended_at.is_none() && details.dry {
+            println!("Closing cycle {} for device {}: the washing is dry", cycle.id, device_id);
+            self.repo.close_cycle(cycle.id, reading_time, CycleEndReason::Dry).await?;
+            cycle.ended_at = Some(reading_time);
+            cycle.end_reason = Some(CycleEndReason::Dry);
+        }
+
+        self.latest.insert(device_id.to_string(), cycle.clone());
+        self.repo
+            .link_reading(cycle.id, device_id, telemetry_id, reading_time)
+            .await?;
+        Ok(cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drying_model::ModelKind;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockCycleRepository {
+        cycles: Mutex<Vec<Cycle>>,
+        links: Mutex<Vec<(i64, Option<i64>)>>,
+    }
+
+    impl CycleRepository for MockCycleRepository {
+        async fn get_latest_cycle(&self, device_id: &str) -> Result<Option<Cycle>, CycleError> {
+            let cycles = self.cycles.lock().unwrap();
+            Ok(cycles.iter().filter(|cycle| cycle.device_id == device_id).max_by_key(|cycle| cycle.started_at).cloned())
+        }
+
+        async fn open_cycle(&self, device_id: &str, started_at: DateTime<Utc>) -> Result<Cycle, CycleError> {
+            let mut cycles = self.cycles.lock().unwrap();
+            let cycle = Cycle {
+                id: cycles.len() as i64 + 1,
+                device_id: device_id.to_string(),
+                started_at,
+                ended_at: None,
+                end_reason: None,
+            };
+            cycles.push(cycle.clone());
+            Ok(cycle)
+        }
+
+        async fn close_cycle(&self, cycle_id: i64, ended_at: DateTime<Utc>, reason: CycleEndReason) -> Result<(), CycleError> {
+            let mut cycles = self.cycles.lock().unwrap();
+            if let Some(cycle) = cycles.iter_mut().find(|cycle| cycle.id == cycle_id && cycle.ended_at.is_none()) {
+                cycle.ended_at = Some(ended_at);
+                cycle.end_reason = Some(reason);
+            }
+            Ok(())
+        }
+
+        async fn link_reading(
+            &self,
+            cycle_id: i64,
+            _device_id: &str,
+            telemetry_id: Option<i64>,
+            _reading_time: DateTime<Utc>,
+        ) -> Result<(), CycleError> {
+            self.links.lock().unwrap().push((cycle_id, telemetry_id));
+            Ok(())
+        }
+    }
+
+    fn details(cycle_start: DateTime<Utc>, reading_time: DateTime<Utc>, remaining_minutes: Option<f64>, dry: bool) -> PredictionDetails {
+        PredictionDetails {
+            model: ModelKind::Ekf,
+            state: vec![30000.0, 0.02, 0.1, 0.81, 1e-9, 29976.33],
+            covariance_diagonal: vec![0.0; 6],
+            last_innovation: None,
+            cycle_start,
+            last_received_time: reading_time,
+            update_count: 1,
+            rewet_count: 0,
+            remaining_minutes,
+            dry,
+            estimate: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cycle_opens_and_closes_when_dry() {
+        let tracker = CycleTracker::new(MockCycleRepository::default());
+        let start = Utc::now();
+
+        let cycle = tracker.observe("wash-1", Some(1), start, &details(start, start, Some(120.0), false)).await.unwrap();
+        assert_eq!(cycle.started_at, start);
+        assert!(cycle.ended_at.is_none());
+
+        let later = start + chrono::Duration::minutes(60);
+        let same = tracker.observe("wash-1", Some(2), later, &details(start, later, Some(60.0), false)).await.unwrap();
+        assert_eq!(same.id, cycle.id);
+
+        // The moisture has reached M_c, so there is no remaining time left to report.
+        let dry_at = start + chrono::Duration::minutes(122);
+        let dry = tracker.observe("wash-1", Some(3), dry_at, &details(start, dry_at, None, true)).await.unwrap();
+        assert_eq!(dry.id, cycle.id);
+        assert_eq!(dry.ended_at, Some(dry_at));
+        assert_eq!(dry.end_reason, Some(CycleEndReason::Dry));
+
+        // Readings after the washing dried stay with the load.
+        let after = start + chrono::Duration::minutes(130);
+        let still = tracker.observe("wash-1", Some(4), after, &details(start, after, None, true)).await.unwrap();
+        assert_eq!(still.id, cycle.id);
+        assert_eq!(still.ended_at, Some(dry_at));
+
+        assert_eq!(tracker.repo.cycles.lock().unwrap().len(), 1);
+        assert_eq!(*tracker.repo.links.lock().unwrap(), vec![(1, Some(1)), (1, Some(2)), (1, Some(3)), (1, Some(4))]);
+    }
+
+    #[tokio::test]
+    async fn test_cycle_stays_open_without_an_estimate() {
+        let tracker = CycleTracker::new(MockCycleRepository::default());
+        let start = Utc::now();
+
+        // A plateau or curve fit model has no estimate until it has seen enough readings, which
+        // says nothing about the washing being dry.
+        for minutes in [0, 2, 4] {
+            let reading_time = start + chrono::Duration::minutes(minutes);
+            let cycle = tracker.observe("wash-1", None, reading_time, &details(start, reading_time, None, false)).await.unwrap();
+            assert!(cycle.ended_at.is_none());
+        }
+        assert_eq!(tracker.repo.cycles.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_new_load_closes_open_cycle_as_collected() {
+        let tracker = CycleTracker::new(MockCycleRepository::default());
+        let start = Utc::now();
+        tracker.observe("wash-1", None, start, &details(start, start, Some(120.0), false)).await.unwrap();
+
+        let new_start = start + chrono::Duration::minutes(30);
+        let cycle = tracker.observe("wash-1", None, new_start, &details(new_start, new_start, Some(150.0), false)).await.unwrap();
+        assert_eq!(cycle.id, 2);
+
+        let cycles = tracker.repo.cycles.lock().unwrap();
+        assert_eq!(cycles[0].ended_at, Some(new_start));
+        assert_eq!(cycles[0].end_reason, Some(CycleEndReason::Collected));
+        assert!(cycles[1].ended_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_open_cycle_is_picked_up_after_restart() {
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let repo = MockCycleRepository::default();
+        repo.open_cycle("wash-1", start).await.unwrap();
+
+        let tracker = CycleTracker::new(repo);
+        let now = Utc::now();
+        let cycle = tracker.observe("wash-1", None, now, &details(start, now, Some(60.0), false)).await.unwrap();
+        assert_eq!(cycle.id, 1);
+        assert_eq!(tracker.repo.cycles.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,597 @@
+//! The algorithms a `WashingPredictor` can run for a device.
+//!
+//! Every algorithm implements `DryingModel`: it is fed the device's readings one at a time and
+//! answers how long the washing still needs. Cycle handling (new loads, re-wetting, stale
+//! readings), persistence and the prediction records stay in the predictor, so a new algorithm
+//! only has to implement the trait and be added to `ModelKind` and `build_model`.
+//!
+//! The model is picked per device with the `model` field of its (preset or own) configuration:
+//!
+//! - `ekf` (default): the extended Kalman filter over the state `[R, M, k, tau, M_c, R_offset]`
+//!   of `MoistureSensorModel`.
+//! - `ukf`: the unscented Kalman filter over the same state (`unscented::UkfModel`), which
+//!   propagates sigma points through the model instead of using its Jacobians.
+//! - `particle`: a particle filter over the same state (`particle_filter::ParticleModel`), which
+//!   reports the median and percentiles of the particles' completion times.
+//! - `curve_fit`: a Levenberg-Marquardt fit of the closed-form drying curve to a sliding
+//!   window of the cycle's readings (`curve_fit::CurveFitModel`), refitted after every reading.
+//! - `plateau`: the `trigger_algorithms::is_stable_resistance` heuristic. It has no process
+//!   model, so it cannot say how long is left while the washing is drying; it reports the
+//!   washing as dry once the resistance has levelled off.
+//! - `ensemble`: runs the models listed in `ensemble_models` side by side and combines their
+//!   estimates, weighted by their uncertainty and how much they have drifted
+//!   (`ensemble::EnsembleModel`).
+
+use crate::accuracy::{PLATEAU_THRESHOLD, PLATEAU_WINDOW};
+use crate::curve_fit::CurveFitModel;
+use crate::ensemble::EnsembleModel;
+use crate::particle_filter::ParticleModel;
+use crate::prediction_algorithms::MoistureSensorModel;
+use crate::trigger_algorithms::is_stable_resistance;
+use crate::unscented::UkfModel;
+use crate::washing_predictor::{EKFParameters, Forecast, ForecastPoint, PredictorError};
+use chrono::{DateTime, Utc};
+use kalman_filters::{ExtendedKalmanFilter, ExtendedKalmanFilterBuilder, NonlinearSystem};
+
+/// The 90th percentile of the standard normal distribution, used for the p10/p90 band.
+pub(crate) const Z_90: f64 = 1.2815515655446004;
+
+/// Upper bound on predict sub-steps per reading, so a sensor that was away for days does not
+/// stall the predictor. Longer gaps are covered with longer sub-steps instead.
+const MAX_PREDICT_STEPS: usize = 1000;
+
+/// Forecasts are spread over at most this many points.
+const MAX_FORECAST_POINTS: usize = 500;
+
+/// Below this much remaining drying time the washing is considered dry.
+pub const DRY_REMAINING_MINUTES: f64 = 1.0;
+
+/// Which `DryingModel` a device runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    #[default]
+    Ekf,
+    Ukf,
+    Particle,
+    CurveFit,
+    Plateau,
+    Ensemble,
+}
+
+impl ModelKind {
+    pub const ALL: [ModelKind; 6] = [
+        ModelKind::Ekf,
+        ModelKind::Ukf,
+        ModelKind::Particle,
+        ModelKind::CurveFit,
+        ModelKind::Plateau,
+        ModelKind::Ensemble,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModelKind::Ekf => "ekf",
+            ModelKind::Ukf => "ukf",
+            ModelKind::Particle => "particle",
+            ModelKind::CurveFit => "curve_fit",
+            ModelKind::Plateau => "plateau",
+            ModelKind::Ensemble => "ensemble",
+        }
+    }
+
+    /// The kind named `name`, as written by `as_str`.
+    pub fn from_name(name: &str) -> Option<ModelKind> {
+        ModelKind::ALL.into_iter().find(|kind| kind.as_str() == name)
+    }
+}
+
+/// Remaining drying time after the last reading, in minutes, with its p10/p90 band.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RemainingTime {
+    pub minutes: f64,
+    pub p10: f64,
+    pub p90: f64,
+}
+
+/// Common interface of the drying algorithms: ingest a reading, estimate the completion.
+pub trait DryingModel: Send + Sync {
+    fn kind(&self) -> ModelKind;
+
+    /// The model behind the current estimate. Only differs from `kind` for a model that combines
+    /// several others.
+    fn driver(&self) -> ModelKind {
+        self.kind()
+    }
+
+    /// Moves the model over the `elapsed` time since the previous reading (zero for the first
+    /// reading of a cycle) and applies the new resistance. Returns the innovation: the measured
+    /// minus the expected resistance before the reading was applied.
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String>;
+
+    /// Resistance the model expects as of the last reading. New readings are compared against
+    /// it to detect a new load or re-wetting. `None` before the model has seen a reading and has
+    /// no expectation of its own.
+    fn expected_resistance(&self) -> Option<f64>;
+
+    /// The part of `expected_resistance` that is down to moisture, i.e. without the resistance
+    /// of the dry washing.
+    fn moisture_resistance(&self) -> f64;
+
+    /// Remaining drying time as of the last reading.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError>;
+
+    /// Whether the washing is dry as of the last reading. A model without an estimate is not:
+    /// it has not seen enough readings yet, or its state has diverged.
+    fn is_dry(&self) -> bool {
+        self.remaining().is_ok_and(|remaining| remaining.minutes < DRY_REMAINING_MINUTES)
+    }
+
+    /// Starts the moisture estimate again from a re-wetted reading, keeping what has been learned
+    /// about the load.
+    fn rewet(&mut self, resistance: f64);
+
+    /// State vector `[R, M, k, tau, M_c, R_offset]`, or empty if the model has none.
+    fn state(&self) -> Vec<f64>;
+
+    /// Flattened row-major covariance of `state`, or empty if the model has none.
+    fn covariance(&self) -> Vec<f64>;
+
+    /// Overwrites the state with a saved `state` and `covariance`. Returns false if the model
+    /// cannot be restored that way, in which case the stored readings are replayed instead.
+    fn restore(&mut self, state: &[f64], covariance: &[f64]) -> bool;
+
+    /// Expected resistance and moisture from `from` (the last reading) over `horizon_minutes`.
+    /// `None` if the model cannot run forward in time.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast>;
+}
+
+/// Builds the model configured in `parameters`, in its initial state.
+pub fn build_model(parameters: &EKFParameters) -> Result<Box<dyn DryingModel>, String> {
+    Ok(match parameters.model {
+        ModelKind::Ekf => Box::new(EkfModel::new(parameters)?),
+        ModelKind::Ukf => Box::new(UkfModel::new(parameters)?),
+        ModelKind::Particle => Box::new(ParticleModel::new(parameters)?),
+        ModelKind::CurveFit => Box::new(CurveFitModel::new(parameters)?),
+        ModelKind::Plateau => Box::new(PlateauModel::new()),
+        ModelKind::Ensemble => Box::new(EnsembleModel::new(parameters)?),
+    })
+}
+
+/// Minutes until the moisture of the state `[R, M, k, tau, M_c, R_offset]` decays to M_c.
+pub(crate) fn remaining_minutes(state: &[f64]) -> Result<f64, PredictorError> {
+    // state[1] is the CURRENT moisture M(t), not the initial M_0.
+    // We compute how much longer until M decays to M_c:
+    //   M(t) * exp(-k * t_remaining) = M_c
+    //   t_remaining = ln(M(t) / M_c) / k
+    let m = state[1]; // Current moisture (advances each EKF step)
+    let k = state[2];
+    let m_c = state[4];
+
+    let t_remaining = -((m_c / m).ln() / k); // Remaining time until moisture reaches M_c
+
+    if t_remaining.is_nan() || t_remaining < 0.0 {
+        return Err(PredictorError::InvalidPrediction);
+    }
+    Ok(t_remaining)
+}
+
+/// Whether the moisture of the state `[R, M, k, tau, M_c, R_offset]` has reached M_c, or is
+/// less than `DRY_REMAINING_MINUTES` away from it. `remaining_minutes` has no answer for the
+/// former, so the filters check this instead of their remaining time.
+pub(crate) fn state_is_dry(state: &[f64]) -> bool {
+    let (m, m_c) = (state[1], state[4]);
+    if !(m.is_finite() && m_c.is_finite()) {
+        return false;
+    }
+    m <= m_c || remaining_minutes(state).is_ok_and(|minutes| minutes < DRY_REMAINING_MINUTES)
+}
+
+/// Like `remaining_minutes`, but also propagates the uncertainty of M, k and M_c to a p10/p90
+/// band. The remaining time is linearised around the current estimate:
+///   var(t) = g^T P g,  g = [dt/dM, dt/dk, dt/dM_c] = [1/(k M), -t/k, -1/(k M_c)]
+/// and the band is t ± Z_90 * sqrt(var(t)), with the lower end clamped at zero.
+pub(crate) fn remaining_with_band(state: &[f64], covariance: &[f64]) -> Result<RemainingTime, PredictorError> {
+    let t_remaining = remaining_minutes(state)?;
+    let m = state[1];
+    let k = state[2];
+    let m_c = state[4];
+
+    let n = state.len();
+    let indices = [1, 2, 4]; // M, k, M_c
+    let gradient = [1.0 / (k * m), -t_remaining / k, -1.0 / (k * m_c)];
+    let mut variance = 0.0;
+    for (a, &i) in indices.iter().enumerate() {
+        for (b, &j) in indices.iter().enumerate() {
+            variance += gradient[a] * gradient[b] * covariance[i * n + j];
+        }
+    }
+    // A zero variance (e.g. M_c held fixed) gives 0 * inf = NaN terms, so fall back to no band.
+    let spread = if variance.is_finite() && variance > 0.0 { Z_90 * variance.sqrt() } else { 0.0 };
+
+    Ok(RemainingTime {
+        minutes: t_remaining,
+        p10: (t_remaining - spread).max(0.0),
+        p90: t_remaining + spread,
+    })
+}
+
+/// Moisture implied by a reading, from inverting R = (M - M_c)^-tau + R_offset with the given
+/// state's tau, M_c and R_offset. Falls back to `initial_m` if the reading cannot be inverted.
+pub(crate) fn implied_moisture(state: &[f64], resistance: f64, initial_m: f64) -> f64 {
+    let (tau, m_c, r_offset) = (state[3], state[4], state[5]);
+    let implied_m = if resistance > r_offset && tau > 0.0 {
+        (resistance - r_offset).powf(-1.0 / tau) + m_c
+    } else {
+        initial_m
+    };
+    if implied_m.is_finite() { implied_m } else { initial_m }
+}
+
+/// Re-initialises R and M in `x` from a re-wetted reading and restores their initial
+/// uncertainty in `p`, keeping k, tau, M_c and R_offset that have been learned so far.
+pub(crate) fn rewet_state(x: &mut [f64], p: &mut [f64], parameters: &EKFParameters, resistance: f64) {
+    let implied_m = implied_moisture(x, resistance, parameters.initial_state[1]);
+    x[0] = resistance;
+    x[1] = implied_m;
+
+    // Restore the initial uncertainty for R and M so the filter re-converges quickly.
+    let n = x.len();
+    for i in 0..n {
+        for j in 0..n {
+            if i < 2 || j < 2 {
+                p[i * n + j] = parameters.initial_covariance[i * n + j];
+            }
+        }
+    }
+}
+
+/// Splits the real time between readings into predict sub-steps no longer than the configured
+/// `dt`. Returns the number of sub-steps, their length and the process noise Q scaled to that
+/// length (Q is configured per `dt`).
+pub(crate) fn predict_steps(parameters: &EKFParameters, elapsed: chrono::Duration) -> Option<(usize, f64, Vec<f64>)> {
+    let elapsed_minutes = elapsed.num_milliseconds() as f64 / 60_000.0;
+    if elapsed_minutes <= 0.0 {
+        return None;
+    }
+    let nominal_dt = if parameters.dt > 0.0 { parameters.dt } else { elapsed_minutes };
+    let steps = ((elapsed_minutes / nominal_dt).ceil() as usize).clamp(1, MAX_PREDICT_STEPS);
+    let step = elapsed_minutes / steps as f64;
+    Some((steps, step, scaled_process_noise(parameters, step, nominal_dt)))
+}
+
+/// Splits a forecast horizon into at most `MAX_FORECAST_POINTS` steps of about `dt`. Returns the
+/// number of steps, their length and the process noise Q scaled to that length.
+pub(crate) fn forecast_steps(parameters: &EKFParameters, horizon_minutes: f64) -> (usize, f64, Vec<f64>) {
+    let nominal_dt = if parameters.dt > 0.0 { parameters.dt } else { 2.0 };
+    let steps = ((horizon_minutes / nominal_dt).ceil() as usize).clamp(1, MAX_FORECAST_POINTS);
+    let step = horizon_minutes / steps as f64;
+    (steps, step, scaled_process_noise(parameters, step, nominal_dt))
+}
+
+fn scaled_process_noise(parameters: &EKFParameters, step: f64, nominal_dt: f64) -> Vec<f64> {
+    parameters
+        .process_noise_covariance
+        .iter()
+        .map(|q| q * step / nominal_dt)
+        .collect()
+}
+
+/// One forecast point from a state `x` and covariance `p`, `minutes` after `from`.
+pub(crate) fn forecast_point(from: DateTime<Utc>, minutes: f64, x: &[f64], p: &[f64]) -> ForecastPoint {
+    let n = x.len();
+    let resistance_spread = Z_90 * p[0].max(0.0).sqrt();
+    let moisture_spread = Z_90 * p[n + 1].max(0.0).sqrt();
+    ForecastPoint {
+        time: from + chrono::Duration::milliseconds((minutes * 60_000.0) as i64),
+        minutes,
+        resistance: x[0],
+        resistance_p10: (x[0] - resistance_spread).max(0.0),
+        resistance_p90: x[0] + resistance_spread,
+        moisture: x[1],
+        moisture_p10: (x[1] - moisture_spread).max(0.0),
+        moisture_p90: x[1] + moisture_spread,
+    }
+}
+
+/// Builds the `MoistureSensorModel` for the configured initial state.
+pub(crate) fn moisture_sensor_model(parameters: &EKFParameters) -> MoistureSensorModel {
+    MoistureSensorModel {
+        _r: parameters.initial_state[0],
+        _m: parameters.initial_state[1],
+        _k: parameters.initial_state[2],
+        _tau: parameters.initial_state[3],
+        _m_c: parameters.initial_state[4],
+        _r_offset: parameters.initial_state[5],
+    }
+}
+
+/// F P F^T + Q for flattened row-major n×n matrices.
+fn propagate_covariance(p: &[f64], f: &[f64], q: &[f64], n: usize) -> Vec<f64> {
+    let mut fp = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            fp[i * n + j] = (0..n).map(|k| f[i * n + k] * p[k * n + j]).sum();
+        }
+    }
+    let mut result = q.to_vec();
+    for i in 0..n {
+        for j in 0..n {
+            result[i * n + j] += (0..n).map(|k| fp[i * n + k] * f[j * n + k]).sum::<f64>();
+        }
+    }
+    result
+}
+
+/// The extended Kalman filter over `MoistureSensorModel`.
+pub struct EkfModel {
+    ekf: ExtendedKalmanFilter<f64, MoistureSensorModel>,
+    parameters: EKFParameters, // dt and Q for the predict sub-steps, and the covariance a re-wetting resets to
+}
+
+impl EkfModel {
+    pub fn new(parameters: &EKFParameters) -> Result<Self, String> {
+        let ekf = ExtendedKalmanFilterBuilder::new(moisture_sensor_model(parameters))
+            .initial_state(parameters.initial_state.clone())
+            .initial_covariance(parameters.initial_covariance.clone())
+            .process_noise(parameters.process_noise_covariance.clone())
+            .measurement_noise(parameters.measurement_noise_covariance.clone())
+            .dt(parameters.dt)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(EkfModel {
+            ekf,
+            parameters: parameters.clone(),
+        })
+    }
+
+    /// Runs the EKF's predict once per sub-step of `predict_steps`, with its `dt` and Q set to
+    /// the sub-step's.
+    fn advance(&mut self, elapsed: chrono::Duration) {
+        let Some((steps, step, q)) = predict_steps(&self.parameters, elapsed) else {
+            return;
+        };
+        self.ekf.dt = step;
+        self.ekf.Q = q;
+        for _ in 0..steps {
+            self.ekf.predict();
+        }
+    }
+}
+
+impl DryingModel for EkfModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::Ekf
+    }
+
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        self.advance(elapsed);
+        let innovation = resistance - self.ekf.state()[0];
+        self.ekf.update(&[resistance]).map_err(|e| e.to_string())?;
+        Ok(innovation)
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        Some(self.ekf.state()[0])
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        self.ekf.state()[0] - self.ekf.state()[5]
+    }
+
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        remaining_with_band(self.ekf.state(), self.ekf.covariance())
+    }
+
+    fn is_dry(&self) -> bool {
+        state_is_dry(self.ekf.state())
+    }
+
+    /// Re-initialises the resistance and moisture states from a re-wetted reading while keeping
+    /// k, tau, M_c and R_offset that have been learned so far in the cycle.
+    fn rewet(&mut self, resistance: f64) {
+        rewet_state(&mut self.ekf.x, &mut self.ekf.P, &self.parameters, resistance);
+    }
+
+    fn state(&self) -> Vec<f64> {
+        self.ekf.state().to_vec()
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        self.ekf.covariance().to_vec()
+    }
+
+    fn restore(&mut self, state: &[f64], covariance: &[f64]) -> bool {
+        let n = self.ekf.state_dim;
+        if state.len() != n || covariance.len() != n * n {
+            return false;
+        }
+        self.ekf.x = state.to_vec();
+        self.ekf.P = covariance.to_vec();
+        true
+    }
+
+    /// Runs the process model forward from the current state without touching the filter. The
+    /// covariance is propagated the same way as the filter's predict step (P = F P F^T + Q),
+    /// so the bands widen with the horizon.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast> {
+        let n = self.ekf.state_dim;
+        let (steps, step, q) = forecast_steps(&self.parameters, horizon_minutes);
+
+        let mut x = self.ekf.x.clone();
+        let mut p = self.ekf.P.clone();
+        let mut points = Vec::with_capacity(steps + 1);
+        for i in 0..=steps {
+            if i > 0 {
+                let f = self.ekf.system.state_jacobian(&x, None, step);
+                x = self.ekf.system.state_transition(&x, None, step);
+                p = propagate_covariance(&p, &f, &q, n);
+            }
+            points.push(forecast_point(from, step * i as f64, &x, &p));
+        }
+
+        Some(Forecast {
+            from,
+            step_minutes: step,
+            points,
+        })
+    }
+}
+
+/// Declares the washing dry once the last `PLATEAU_WINDOW` readings are stable, the same test
+/// the accuracy evaluation uses to find the actual completion.
+pub struct PlateauModel {
+    readings: Vec<f64>, // The last `PLATEAU_WINDOW` resistances, oldest first
+}
+
+impl PlateauModel {
+    pub fn new() -> Self {
+        PlateauModel {
+            readings: Vec::with_capacity(PLATEAU_WINDOW),
+        }
+    }
+}
+
+impl Default for PlateauModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DryingModel for PlateauModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::Plateau
+    }
+
+    fn ingest(&mut self, _elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        let innovation = self.readings.last().map_or(0.0, |last| resistance - last);
+        if self.readings.len() == PLATEAU_WINDOW {
+            self.readings.remove(0);
+        }
+        self.readings.push(resistance);
+        Ok(innovation)
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        self.readings.last().copied()
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        self.readings.last().copied().unwrap_or(0.0)
+    }
+
+    /// Zero once the resistance has levelled off. Before that the heuristic has no estimate.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        if !self.is_dry() {
+            return Err(PredictorError::NoEstimate);
+        }
+        Ok(RemainingTime {
+            minutes: 0.0,
+            p10: 0.0,
+            p90: 0.0,
+        })
+    }
+
+    fn is_dry(&self) -> bool {
+        self.readings.len() == PLATEAU_WINDOW && is_stable_resistance(&self.readings, PLATEAU_THRESHOLD)
+    }
+
+    fn rewet(&mut self, resistance: f64) {
+        self.readings.clear();
+        self.readings.push(resistance);
+    }
+
+    fn state(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    fn restore(&mut self, _state: &[f64], _covariance: &[f64]) -> bool {
+        false
+    }
+
+    fn forecast(&self, _from: DateTime<Utc>, _horizon_minutes: f64) -> Option<Forecast> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::washing_predictor::test_parameters;
+
+    #[test]
+    fn test_build_model_picks_configured_kind() {
+        for kind in ModelKind::ALL {
+            assert_eq!(build_model(&test_parameters(kind)).unwrap().kind(), kind);
+            assert_eq!(ModelKind::from_name(kind.as_str()), Some(kind));
+        }
+        let configured: EKFParameters = serde_json::from_value(serde_json::json!({
+            "initial_state": [30000.0, 0.02, 0.1, 0.81, 1e-9, 29976.33],
+            "initial_covariance": vec![0.0; 36],
+            "process_noise_covariance": vec![0.0; 36],
+            "measurement_noise_covariance": [1.0e6],
+            "dt": 2.0,
+        }))
+        .unwrap();
+        assert_eq!(configured.model, ModelKind::Ekf);
+    }
+
+    #[test]
+    fn test_ekf_restore_rejects_wrong_dimensions() {
+        let mut model = build_model(&test_parameters(ModelKind::Ekf)).unwrap();
+        assert!(!model.restore(&[1.0; 5], &[0.0; 25]));
+        assert_eq!(model.state(), test_parameters(ModelKind::Ekf).initial_state);
+
+        let mut state = model.state();
+        state[1] = 0.01;
+        assert!(model.restore(&state, &model.covariance()));
+        assert_eq!(model.state(), state);
+    }
+
+    #[test]
+    fn test_ekf_is_dry_once_moisture_reaches_m_c() {
+        let mut model = build_model(&test_parameters(ModelKind::Ekf)).unwrap();
+        assert!(!model.is_dry());
+
+        // Below M_c there is no remaining time, but the washing is dry.
+        let mut state = model.state();
+        state[1] = state[4] / 2.0;
+        assert!(model.restore(&state, &model.covariance()));
+        assert!(model.remaining().is_err());
+        assert!(model.is_dry());
+
+        // A diverged state has no estimate either, and is not dry.
+        state[1] = f64::NAN;
+        assert!(model.restore(&state, &model.covariance()));
+        assert!(!model.is_dry());
+    }
+
+    #[test]
+    fn test_plateau_window_slides() {
+        let mut model = PlateauModel::new();
+        assert_eq!(model.expected_resistance(), None);
+        assert_eq!(model.ingest(chrono::Duration::zero(), 1000.0), Ok(0.0));
+        assert_eq!(model.ingest(chrono::Duration::minutes(2), 1500.0), Ok(500.0));
+        assert_eq!(model.expected_resistance(), Some(1500.0));
+
+        // A window that starts with the rising readings is not yet flat.
+        for _ in 0..PLATEAU_WINDOW - 2 {
+            model.ingest(chrono::Duration::minutes(2), 2000.0).unwrap();
+        }
+        assert!(matches!(model.remaining(), Err(PredictorError::NoEstimate)));
+        assert!(!model.is_dry());
+        for _ in 0..2 {
+            model.ingest(chrono::Duration::minutes(2), 2000.0).unwrap();
+        }
+        assert_eq!(model.remaining().unwrap().minutes, 0.0);
+        assert!(model.is_dry());
+
+        model.rewet(800.0);
+        assert!(matches!(model.remaining(), Err(PredictorError::NoEstimate)));
+        assert_eq!(model.expected_resistance(), Some(800.0));
+    }
+}
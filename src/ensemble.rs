@@ -0,0 +1,320 @@
+//! Runs several drying models side by side and combines their estimates.
+//!
+//! Each model fails in its own way: the EKF can diverge, the curve fit is noisy early in the
+//! cycle and the plateau heuristic only has an answer at the very end. `EnsembleModel` feeds
+//! every reading to each of the models in `ensemble_models` and combines the remaining times
+//! they have an estimate for, weighting each by the inverse of
+//!
+//!   spread^2 + drift
+//!
+//! where `spread` is the model's own uncertainty (the p10/p90 band as a standard deviation, at
+//! least `MIN_SPREAD_MINUTES`) and `drift` is a running mean of the squared distance its
+//! completion time moved between readings. The drift is how wrong the model's previous
+//! estimate turned out to be once the next reading was in, so a model that keeps jumping (a
+//! diverging filter, a fit to too few readings) loses weight, and one that settles gains it.
+//! The model with the largest weight is reported as the one that drove the estimate
+//! (`DryingModel::driver`), and alerts record it.
+//!
+//! The combined band is that of the weighted mixture of the members' estimates, so it widens
+//! when the models disagree even if each of them is confident.
+//!
+//! The new-load and re-wetting detection, the state and the innovation follow the first model
+//! of the list (the EKF by default).
+
+use crate::drying_model::{build_model, DryingModel, ModelKind, RemainingTime, Z_90};
+use crate::washing_predictor::{EKFParameters, Forecast, PredictorError};
+use chrono::{DateTime, Utc};
+
+/// Models an ensemble runs when the parameters do not list them.
+pub const DEFAULT_ENSEMBLE_MODELS: [ModelKind; 3] = [ModelKind::Ekf, ModelKind::CurveFit, ModelKind::Plateau];
+/// Smallest standard deviation a model's estimate is taken to have, in minutes, so a model
+/// without a band (the plateau heuristic, or a filter with fixed states) does not get an
+/// infinite weight.
+const MIN_SPREAD_MINUTES: f64 = 1.0;
+/// Weight of the latest change in the running drift.
+const DRIFT_SMOOTHING: f64 = 0.3;
+
+struct Member {
+    model: Box<dyn DryingModel>,
+    last_completion: Option<f64>, // Completion in minutes since the cycle started, as of the previous reading
+    drift: f64, // Running mean of the squared change of the completion between readings, in minutes²
+}
+
+/// One model's estimate with its weight in the combination.
+struct WeightedEstimate {
+    model: ModelKind,
+    remaining: RemainingTime,
+    spread: f64, // The p10/p90 band as a standard deviation, in minutes
+    weight: f64,
+}
+
+/// The combination of the estimates of several `DryingModel`s.
+pub struct EnsembleModel {
+    members: Vec<Member>,
+    elapsed_minutes: f64, // Minutes since the cycle started, as of the last reading
+}
+
+impl EnsembleModel {
+    pub fn new(parameters: &EKFParameters) -> Result<Self, String> {
+        if parameters.ensemble_models.is_empty() {
+            return Err("ensemble_models must name at least one model".to_string());
+        }
+        let members = parameters
+            .ensemble_models
+            .iter()
+            .map(|&kind| {
+                if kind == ModelKind::Ensemble {
+                    return Err("an ensemble cannot contain another ensemble".to_string());
+                }
+                Ok(Member {
+                    model: build_model(&EKFParameters { model: kind, ..parameters.clone() })?,
+                    last_completion: None,
+                    drift: 0.0,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(EnsembleModel {
+            members,
+            elapsed_minutes: 0.0,
+        })
+    }
+
+    /// The first member, which the cycle handling and the reported state follow.
+    fn primary(&self) -> &dyn DryingModel {
+        self.members[0].model.as_ref()
+    }
+
+    /// The members that currently have an estimate, with their weights.
+    fn weighted_estimates(&self) -> Vec<WeightedEstimate> {
+        self.members
+            .iter()
+            .filter_map(|member| {
+                let remaining = member.model.remaining().ok()?;
+                let spread = ((remaining.p90 - remaining.p10) / (2.0 * Z_90)).max(MIN_SPREAD_MINUTES);
+                let weight = 1.0 / (spread * spread + member.drift);
+                weight.is_finite().then_some(WeightedEstimate {
+                    model: member.model.kind(),
+                    remaining,
+                    spread,
+                    weight,
+                })
+            })
+            .collect()
+    }
+}
+
+impl DryingModel for EnsembleModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::Ensemble
+    }
+
+    /// The member whose estimate carries the most weight.
+    fn driver(&self) -> ModelKind {
+        self.weighted_estimates()
+            .into_iter()
+            .max_by(|a, b| a.weight.total_cmp(&b.weight))
+            .map_or(self.primary().kind(), |estimate| estimate.model)
+    }
+
+    /// Feeds the reading to every member and updates their drift. A member that fails on the
+    /// reading is skipped; the reading only fails if every member does.
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        self.elapsed_minutes += (elapsed.num_milliseconds() as f64 / 60_000.0).max(0.0);
+
+        let mut innovation = None;
+        let mut first_error = None;
+        for member in &mut self.members {
+            match member.model.ingest(elapsed, resistance) {
+                Ok(value) => {
+                    innovation.get_or_insert(value);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(format!("{}: {e}", member.model.kind().as_str()));
+                    continue;
+                }
+            }
+            let Ok(remaining) = member.model.remaining() else {
+                continue;
+            };
+            let completion = self.elapsed_minutes + remaining.minutes;
+            if let Some(last) = member.last_completion {
+                // A completion that has already passed only moves with the clock, which is not
+                // an error of the model, so both are taken from no earlier than now.
+                let change = completion.max(self.elapsed_minutes) - last.max(self.elapsed_minutes);
+                member.drift = (1.0 - DRIFT_SMOOTHING) * member.drift + DRIFT_SMOOTHING * change * change;
+            }
+            member.last_completion = Some(completion);
+        }
+        match (innovation, first_error) {
+            (Some(innovation), _) => Ok(innovation),
+            (None, error) => Err(error.unwrap_or_default()),
+        }
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        self.primary().expected_resistance()
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        self.primary().moisture_resistance()
+    }
+
+    /// The weighted mean of the members' remaining times, with the p10 and p90 of the weighted
+    /// mixture of their estimates: its variance is the members' own variance plus how far they
+    /// are from the mean, `Σ w (spread² + (minutes - mean)²) / Σ w`.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        let estimates = self.weighted_estimates();
+        let total: f64 = estimates.iter().map(|estimate| estimate.weight).sum();
+        if estimates.is_empty() || total <= 0.0 {
+            return Err(PredictorError::NoEstimate);
+        }
+        let minutes = estimates.iter().map(|estimate| estimate.weight * estimate.remaining.minutes).sum::<f64>() / total;
+        let variance = estimates
+            .iter()
+            .map(|estimate| {
+                let distance = estimate.remaining.minutes - minutes;
+                estimate.weight * (estimate.spread * estimate.spread + distance * distance)
+            })
+            .sum::<f64>()
+            / total;
+        let spread = variance.sqrt();
+        Ok(RemainingTime {
+            minutes,
+            p10: (minutes - Z_90 * spread).max(0.0),
+            p90: minutes + Z_90 * spread,
+        })
+    }
+
+    /// Re-wets every member. The jump in their completion times is expected, so it does not
+    /// count towards their drift.
+    fn rewet(&mut self, resistance: f64) {
+        for member in &mut self.members {
+            member.model.rewet(resistance);
+            member.last_completion = None;
+        }
+    }
+
+    fn state(&self) -> Vec<f64> {
+        self.members
+            .iter()
+            .map(|member| member.model.state())
+            .find(|state| !state.is_empty())
+            .unwrap_or_default()
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        self.members
+            .iter()
+            .find(|member| !member.model.state().is_empty())
+            .map(|member| member.model.covariance())
+            .unwrap_or_default()
+    }
+
+    /// A saved state only covers one member, so the readings are replayed to rebuild them all.
+    fn restore(&mut self, _state: &[f64], _covariance: &[f64]) -> bool {
+        false
+    }
+
+    /// The forecast of the driving member, or of the first member that can forecast.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast> {
+        let driver = self.driver();
+        let (driving, others): (Vec<&Member>, Vec<&Member>) =
+            self.members.iter().partition(|member| member.model.kind() == driver);
+        driving
+            .into_iter()
+            .chain(others)
+            .find_map(|member| member.model.forecast(from, horizon_minutes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accuracy::PLATEAU_WINDOW;
+    use crate::washing_predictor::test_parameters;
+
+    #[test]
+    fn test_ensemble_rejects_nested_ensemble() {
+        let mut nested = test_parameters(ModelKind::Ensemble);
+        nested.ensemble_models = vec![ModelKind::Ekf, ModelKind::Ensemble];
+        assert!(EnsembleModel::new(&nested).is_err());
+        nested.ensemble_models.clear();
+        assert!(EnsembleModel::new(&nested).is_err());
+    }
+
+    /// A member with a fixed estimate.
+    struct FixedModel(RemainingTime);
+
+    impl DryingModel for FixedModel {
+        fn kind(&self) -> ModelKind {
+            ModelKind::Plateau
+        }
+        fn ingest(&mut self, _elapsed: chrono::Duration, _resistance: f64) -> Result<f64, String> {
+            Ok(0.0)
+        }
+        fn expected_resistance(&self) -> Option<f64> {
+            None
+        }
+        fn moisture_resistance(&self) -> f64 {
+            0.0
+        }
+        fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+            Ok(self.0)
+        }
+        fn rewet(&mut self, _resistance: f64) {}
+        fn state(&self) -> Vec<f64> {
+            Vec::new()
+        }
+        fn covariance(&self) -> Vec<f64> {
+            Vec::new()
+        }
+        fn restore(&mut self, _state: &[f64], _covariance: &[f64]) -> bool {
+            false
+        }
+        fn forecast(&self, _from: DateTime<Utc>, _horizon_minutes: f64) -> Option<Forecast> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_band_widens_when_members_disagree() {
+        let member = |minutes: f64| Member {
+            model: Box::new(FixedModel(RemainingTime { minutes, p10: minutes - 2.0, p90: minutes + 2.0 })),
+            last_completion: None,
+            drift: 0.0,
+        };
+        let model = EnsembleModel {
+            members: vec![member(20.0), member(80.0)],
+            elapsed_minutes: 0.0,
+        };
+
+        // Each member is sure to within a couple of minutes, but they are an hour apart, so the
+        // band has to cover both of them.
+        let remaining = model.remaining().unwrap();
+        assert!((remaining.minutes - 50.0).abs() < 1e-9, "{remaining:?}");
+        assert!(remaining.p10 < 20.0 && remaining.p90 > 80.0, "{remaining:?}");
+    }
+
+    #[test]
+    fn test_plateau_drives_the_estimate_once_dry() {
+        let mut model = EnsembleModel::new(&test_parameters(ModelKind::Ensemble)).unwrap();
+
+        // While the resistance is still climbing the plateau heuristic has no estimate.
+        for i in 0..20 {
+            let elapsed = if i == 0 { chrono::Duration::zero() } else { chrono::Duration::minutes(2) };
+            model.ingest(elapsed, 30000.0 + 1000.0 * i as f64).unwrap();
+        }
+        let drying = model.remaining().unwrap();
+        assert_ne!(model.driver(), ModelKind::Plateau);
+
+        // Once it has levelled off the plateau heuristic says dry, with a tighter spread than
+        // the other models, and pulls the estimate towards zero.
+        for _ in 0..PLATEAU_WINDOW {
+            model.ingest(chrono::Duration::minutes(2), 49000.0).unwrap();
+        }
+        assert_eq!(model.driver(), ModelKind::Plateau);
+        let dry = model.remaining().unwrap();
+        assert!(dry.minutes < drying.minutes, "{dry:?} vs {drying:?}");
+        assert_eq!(model.state().len(), 6);
+    }
+}
@@ -0,0 +1,354 @@
+//! Detects sensors that have stopped reporting.
+//!
+//! Alerts are otherwise only evaluated when a telemetry message arrives, so a sensor whose
+//! Wi-Fi drops in the middle of a cycle would never produce any notification at all. The
+//! `HeartbeatMonitor` is driven by a background task instead: every tick it refreshes each
+//! device's expected reporting interval and last telemetry time from the database and flags a
+//! device as offline once it has missed `OFFLINE_MISSED_REPORTS` reports in a row.
+//!
+//! The expected interval comes from `reporting_interval_minutes` in the device's
+//! `configuration` JSON if present, otherwise it is learned as the median gap between the
+//! device's most recent telemetry timestamps.
+
+use crate::notifier::{Notification, Notifier, NotifierError, Priority};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use rocket_db_pools::sqlx::{self, PgPool, Row};
+use std::sync::Arc;
+
+/// Used until a device has either configured or sent enough telemetry to learn an interval.
+const DEFAULT_REPORTING_INTERVAL_MINUTES: f64 = 10.0;
+/// A device is offline once this many expected reports have been missed.
+const OFFLINE_MISSED_REPORTS: f64 = 3.0;
+/// Number of recent telemetry timestamps used to learn the reporting interval.
+const INTERVAL_SAMPLE_SIZE: i64 = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeartbeatError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("notification error: {0}")]
+    Notifier(#[from] NotifierError),
+}
+
+/// What the database knows about a device's reporting.
+pub struct DeviceActivity {
+    pub device_id: String,
+    pub configured_interval_minutes: Option<f64>,
+    /// Most recent telemetry timestamps, newest first.
+    pub recent_timestamps: Vec<DateTime<Utc>>,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait HeartbeatRepository: Send + Sync {
+    async fn load_device_activity(&self) -> Result<Vec<DeviceActivity>, HeartbeatError>;
+}
+
+pub struct PostgresHeartbeatRepository {
+    pool: PgPool,
+}
+
+impl PostgresHeartbeatRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+impl HeartbeatRepository for PostgresHeartbeatRepository {
+    async fn load_device_activity(&self) -> Result<Vec<DeviceActivity>, HeartbeatError> {
+        let rows = sqlx::query(
+            "SELECT RTRIM(d.device_id) AS device_id,
+                (d.configuration->>'reporting_interval_minutes')::float8 AS configured_interval_minutes,
+                recent.timestamps
+            FROM devices d
+            LEFT JOIN LATERAL (
+                SELECT array_agg(t.timestamp ORDER BY t.timestamp DESC) AS timestamps
+                FROM (
+                    SELECT timestamp FROM telemetry
+                    WHERE device_id = d.device_id
+                    ORDER BY timestamp DESC
+                    LIMIT $1
+                ) t
+            ) recent ON true",
+        )
+        .bind(INTERVAL_SAMPLE_SIZE)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(DeviceActivity {
+                    device_id: row.try_get("device_id")?,
+                    configured_interval_minutes: row.try_get("configured_interval_minutes")?,
+                    recent_timestamps: row
+                        .try_get::<Option<Vec<DateTime<Utc>>>, _>("timestamps")?
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Median gap between consecutive timestamps (newest first), in minutes.
+fn learn_interval_minutes(timestamps: &[DateTime<Utc>]) -> Option<f64> {
+    let mut gaps: Vec<f64> = timestamps
+        .windows(2)
+        .map(|pair| (pair[0] - pair[1]).num_milliseconds() as f64 / 60_000.0)
+        .filter(|gap| *gap > 0.0)
+        .collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_by(|a, b| a.total_cmp(b));
+    Some(gaps[gaps.len() / 2])
+}
+
+#[derive(Clone, Copy)]
+struct DeviceHeartbeat {
+    last_seen: Option<DateTime<Utc>>,
+    expected_interval_minutes: f64,
+    offline: bool, // Set once the "went quiet" alert has been sent for the current outage
+}
+
+/// Reporting status of a device as exposed through the API.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct DeviceStatus {
+    pub last_seen: Option<DateTime<Utc>>,
+    pub expected_interval_minutes: f64,
+    pub online: bool,
+}
+
+pub struct HeartbeatMonitor<H: HeartbeatRepository, N: Notifier> {
+    repo: H,
+    notifier: Arc<N>,
+    devices: DashMap<String, DeviceHeartbeat>, // Heartbeat state keyed by device ID
+}
+
+impl<H: HeartbeatRepository, N: Notifier> HeartbeatMonitor<H, N> {
+    pub fn new(repo: H, notifier: Arc<N>) -> Self {
+        HeartbeatMonitor {
+            repo,
+            notifier,
+            devices: DashMap::new(),
+        }
+    }
+
+    /// Records that a device has just reported. Clears the offline flag if it was set.
+    pub fn record_seen(&self, device_id: &str, at: DateTime<Utc>) {
+        let mut heartbeat = self.devices.entry(device_id.to_string()).or_insert(DeviceHeartbeat {
+            last_seen: None,
+            expected_interval_minutes: DEFAULT_REPORTING_INTERVAL_MINUTES,
+            offline: false,
+        });
+        if heartbeat.offline {
+            println!("Device {} is back online", device_id);
+        }
+        heartbeat.last_seen = Some(heartbeat.last_seen.map_or(at, |last| last.max(at)));
+        heartbeat.offline = false;
+    }
+
+    pub fn status(&self, device_id: &str, now: DateTime<Utc>) -> Option<DeviceStatus> {
+        self.devices.get(device_id).map(|heartbeat| DeviceStatus {
+            last_seen: heartbeat.last_seen,
+            expected_interval_minutes: heartbeat.expected_interval_minutes,
+            online: !Self::is_overdue(&heartbeat, now),
+        })
+    }
+
+    fn is_overdue(heartbeat: &DeviceHeartbeat, now: DateTime<Utc>) -> bool {
+        match heartbeat.last_seen {
+            Some(last_seen) => {
+                let allowed = heartbeat.expected_interval_minutes * OFFLINE_MISSED_REPORTS;
+                (now - last_seen).num_milliseconds() as f64 / 60_000.0 > allowed
+            }
+            None => true, // Never heard from
+        }
+    }
+
+    /// Reloads the expected intervals and last telemetry times from the repository.
+    /// Devices seen for the first time (e.g. after a restart) that are already overdue are
+    /// flagged offline without an alert, since we cannot know whether one was already sent.
+    pub async fn refresh(&self, now: DateTime<Utc>) -> Result<(), HeartbeatError> {
+        let activity = self.repo.load_device_activity().await?;
+        let known: Vec<String> = activity.iter().map(|a| a.device_id.clone()).collect();
+        for device in activity {
+            let interval = device
+                .configured_interval_minutes
+                .filter(|minutes| *minutes > 0.0)
+                .or_else(|| learn_interval_minutes(&device.recent_timestamps))
+                .unwrap_or(DEFAULT_REPORTING_INTERVAL_MINUTES);
+            let stored_last_seen = device.recent_timestamps.first().copied();
+
+            let mut heartbeat = self.devices.entry(device.device_id).or_insert_with(|| {
+                let mut heartbeat = DeviceHeartbeat {
+                    last_seen: stored_last_seen,
+                    expected_interval_minutes: interval,
+                    offline: false,
+                };
+                heartbeat.offline = Self::is_overdue(&heartbeat, now);
+                heartbeat
+            });
+            heartbeat.expected_interval_minutes = interval;
+            heartbeat.last_seen = heartbeat.last_seen.max(stored_last_seen);
+        }
+        // Forget devices that have been deleted.
+        self.devices.retain(|device_id, _| known.contains(device_id));
+        Ok(())
+    }
+
+    /// Flags devices that have become overdue and sends one "went quiet" alert per outage.
+    /// Returns the IDs of the devices that were newly flagged.
+    pub async fn check(&self, now: DateTime<Utc>) -> Result<Vec<String>, HeartbeatError> {
+        let mut newly_offline = Vec::new();
+        for mut heartbeat in self.devices.iter_mut() {
+            // Devices that never reported are not alerted on; there is nothing that went quiet.
+            if !heartbeat.offline && heartbeat.last_seen.is_some() && Self::is_overdue(&heartbeat, now) {
+                heartbeat.offline = true;
+                newly_offline.push((heartbeat.key().clone(), *heartbeat));
+            }
+        }
+
+        for (device_id, heartbeat) in &newly_offline {
+            let last_seen = heartbeat.last_seen.unwrap_or(now);
+            println!("Device {} went quiet, last seen at {}", device_id, last_seen);
+            let notification = Notification {
+                device_id: device_id.clone(),
+                title: "Washing line sensor went quiet".to_string(),
+                message: format!(
+                    "Device {} has not reported since {} (expected every {:.0} minutes). Check its Wi-Fi and battery.",
+                    device_id,
+                    last_seen.to_rfc3339(),
+                    heartbeat.expected_interval_minutes
+                ),
+                priority: Priority::High,
+            };
+            if let Err(e) = self.notifier.notify(&notification).await {
+                // Clear the flag so the next tick retries the alert.
+                if let Some(mut heartbeat) = self.devices.get_mut(device_id) {
+                    heartbeat.offline = false;
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(newly_offline.into_iter().map(|(device_id, _)| device_id).collect())
+    }
+
+    /// Runs the supervisor loop forever, refreshing and checking every `period`.
+    pub async fn run(self: Arc<Self>, period: std::time::Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh(Utc::now()).await {
+                eprintln!("[heartbeat] Failed to refresh device activity: {e}");
+            }
+            if let Err(e) = self.check(Utc::now()).await {
+                eprintln!("[heartbeat] Failed to check device heartbeats: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::sync::Mutex;
+
+    struct MockHeartbeatRepository {
+        activity: Vec<(String, Option<f64>, Vec<DateTime<Utc>>)>,
+    }
+
+    impl HeartbeatRepository for MockHeartbeatRepository {
+        async fn load_device_activity(&self) -> Result<Vec<DeviceActivity>, HeartbeatError> {
+            Ok(self
+                .activity
+                .iter()
+                .map(|(device_id, configured, timestamps)| DeviceActivity {
+                    device_id: device_id.clone(),
+                    configured_interval_minutes: *configured,
+                    recent_timestamps: timestamps.clone(),
+                })
+                .collect())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockNotifier {
+        sent: Mutex<Vec<Notification>>,
+    }
+
+    impl Notifier for MockNotifier {
+        async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+            self.sent.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    fn every_two_minutes(last: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        (0..10).map(|i| last - Duration::minutes(2 * i)).collect()
+    }
+
+    #[test]
+    fn test_learn_interval_uses_median_gap() {
+        let now = Utc::now();
+        let mut timestamps = every_two_minutes(now);
+        timestamps.push(now - Duration::hours(5)); // One long gap should not skew the result
+        assert_eq!(learn_interval_minutes(&timestamps), Some(2.0));
+        assert_eq!(learn_interval_minutes(&[now]), None);
+    }
+
+    #[tokio::test]
+    async fn test_quiet_device_alerts_once_until_seen_again() {
+        let last = Utc::now();
+        let repo = MockHeartbeatRepository {
+            activity: vec![("wash-1".to_string(), None, every_two_minutes(last))],
+        };
+        let notifier = Arc::new(MockNotifier::default());
+        let monitor = HeartbeatMonitor::new(repo, notifier.clone());
+        monitor.refresh(last).await.unwrap();
+        assert_eq!(monitor.status("wash-1", last).unwrap().expected_interval_minutes, 2.0);
+
+        // Two missed reports is still fine, the third is not.
+        assert!(monitor.check(last + Duration::minutes(5)).await.unwrap().is_empty());
+        assert!(monitor.status("wash-1", last + Duration::minutes(5)).unwrap().online);
+        assert_eq!(monitor.check(last + Duration::minutes(7)).await.unwrap(), vec!["wash-1".to_string()]);
+        assert!(monitor.check(last + Duration::minutes(9)).await.unwrap().is_empty());
+        assert!(!monitor.status("wash-1", last + Duration::minutes(9)).unwrap().online);
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+
+        // Reporting again re-arms the alert.
+        monitor.record_seen("wash-1", last + Duration::minutes(10));
+        assert!(monitor.status("wash-1", last + Duration::minutes(10)).unwrap().online);
+        assert_eq!(monitor.check(last + Duration::minutes(17)).await.unwrap(), vec!["wash-1".to_string()]);
+        assert_eq!(notifier.sent.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_configured_interval_overrides_learned_interval() {
+        let last = Utc::now();
+        let repo = MockHeartbeatRepository {
+            activity: vec![("wash-1".to_string(), Some(30.0), every_two_minutes(last))],
+        };
+        let monitor = HeartbeatMonitor::new(repo, Arc::new(MockNotifier::default()));
+        monitor.refresh(last).await.unwrap();
+        assert!(monitor.check(last + Duration::minutes(60)).await.unwrap().is_empty());
+        assert_eq!(monitor.status("wash-1", last).unwrap().expected_interval_minutes, 30.0);
+    }
+
+    #[tokio::test]
+    async fn test_devices_already_quiet_at_startup_are_not_alerted() {
+        let last = Utc::now() - Duration::days(2);
+        let repo = MockHeartbeatRepository {
+            activity: vec![("wash-1".to_string(), None, every_two_minutes(last))],
+        };
+        let notifier = Arc::new(MockNotifier::default());
+        let monitor = HeartbeatMonitor::new(repo, notifier.clone());
+        let now = Utc::now();
+        monitor.refresh(now).await.unwrap();
+
+        assert!(monitor.check(now).await.unwrap().is_empty());
+        assert!(!monitor.status("wash-1", now).unwrap().online);
+        assert!(notifier.sent.lock().unwrap().is_empty());
+    }
+}
@@ -21,6 +21,18 @@ use std::sync::Arc;
 mod trigger_algorithms;
 mod prediction_algorithms;
 mod washing_predictor;
+mod drying_model;
+mod unscented;
+mod particle_filter;
+mod curve_fit;
+mod ensemble;
+mod notifier;
+mod alerts;
+mod battery;
+mod heartbeat;
+mod cycles;
+mod accuracy;
+mod calibration;
 
 // Define the database connection pool
 #[derive(Database)]
@@ -34,6 +46,67 @@ struct NewDeviceMessage<'r> {
     configuration: Value,
 }
 
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UpdateDeviceMessage {
+    configuration: Value,
+    /// Whether a changed EKF configuration replaces the running filter now or from the next cycle.
+    #[serde(default)]
+    apply: washing_predictor::ApplyConfiguration,
+}
+
+/// Error response of the routes that write a configuration: either a plain status, or `422`
+/// with the list of fields that failed validation.
+#[derive(Responder)]
+enum ConfigurationError {
+    #[response(status = 422)]
+    Invalid(Json<serde_json::Value>),
+    Status(Status),
+}
+
+impl From<Status> for ConfigurationError {
+    fn from(status: Status) -> Self {
+        ConfigurationError::Status(status)
+    }
+}
+
+impl ConfigurationError {
+    fn invalid(errors: Vec<washing_predictor::FieldError>) -> Self {
+        ConfigurationError::Invalid(Json(serde_json::json!({ "errors": errors })))
+    }
+}
+
+/// Validates a device configuration against the preset it names (or the default preset).
+async fn validate_device_configuration(
+    db: &mut Connection<Db>,
+    configuration: &Value,
+) -> Result<(), ConfigurationError> {
+    let preset_name = configuration["preset"]
+        .as_str()
+        .unwrap_or(washing_predictor::DEFAULT_PRESET);
+    let preset: Option<serde_json::Value> = sqlx::query_scalar("SELECT parameters FROM model_presets WHERE name = $1")
+        .bind(preset_name)
+        .fetch_optional(&mut ***db)
+        .await
+        .map_err(|e| { eprintln!("[validate_device_configuration] DB error: {e}"); Status::InternalServerError })?;
+
+    let errors = washing_predictor::validate_configuration(configuration, preset.as_ref());
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        println!("Rejected device configuration: {:?}", errors);
+        Err(ConfigurationError::invalid(errors))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PresetMessage {
+    #[serde(default)]
+    description: String,
+    parameters: Value,
+}
+
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct NewTelemetryMessage<'r> {
@@ -61,26 +134,136 @@ impl<'r> FromRow<'r, sqlx::postgres::PgRow> for TelemetryRecord {
     }
 }
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct PredictionRecord {
+    device_id: String,
+    cycle_id: Option<i64>,
+    reading_time: chrono::DateTime<chrono::Utc>,
+    resistance: f64,
+    cycle_start: chrono::DateTime<chrono::Utc>,
+    event: String,
+    model: String,
+    completion_time: chrono::DateTime<chrono::Utc>,
+    p10: chrono::DateTime<chrono::Utc>,
+    p90: chrono::DateTime<chrono::Utc>,
+    state: Vec<f64>,
+    covariance_diagonal: Vec<f64>,
+}
+
+impl<'r> FromRow<'r, sqlx::postgres::PgRow> for PredictionRecord {
+    fn from_row(row: &'r sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(PredictionRecord {
+            device_id: row.try_get("device_id")?,
+            cycle_id: row.try_get("cycle_id")?,
+            reading_time: row.try_get("reading_time")?,
+            resistance: row.try_get("resistance")?,
+            cycle_start: row.try_get("cycle_start")?,
+            event: row.try_get("event")?,
+            model: row.try_get("model")?,
+            completion_time: row.try_get("completion_time")?,
+            p10: row.try_get("completion_p10")?,
+            p90: row.try_get("completion_p90")?,
+            state: row.try_get("state")?,
+            covariance_diagonal: row.try_get("covariance_diagonal")?,
+        })
+    }
+}
 
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct AlertRecord {
+    device_id: String,
+    stage_name: String,
+    title: String,
+    cycle_start: chrono::DateTime<chrono::Utc>,
+    completion_time: chrono::DateTime<chrono::Utc>,
+    p10: chrono::DateTime<chrono::Utc>,
+    p90: chrono::DateTime<chrono::Utc>,
+    model: String,
+    sent_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl<'r> FromRow<'r, sqlx::postgres::PgRow> for AlertRecord {
+    fn from_row(row: &'r sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(AlertRecord {
+            device_id: row.try_get("device_id")?,
+            stage_name: row.try_get("stage_name")?,
+            title: row.try_get("title")?,
+            cycle_start: row.try_get("cycle_start")?,
+            completion_time: row.try_get("completion_time")?,
+            p10: row.try_get("completion_p10")?,
+            p90: row.try_get("completion_p90")?,
+            model: row.try_get("model")?,
+            sent_at: row.try_get("sent_at")?,
+        })
+    }
+}
 
 
-pub async fn process_telemetry(pool: sqlx::PgPool, predictor: Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>, device_id: String, payload: Value) {
+
+/// The `telemetry` row a message was stored as.
+#[derive(Debug, Clone, Copy)]
+pub struct StoredTelemetry {
+    pub id: i64,
+    /// When the row was stored. Used as the reading's time if the payload has none, so a replay
+    /// of the stored rows gives the same reading.
+    pub received_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub async fn process_telemetry<
+    R: washing_predictor::DeviceRepository,
+    A: alerts::AlertRepository,
+    N: notifier::Notifier,
+    C: cycles::CycleRepository,
+>(
+    predictor: Arc<washing_predictor::WashingPredictor<R>>,
+    alerts: Arc<alerts::AlertManager<A, N>>,
+    battery: Arc<battery::BatteryMonitor<N>>,
+    cycles: Arc<cycles::CycleTracker<C>>,
+    stored: Option<StoredTelemetry>,
+    device_id: String,
+    payload: Value,
+) {
     println!("Processing telemetry for device {}", device_id);
-    let timestamp = &payload["timestamp"];
-    let resistance = &payload["resistance"];
+    let timestamp = payload["timestamp"]
+        .as_str()
+        .and_then(|s| s.parse::<chrono::DateTime<chrono::Utc>>().ok())
+        .or(stored.map(|stored| stored.received_at))
+        .unwrap_or_else(chrono::Utc::now);
+    let telemetry_id = stored.map(|stored| stored.id);
 
-    match (resistance.as_f64()) {
-        (Some(res)) => {}
-        _ => return, // If resistance is not a valid f64, exit early. i.e. battery voltage message.
+    if let Some(voltage) = battery::parse_voltage(&payload) {
+        match battery.record_voltage(&device_id, voltage, timestamp).await {
+            Ok(true) => println!("Low battery alert sent successfully for device {}", device_id),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to send low battery alert for device {}: {}", device_id, e),
+        }
     }
 
+    let resistance = match payload["resistance"].as_f64() {
+        Some(res) => res,
+        None => return, // If resistance is not a valid f64, exit early. i.e. battery voltage message.
+    };
+
     let telemetry_data = washing_predictor::TelemetryData {
-        timestamp: timestamp.as_str().unwrap_or(&chrono::Utc::now().to_rfc3339()).parse::<chrono::DateTime<chrono::Utc>>().unwrap(),
-        resistance: resistance.as_f64().unwrap(),
+        timestamp,
+        resistance,
     };
 
-    let completion_time = match predictor.predict_drying_time(&device_id, telemetry_data).await {
-        Ok(completion_time) => completion_time,
+    let result = predictor.predict_drying_time(&device_id, telemetry_data).await;
+
+    // Assign the reading to a cycle. This also runs when no completion time could be estimated,
+    // which is how the cycle notices the washing is dry. Stale readings were not applied.
+    if !matches!(result, Err(washing_predictor::PredictorError::StaleReading { .. }))
+        && let Some(details) = predictor.get_prediction_details(&device_id)
+        && let Err(e) = cycles.observe(&device_id, telemetry_id, timestamp, &details).await
+    {
+        eprintln!("Failed to update the drying cycle for device {}: {}", device_id, e);
+    }
+
+    let prediction = match result {
+        Ok(prediction) => prediction,
         Err(error) => {
             eprintln!(
                 "Failed to predict drying time for device {}: {}",
@@ -90,34 +273,29 @@ pub async fn process_telemetry(pool: sqlx::PgPool, predictor: Arc<washing_predic
         }
     };
 
-    println!("Predicted drying time for device {}: {:?}", device_id, completion_time);
-    
-    if completion_time.signed_duration_since(chrono::Utc::now()).num_minutes() < 5 {
-        println!("Alert: Device {} is predicted to be dry in less than 5 minutes!", device_id);
-        let ntfy_topic = std::env::var("NTFY_TOPIC").expect("NTFY_TOPCI must be set");
-        let client = reqwest::Client::new();
-    
-        let response = client
-            .post(format!("https://ntfy.sh/{}", ntfy_topic))
-            .header("Title", "Washing Complete :)")
-            .header("Priority", "default")
-            .body(format!("Device {} reported stable resistance", device_id))
-            .send()
-            .await;
-    
-        if response.as_ref().unwrap().status().is_success() {
-            println!("Alert sent successfully for device {}", device_id);
-        } else {
-            eprintln!(
-                "Failed to send alert for device {}: {:?}",
-                device_id, response
-            );
+    println!("Predicted drying time for device {}: {:?}", device_id, prediction);
+
+    match prediction.event {
+        washing_predictor::CycleEvent::Rewetting => {
+            match alerts.notify_rewetting(&device_id, prediction.cycle_start, prediction.estimate, chrono::Utc::now()).await {
+                Ok(()) => println!("Rain alert sent successfully for device {}", device_id),
+                Err(e) => eprintln!("Failed to send rain alert for device {}: {}", device_id, e),
+            }
+            return;
         }
+        // The reading was held back from the filter, so the estimate has not moved.
+        washing_predictor::CycleEvent::SuspectedRewetting => return,
+        washing_predictor::CycleEvent::Continuing | washing_predictor::CycleEvent::NewCycle => {}
     }
 
+    match alerts.check_completion(&device_id, prediction.cycle_start, prediction.estimate, chrono::Utc::now()).await {
+        Ok(Some(title)) => println!("Alert \"{}\" sent successfully for device {}", title, device_id),
+        Ok(None) => {}
+        Err(e) => eprintln!("Failed to send alert for device {}: {}", device_id, e),
+    }
 }
 
-pub async fn process_telemetry_old(pool: sqlx::PgPool, device_id: String, _payload: Value) {
+pub async fn process_telemetry_old<N: notifier::Notifier>(pool: sqlx::PgPool, notifier: Arc<N>, device_id: String, _payload: Value) {
     println!("Processing telemetry for device {}", device_id);
 
     let  historical_telemetry_data = sqlx::query_as::<_, TelemetryRecord>(
@@ -153,25 +331,16 @@ pub async fn process_telemetry_old(pool: sqlx::PgPool, device_id: String, _paylo
 
     if trigger_algorithms::is_stable_resistance(&data_point_list, 1.0) {
         println!("Alert: Device {} reported stable resitance", device_id);
-        // Here you could add code to send an alert (e.g., email, SMS, push notification)
-        let ntfy_topic = std::env::var("NTFY_TOPIC").expect("NTFY_TOPCI must be set");
-        let client = reqwest::Client::new();
-
-        let response = client
-            .post(format!("https://ntfy.sh/{}", ntfy_topic))
-            .header("Title", "Washing Complete :)")
-            .header("Priority", "default")
-            .body(format!("Device {} reported stable resistance", device_id))
-            .send()
-            .await;
-
-        if response.as_ref().unwrap().status().is_success() {
-            println!("Alert sent successfully for device {}", device_id);
-        } else {
-            eprintln!(
-                "Failed to send alert for device {}: {:?}",
-                device_id, response
-            );
+        let notification = notifier::Notification {
+            device_id: device_id.clone(),
+            title: "Washing Complete :)".to_string(),
+            message: format!("Device {} reported stable resistance", device_id),
+            priority: notifier::Priority::Default,
+        };
+
+        match notifier.notify(&notification).await {
+            Ok(()) => println!("Alert sent successfully for device {}", device_id),
+            Err(e) => eprintln!("Failed to send alert for device {}: {}", device_id, e),
         }
     }
 }
@@ -204,8 +373,9 @@ async fn get_devices(mut db: Connection<Db>) -> Result<Json<Vec<serde_json::Valu
 async fn create_device(
     mut db: Connection<Db>,
     message: Json<NewDeviceMessage<'_>>,
-) -> Result<Status, Status> {
+) -> Result<Status, ConfigurationError> {
     println!("Creating device with ID: {}", message.device_id);
+    validate_device_configuration(&mut db, &message.configuration).await?;
     let result = sqlx::query("INSERT INTO devices (device_id, configuration) VALUES ($1, $2)")
         .bind(message.device_id)
         .bind(message.configuration.clone())
@@ -223,15 +393,15 @@ async fn create_device(
                 Error::Database(db_err) => {
                     if db_err.message().contains("duplicate key value") {
                         println!("Device with ID {} already exists", message.device_id);
-                        return Err(Status::Conflict);
+                        return Err(Status::Conflict.into());
                     }
 
                     println!("Failed to create device: {}", db_err.message());
-                    Err(Status::BadRequest)
+                    Err(Status::BadRequest.into())
                 }
                 _ => {
                     println!("Failed to create device: {}", e);
-                    Err(Status::BadRequest)
+                    Err(Status::BadRequest.into())
                 }
             }
         }
@@ -241,6 +411,8 @@ async fn create_device(
 #[get("/devices/<device_id>")]
 async fn get_device(
     mut db: Connection<Db>,
+    battery: &rocket::State<Arc<battery::BatteryMonitor<notifier::NotifierSet>>>,
+    heartbeat: &rocket::State<Arc<heartbeat::HeartbeatMonitor<heartbeat::PostgresHeartbeatRepository, notifier::NotifierSet>>>,
     device_id: String,
 ) -> Result<Json<serde_json::Value>, Status> {
     let row = sqlx::query("SELECT RTRIM(device_id) AS device_id, configuration FROM devices WHERE device_id = $1")
@@ -250,9 +422,20 @@ async fn get_device(
         .map_err(|e| { eprintln!("[get_device] DB error: {e}"); Status::InternalServerError })?;
     match row {
         Some(row) => {
+            // The battery state is kept in memory, so after a restart fall back to the latest
+            // battery message stored for the device.
+            let battery_state = match battery.get_state(&device_id) {
+                Some(state) => Some(state),
+                None => latest_stored_battery_state(&mut db, &device_id).await?,
+            };
+            let status = heartbeat.status(&device_id, chrono::Utc::now());
             let device = serde_json::json!({
                 "device_id": row.get::<String, _>("device_id"),
                 "configuration": row.get::<serde_json::Value, _>("configuration"),
+                "battery": battery_state,
+                "last_seen": status.and_then(|status| status.last_seen),
+                "online": status.is_some_and(|status| status.online),
+                "expected_interval_minutes": status.map(|status| status.expected_interval_minutes),
             });
             Ok(Json(device))
         }
@@ -263,6 +446,28 @@ async fn get_device(
     }
 }
 
+async fn latest_stored_battery_state(
+    db: &mut Connection<Db>,
+    device_id: &str,
+) -> Result<Option<battery::BatteryState>, Status> {
+    let record = sqlx::query_as::<_, TelemetryRecord>(
+        "SELECT RTRIM(device_id) AS device_id, payload, timestamp FROM telemetry
+        WHERE device_id = $1
+        AND (payload ? 'battery_voltage' OR payload ? 'voltage')
+        ORDER BY timestamp DESC
+        LIMIT 1",
+    )
+    .bind(device_id)
+    .fetch_optional(&mut ***db)
+    .await
+    .map_err(|e| { eprintln!("[get_device] DB error: {e}"); Status::InternalServerError })?;
+
+    Ok(record.and_then(|record| {
+        battery::parse_voltage(&record.payload)
+            .map(|voltage| battery::BatteryState::new(voltage, record.timestamp))
+    }))
+}
+
 #[delete("/devices/<device_id>")]
 async fn delete_device(mut db: Connection<Db>, device_id: String) -> Result<Status, Status> {
     let row = sqlx::query("DELETE FROM devices WHERE device_id = $1 RETURNING device_id")
@@ -284,26 +489,110 @@ async fn delete_device(mut db: Connection<Db>, device_id: String) -> Result<Stat
 async fn update_device_configuration(
     mut db: Connection<Db>,
     device_id: String,
-    message: Json<NewDeviceMessage<'_>>,
-) -> Result<Status, Status> {
+    message: Json<UpdateDeviceMessage>,
+) -> Result<Status, ConfigurationError> {
+    validate_device_configuration(&mut db, &message.configuration).await?;
+
+    // The `devices` trigger notifies the predictor (`listen_for_configuration_changes`) when the
+    // transaction commits, with `apply` taken from the transaction's setting.
+    let db_error = |e: sqlx::Error| { eprintln!("[update_device_configuration] DB error: {e}"); Status::InternalServerError };
+    let mut tx = sqlx::Connection::begin(&mut **db).await.map_err(db_error)?;
+    sqlx::query("SELECT set_config($1, $2, true)")
+        .bind(washing_predictor::APPLY_CONFIGURATION_SETTING)
+        .bind(message.apply.as_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
     let row = sqlx::query(
         "UPDATE devices SET configuration = $1 WHERE device_id = $2 RETURNING device_id",
     )
     .bind(&message.configuration)
     .bind(&device_id)
-    .fetch_optional(&mut **db)
+    .fetch_optional(&mut *tx)
     .await
-    .map_err(|e| { eprintln!("[update_device_configuration] DB error: {e}"); Status::InternalServerError })?;
+    .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
 
     match row {
         Some(_) => Ok(Status::Ok),
         None => {
             println!("Device with ID {} not found for update", device_id);
+            Err(Status::NotFound.into())
+        }
+    }
+}
+
+// Model preset routes
+#[get("/presets")]
+async fn get_presets(mut db: Connection<Db>) -> Result<Json<Vec<serde_json::Value>>, Status> {
+    let rows = sqlx::query("SELECT name, description, parameters FROM model_presets ORDER BY name")
+        .fetch_all(&mut **db)
+        .await
+        .map_err(|e| { eprintln!("[get_presets] DB error: {e}"); Status::InternalServerError })?;
+
+    let presets: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            serde_json::json!({
+                "name": row.get::<String, _>("name"),
+                "description": row.get::<String, _>("description"),
+                "parameters": row.get::<serde_json::Value, _>("parameters"),
+            })
+        })
+        .collect();
+    Ok(Json(presets))
+}
+
+#[get("/presets/<name>")]
+async fn get_preset(mut db: Connection<Db>, name: &str) -> Result<Json<serde_json::Value>, Status> {
+    let row = sqlx::query("SELECT name, description, parameters FROM model_presets WHERE name = $1")
+        .bind(name)
+        .fetch_optional(&mut **db)
+        .await
+        .map_err(|e| { eprintln!("[get_preset] DB error: {e}"); Status::InternalServerError })?;
+
+    match row {
+        Some(row) => Ok(Json(serde_json::json!({
+            "name": row.get::<String, _>("name"),
+            "description": row.get::<String, _>("description"),
+            "parameters": row.get::<serde_json::Value, _>("parameters"),
+        }))),
+        None => {
+            println!("Preset {} not found", name);
             Err(Status::NotFound)
         }
     }
 }
 
+#[put("/presets/<name>", format = "json", data = "<message>")]
+async fn put_preset(mut db: Connection<Db>, name: &str, message: Json<PresetMessage>) -> Result<Status, ConfigurationError> {
+    // A preset must be complete on its own, devices only override parts of it.
+    let errors = match washing_predictor::resolve_ekf_parameters(Some(&message.parameters), None) {
+        Ok(parameters) => parameters
+            .validate()
+            .into_iter()
+            .map(|error| washing_predictor::FieldError { field: format!("parameters.{}", error.field), ..error })
+            .collect(),
+        Err(e) => vec![washing_predictor::FieldError { field: "parameters".to_string(), message: e.to_string() }],
+    };
+    if !errors.is_empty() {
+        println!("Rejected preset {}: {:?}", name, errors);
+        return Err(ConfigurationError::invalid(errors));
+    }
+
+    sqlx::query(
+        "INSERT INTO model_presets (name, description, parameters) VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET description = EXCLUDED.description, parameters = EXCLUDED.parameters",
+    )
+    .bind(name)
+    .bind(&message.description)
+    .bind(&message.parameters)
+    .execute(&mut **db)
+    .await
+    .map_err(|e| { eprintln!("[put_preset] DB error: {e}"); Status::InternalServerError })?;
+    Ok(Status::Ok)
+}
+
 #[get("/devices/<device_id>/completion_time")]
 async fn get_device_completion_time(
     predictor: &rocket::State<Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>>,
@@ -315,42 +604,90 @@ async fn get_device_completion_time(
         None => Err(Status::NotFound),
     }
 }
+
+#[get("/devices/<device_id>/prediction")]
+async fn get_device_prediction(
+    predictor: &rocket::State<Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>>,
+    device_id: String,
+) -> Result<Json<serde_json::Value>, Status> {
+    match predictor.get_prediction_details(&device_id) {
+        Some(details) => Ok(Json(serde_json::json!({
+            "device_id": device_id,
+            "model": details.model,
+            "state": details.state,
+            "covariance_diagonal": details.covariance_diagonal,
+            "last_innovation": details.last_innovation,
+            "cycle_start": details.cycle_start,
+            "last_received_time": details.last_received_time,
+            "update_count": details.update_count,
+            "rewet_count": details.rewet_count,
+            "remaining_minutes": details.remaining_minutes,
+            "completion_time": details.estimate.map(|estimate| estimate.completion_time),
+            "p10": details.estimate.map(|estimate| estimate.p10),
+            "p90": details.estimate.map(|estimate| estimate.p90),
+            "driver": details.estimate.map(|estimate| estimate.model),
+        }))),
+        None => Err(Status::NotFound),
+    }
+}
+/// `horizon` is in minutes. Without it the forecast runs until the predicted completion.
+#[get("/devices/<device_id>/forecast?<horizon>")]
+async fn get_device_forecast(
+    predictor: &rocket::State<Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>>,
+    device_id: String,
+    horizon: Option<f64>,
+) -> Result<Json<washing_predictor::Forecast>, Status> {
+    if horizon.is_some_and(|horizon| !horizon.is_finite() || horizon <= 0.0) {
+        return Err(Status::BadRequest);
+    }
+    match predictor.get_forecast(&device_id, horizon) {
+        Some(forecast) => Ok(Json(forecast)),
+        None => Err(Status::NotFound),
+    }
+}
+
 // Telemetry data routes
 #[post("/telemetry", format = "json", data = "<message>")]
 async fn post_telemetry(
     mut db: Connection<Db>,
-    pool: &rocket::State<sqlx::PgPool>,
     predictor: &rocket::State<Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>>,
+    alerts: &rocket::State<Arc<alerts::AlertManager<alerts::PostgresAlertRepository, notifier::NotifierSet>>>,
+    battery: &rocket::State<Arc<battery::BatteryMonitor<notifier::NotifierSet>>>,
+    heartbeat: &rocket::State<Arc<heartbeat::HeartbeatMonitor<heartbeat::PostgresHeartbeatRepository, notifier::NotifierSet>>>,
+    cycles: &rocket::State<Arc<cycles::CycleTracker<cycles::PostgresCycleRepository>>>,
     message: Json<NewTelemetryMessage<'_>>,
 ) -> Result<Status, Status> {
-    let _result = sqlx::query("INSERT INTO telemetry (device_id, payload) VALUES ($1, $2)")
-        .bind(message.device_id)
-        .bind(message.payload.clone())
-        .execute(&mut **db)
-        .await
-        .map_err(|e| {
-            if let sqlx::Error::Database(ref db_err) = e {
-                if db_err.constraint() == Some("fk_device") {
+    let (telemetry_id, received_at): (i64, chrono::DateTime<chrono::Utc>) =
+        sqlx::query_as("INSERT INTO telemetry (device_id, payload) VALUES ($1, $2) RETURNING id, timestamp")
+            .bind(message.device_id)
+            .bind(message.payload.clone())
+            .fetch_one(&mut **db)
+            .await
+            .map_err(|e| {
+                if let sqlx::Error::Database(ref db_err) = e
+                    && db_err.constraint() == Some("fk_device")
+                {
                     eprintln!("[post_telemetry] Unknown device '{}': foreign key violation", message.device_id);
                     return Status::NotFound;
                 }
-            }
-            eprintln!("[post_telemetry] DB error: {e}");
-            Status::InternalServerError
-        })?;
+                eprintln!("[post_telemetry] DB error: {e}");
+                Status::InternalServerError
+            })?;
+
+    heartbeat.record_seen(message.device_id, chrono::Utc::now());
 
     // start async processing of telemetry data here (e.g., spawn a task)
 
     let device_id = message.device_id.to_string(); // Convert &str to String for 'static lifetime
     let payload = message.payload.clone(); // Clone the JSON value
-    let pool_clone = pool.inner().clone(); // Extract the underlying PgPool from the Connection wrapper
     let predictor = predictor.inner().clone(); // Extract the WashingPredictor from the State wrapper
-    
+    let alerts = alerts.inner().clone(); // Extract the AlertManager from the State wrapper
+    let battery = battery.inner().clone(); // Extract the BatteryMonitor from the State wrapper
+    let cycles = cycles.inner().clone(); // Extract the CycleTracker from the State wrapper
 
 
     tokio::spawn(async move {
-        process_telemetry(pool_clone,predictor, device_id, payload).await;
-        // predictor.predict_drying_time(&device_id, telemetry_data).await;
+        process_telemetry(predictor, alerts, battery, cycles, Some(StoredTelemetry { id: telemetry_id, received_at }), device_id, payload).await;
     });
 
     Ok(Status::Created)
@@ -364,13 +701,9 @@ fn parse_timestamp(s: &str) -> Result<chrono::NaiveDateTime, Box<dyn Error>> {
         .map_err(|e| Box::new(e) as Box<dyn Error>)
 }
 
-#[get("/telemetry/<device_id>?<start_time>&<end_time>")]
-async fn get_telemetry(
-    mut db: Connection<Db>,
-    device_id: &str,
-    start_time: Option<String>,
-    end_time: Option<String>,
-) -> Result<Json<Vec<TelemetryRecord>>, Status> {
+/// Start and end of a `?start_time=&end_time=` filter. Missing or unparseable bounds leave
+/// that side of the range open.
+fn parse_time_range(start_time: Option<&str>, end_time: Option<&str>) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
     let default_start = || {
         chrono::NaiveDate::from_ymd_opt(1970, 1, 1)
             .unwrap()
@@ -386,15 +719,25 @@ async fn get_telemetry(
     };
 
     let start = start_time
-        .as_ref()
         .and_then(|s| parse_timestamp(s).ok())
         .unwrap_or_else(default_start);
 
     let end = end_time
-        .as_ref()
         .and_then(|s| parse_timestamp(s).ok())
         .unwrap_or_else(default_end);
 
+    (start, end)
+}
+
+#[get("/telemetry/<device_id>?<start_time>&<end_time>")]
+async fn get_telemetry(
+    mut db: Connection<Db>,
+    device_id: &str,
+    start_time: Option<String>,
+    end_time: Option<String>,
+) -> Result<Json<Vec<TelemetryRecord>>, Status> {
+    let (start, end) = parse_time_range(start_time.as_deref(), end_time.as_deref());
+
     let result = sqlx::query_as::<_, TelemetryRecord>(
         "SELECT RTRIM(device_id) AS device_id, payload, timestamp FROM telemetry
         WHERE device_id = $1
@@ -417,6 +760,270 @@ async fn get_telemetry(
     Ok(Json(result))
 }
 
+#[get("/devices/<device_id>/predictions?<start_time>&<end_time>")]
+async fn get_predictions(
+    mut db: Connection<Db>,
+    device_id: &str,
+    start_time: Option<String>,
+    end_time: Option<String>,
+) -> Result<Json<Vec<PredictionRecord>>, Status> {
+    let (start, end) = parse_time_range(start_time.as_deref(), end_time.as_deref());
+
+    let result = sqlx::query_as::<_, PredictionRecord>(
+        "SELECT RTRIM(device_id) AS device_id, cycle_id, reading_time, resistance, cycle_start, event, model,
+            completion_time, completion_p10, completion_p90, state, covariance_diagonal
+        FROM predictions
+        WHERE device_id = $1
+        AND reading_time >= $2
+        AND reading_time <= $3
+        ORDER BY reading_time DESC",
+    )
+    .bind(device_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut **db)
+    .await
+    .map_err(|e| {
+        eprintln!(
+            "Database error in get_predictions for device '{}': {:?}",
+            device_id, e
+        );
+        Status::InternalServerError
+    })?;
+    Ok(Json(result))
+}
+
+#[get("/devices/<device_id>/alerts?<start_time>&<end_time>")]
+async fn get_device_alerts(
+    mut db: Connection<Db>,
+    device_id: &str,
+    start_time: Option<String>,
+    end_time: Option<String>,
+) -> Result<Json<Vec<AlertRecord>>, Status> {
+    let (start, end) = parse_time_range(start_time.as_deref(), end_time.as_deref());
+
+    let result = sqlx::query_as::<_, AlertRecord>(
+        "SELECT RTRIM(device_id) AS device_id, stage_name, title, cycle_start,
+            completion_time, completion_p10, completion_p90, model, sent_at
+        FROM alert_history
+        WHERE device_id = $1
+        AND sent_at >= $2
+        AND sent_at <= $3
+        ORDER BY sent_at DESC",
+    )
+    .bind(device_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut **db)
+    .await
+    .map_err(|e| {
+        eprintln!(
+            "Database error in get_device_alerts for device '{}': {:?}",
+            device_id, e
+        );
+        Status::InternalServerError
+    })?;
+    Ok(Json(result))
+}
+
+#[get("/devices/<device_id>/cycles")]
+async fn get_device_cycles(mut db: Connection<Db>, device_id: &str) -> Result<Json<Vec<cycles::Cycle>>, Status> {
+    let rows = sqlx::query(
+        "SELECT id, RTRIM(device_id) AS device_id, started_at, ended_at, end_reason FROM cycles
+        WHERE device_id = $1
+        ORDER BY started_at DESC",
+    )
+    .bind(device_id)
+    .fetch_all(&mut **db)
+    .await
+    .map_err(|e| { eprintln!("[get_device_cycles] DB error: {e}"); Status::InternalServerError })?;
+
+    let cycles = rows
+        .iter()
+        .map(cycles::Cycle::from_row)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| { eprintln!("[get_device_cycles] DB error: {e}"); Status::InternalServerError })?;
+    Ok(Json(cycles))
+}
+
+#[get("/cycles/<cycle_id>")]
+async fn get_cycle(mut db: Connection<Db>, cycle_id: i64) -> Result<Json<serde_json::Value>, Status> {
+    let row = sqlx::query(
+        "SELECT c.id, RTRIM(c.device_id) AS device_id, c.started_at, c.ended_at, c.end_reason,
+            (SELECT COUNT(*) FROM telemetry t WHERE t.cycle_id = c.id) AS telemetry_count,
+            (SELECT COUNT(*) FROM predictions p WHERE p.cycle_id = c.id) AS prediction_count,
+            last.completion_time, last.completion_p10, last.completion_p90
+        FROM cycles c
+        LEFT JOIN LATERAL (
+            SELECT completion_time, completion_p10, completion_p90 FROM predictions
+            WHERE cycle_id = c.id
+            ORDER BY reading_time DESC
+            LIMIT 1
+        ) last ON true
+        WHERE c.id = $1",
+    )
+    .bind(cycle_id)
+    .fetch_optional(&mut **db)
+    .await
+    .map_err(|e| { eprintln!("[get_cycle] DB error: {e}"); Status::InternalServerError })?;
+
+    let Some(row) = row else {
+        println!("Cycle {} not found", cycle_id);
+        return Err(Status::NotFound);
+    };
+    let cycle = cycles::Cycle::from_row(&row)
+        .map_err(|e| { eprintln!("[get_cycle] DB error: {e}"); Status::InternalServerError })?;
+    Ok(Json(serde_json::json!({
+        "id": cycle.id,
+        "device_id": cycle.device_id,
+        "started_at": cycle.started_at,
+        "ended_at": cycle.ended_at,
+        "end_reason": cycle.end_reason,
+        "telemetry_count": row.get::<i64, _>("telemetry_count"),
+        "prediction_count": row.get::<i64, _>("prediction_count"),
+        "completion_time": row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("completion_time"),
+        "p10": row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("completion_p10"),
+        "p90": row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("completion_p90"),
+    })))
+}
+
+#[get("/devices/<device_id>/accuracy?<start_time>&<end_time>")]
+async fn get_device_accuracy(
+    mut db: Connection<Db>,
+    device_id: &str,
+    start_time: Option<String>,
+    end_time: Option<String>,
+) -> Result<Json<serde_json::Value>, Status> {
+    let (start, end) = parse_time_range(start_time.as_deref(), end_time.as_deref());
+
+    let cycle_rows = sqlx::query(
+        "SELECT a.cycle_id, c.started_at, c.ended_at, c.end_reason, a.actual_completion_time,
+            a.prediction_count, a.mae_minutes, a.bias_minutes
+        FROM cycle_accuracy a
+        JOIN cycles c ON c.id = a.cycle_id
+        WHERE a.device_id = $1
+        AND c.started_at >= $2
+        AND c.started_at <= $3
+        ORDER BY c.started_at DESC",
+    )
+    .bind(device_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut **db)
+    .await
+    .map_err(|e| { eprintln!("[get_device_accuracy] DB error: {e}"); Status::InternalServerError })?;
+
+    // Weighted by the number of predictions, so these are the errors over all predictions
+    // rather than an average of the per-cycle figures.
+    let horizon_rows = sqlx::query(
+        "SELECT h.horizon_minutes,
+            SUM(h.mae_minutes * h.samples) / SUM(h.samples) AS mae_minutes,
+            SUM(h.bias_minutes * h.samples) / SUM(h.samples) AS bias_minutes,
+            SUM(h.samples)::BIGINT AS samples,
+            COUNT(*) AS cycles
+        FROM cycle_accuracy_horizons h
+        JOIN cycle_accuracy a ON a.cycle_id = h.cycle_id
+        JOIN cycles c ON c.id = a.cycle_id
+        WHERE a.device_id = $1
+        AND c.started_at >= $2
+        AND c.started_at <= $3
+        GROUP BY h.horizon_minutes
+        ORDER BY h.horizon_minutes DESC",
+    )
+    .bind(device_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut **db)
+    .await
+    .map_err(|e| { eprintln!("[get_device_accuracy] DB error: {e}"); Status::InternalServerError })?;
+
+    let mut weighted_mae = 0.0;
+    let mut weighted_bias = 0.0;
+    let mut prediction_count = 0;
+    let cycles: Vec<serde_json::Value> = cycle_rows
+        .iter()
+        .map(|row| {
+            let count = row.get::<i32, _>("prediction_count");
+            let mae = row.get::<Option<f64>, _>("mae_minutes");
+            let bias = row.get::<Option<f64>, _>("bias_minutes");
+            if let (Some(mae), Some(bias)) = (mae, bias) {
+                weighted_mae += mae * count as f64;
+                weighted_bias += bias * count as f64;
+                prediction_count += count;
+            }
+            serde_json::json!({
+                "cycle_id": row.get::<i64, _>("cycle_id"),
+                "started_at": row.get::<chrono::DateTime<chrono::Utc>, _>("started_at"),
+                "ended_at": row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("ended_at"),
+                "end_reason": row.get::<Option<String>, _>("end_reason"),
+                "actual_completion_time": row.get::<Option<chrono::DateTime<chrono::Utc>>, _>("actual_completion_time"),
+                "prediction_count": count,
+                "mae_minutes": mae,
+                "bias_minutes": bias,
+            })
+        })
+        .collect();
+
+    let horizons: Vec<serde_json::Value> = horizon_rows
+        .iter()
+        .map(|row| {
+            serde_json::json!({
+                "horizon_minutes": row.get::<i32, _>("horizon_minutes"),
+                "mae_minutes": row.get::<f64, _>("mae_minutes"),
+                "bias_minutes": row.get::<f64, _>("bias_minutes"),
+                "samples": row.get::<i64, _>("samples"),
+                "cycles": row.get::<i64, _>("cycles"),
+            })
+        })
+        .collect();
+
+    let overall = |total: f64| (prediction_count > 0).then(|| total / prediction_count as f64);
+    Ok(Json(serde_json::json!({
+        "device_id": device_id,
+        "cycles_evaluated": cycles.len(),
+        "prediction_count": prediction_count,
+        "mae_minutes": overall(weighted_mae),
+        "bias_minutes": overall(weighted_bias),
+        "horizons": horizons,
+        "cycles": cycles,
+    })))
+}
+
+#[get("/cycles/<cycle_id>/comparison")]
+async fn get_cycle_comparison(
+    evaluator: &rocket::State<Arc<accuracy::AccuracyEvaluator<accuracy::PostgresAccuracyRepository>>>,
+    cycle_id: i64,
+) -> Result<Json<serde_json::Value>, Status> {
+    match evaluator.compare_models(cycle_id, &drying_model::ModelKind::ALL).await {
+        Ok(Some(models)) => Ok(Json(serde_json::json!({ "cycle_id": cycle_id, "models": models }))),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            eprintln!("[get_cycle_comparison] {e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/devices/<device_id>/calibration?<apply>")]
+async fn calibrate_device(
+    calibrator: &rocket::State<Arc<calibration::Calibrator<calibration::PostgresCalibrationRepository>>>,
+    device_id: &str,
+    apply: Option<bool>,
+) -> Result<Json<calibration::CalibrationResult>, Status> {
+    match calibrator.calibrate(device_id, apply.unwrap_or(false)).await {
+        Ok(result) => Ok(Json(result)),
+        Err(calibration::CalibrationError::DeviceNotFound(_)) => Err(Status::NotFound),
+        Err(e @ (calibration::CalibrationError::NotEnoughData { .. } | calibration::CalibrationError::InvalidParameters { .. })) => {
+            eprintln!("[calibrate_device] {e}");
+            Err(Status::UnprocessableEntity)
+        }
+        Err(e) => {
+            eprintln!("[calibrate_device] {e}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
 // Rocket launch
 #[launch]
 fn rocket() -> _ {
@@ -432,14 +1039,61 @@ fn rocket() -> _ {
                 let pool = db.0.clone();
                 let predictor= Arc::new(washing_predictor::WashingPredictor::new(
                     washing_predictor::PostgresDeviceRepository::new(pool.clone())));
+                let notifier = Arc::new(notifier::NotifierSet::from_env()
+                    .unwrap_or_else(|e| panic!("Failed to configure notifiers: {e}")));
+                let alerts = Arc::new(alerts::AlertManager::new(
+                    alerts::PostgresAlertRepository::new(pool.clone()), notifier.clone()));
+                let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+                let heartbeat = Arc::new(heartbeat::HeartbeatMonitor::new(
+                    heartbeat::PostgresHeartbeatRepository::new(pool.clone()), notifier.clone()));
+                let cycles = Arc::new(cycles::CycleTracker::new(
+                    cycles::PostgresCycleRepository::new(pool.clone())));
+                let accuracy = Arc::new(accuracy::AccuracyEvaluator::new(
+                    accuracy::PostgresAccuracyRepository::new(pool.clone())));
+                let calibrator = Arc::new(calibration::Calibrator::new(
+                    calibration::PostgresCalibrationRepository::new(pool.clone())));
                 
                 rocket
                     .manage(pool)
                     .manage(predictor)
+                    .manage(notifier)
+                    .manage(alerts)
+                    .manage(battery)
+                    .manage(heartbeat)
+                    .manage(cycles)
+                    .manage(accuracy)
+                    .manage(calibrator)
             } else {
                 panic!("Failed to get database pool - make sure Db::init() is attached first");
             }
         }))
+        .attach(AdHoc::on_liftoff("Background Tasks", |rocket| Box::pin(async move {
+            let heartbeat = rocket
+                .state::<Arc<heartbeat::HeartbeatMonitor<heartbeat::PostgresHeartbeatRepository, notifier::NotifierSet>>>()
+                .expect("heartbeat monitor is managed on ignite")
+                .clone();
+            tokio::spawn(heartbeat.run(std::time::Duration::from_secs(60)));
+
+            let alerts = rocket
+                .state::<Arc<alerts::AlertManager<alerts::PostgresAlertRepository, notifier::NotifierSet>>>()
+                .expect("alert manager is managed on ignite")
+                .clone();
+            tokio::spawn(alerts.run_scheduler(std::time::Duration::from_secs(30)));
+
+            let accuracy = rocket
+                .state::<Arc<accuracy::AccuracyEvaluator<accuracy::PostgresAccuracyRepository>>>()
+                .expect("accuracy evaluator is managed on ignite")
+                .clone();
+            tokio::spawn(accuracy.run(std::time::Duration::from_secs(600)));
+
+            let predictor = rocket
+                .state::<Arc<washing_predictor::WashingPredictor<washing_predictor::PostgresDeviceRepository>>>()
+                .expect("predictor is managed on ignite")
+                .clone();
+            tokio::spawn(predictor.clone().run_eviction(std::time::Duration::from_secs(3600)));
+            let pool = rocket.state::<sqlx::PgPool>().expect("database pool is managed on ignite").clone();
+            tokio::spawn(predictor.listen_for_configuration_changes(pool));
+        })))
         .mount("/", routes![index])
         .mount(
             "/api/v1",
@@ -450,8 +1104,236 @@ fn rocket() -> _ {
                 delete_device,
                 update_device_configuration,
                 get_device_completion_time,
+                get_device_prediction,
+                get_device_forecast,
+                get_predictions,
+                get_device_alerts,
+                get_device_cycles,
+                get_cycle,
+                get_cycle_comparison,
+                get_device_accuracy,
+                get_presets,
+                get_preset,
+                put_preset,
+                calibrate_device,
                 post_telemetry,
                 get_telemetry,
             ],
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use washing_predictor::{DeviceRepository, EKFParameters, PredictorError, StoredFilterState, TelemetryData, WashingPredictor};
+
+    struct MockDeviceRepository;
+
+    impl DeviceRepository for MockDeviceRepository {
+        async fn get_ekf_parameters(&self, _device_id: &str) -> Result<EKFParameters, PredictorError> {
+            Ok(washing_predictor::test_parameters(Default::default()))
+        }
+
+        async fn load_filter_state(&self, _device_id: &str) -> Result<Option<StoredFilterState>, PredictorError> {
+            Ok(None)
+        }
+
+        async fn save_filter_state(&self, _device_id: &str, _state: &StoredFilterState) -> Result<(), PredictorError> {
+            Ok(())
+        }
+
+        async fn delete_filter_state(&self, _device_id: &str) -> Result<(), PredictorError> {
+            Ok(())
+        }
+
+        async fn get_cycle_telemetry(&self, _device_id: &str, _before: chrono::DateTime<chrono::Utc>) -> Result<Vec<TelemetryData>, PredictorError> {
+            Ok(Vec::new())
+        }
+
+        async fn record_prediction(&self, _record: &washing_predictor::PredictionRecord) -> Result<(), PredictorError> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockAlertRepository {
+        last_notification_at: Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+    }
+
+    impl alerts::AlertRepository for MockAlertRepository {
+        async fn get_alert_stages(&self, _device_id: &str) -> Result<Option<Vec<alerts::AlertStage>>, alerts::AlertError> {
+            Ok(None)
+        }
+
+        async fn get_sent_stages(&self, _device_id: &str, _cycle_start: chrono::DateTime<chrono::Utc>) -> Result<Vec<String>, alerts::AlertError> {
+            Ok(Vec::new())
+        }
+
+        async fn set_last_notification_at(&self, _device_id: &str, at: chrono::DateTime<chrono::Utc>) -> Result<(), alerts::AlertError> {
+            *self.last_notification_at.lock().unwrap() = Some(at);
+            Ok(())
+        }
+
+        async fn schedule_alert(&self, _alert: &alerts::ScheduledAlert) -> Result<(), alerts::AlertError> {
+            Ok(())
+        }
+
+        async fn cancel_scheduled_alert(&self, _device_id: &str) -> Result<(), alerts::AlertError> {
+            Ok(())
+        }
+
+        async fn take_due_alerts(&self, _now: chrono::DateTime<chrono::Utc>) -> Result<Vec<alerts::ScheduledAlert>, alerts::AlertError> {
+            Ok(Vec::new())
+        }
+
+        async fn record_alert(&self, _alert: &alerts::SentAlert) -> Result<(), alerts::AlertError> {
+            Ok(())
+        }
+    }
+
+    // Counts the cycles opened and the readings linked to them, without a database. The
+    // lists are shared so a test can inspect them after handing the mock to the tracker.
+    #[derive(Default, Clone)]
+    struct MockCycleRepository {
+        opened: Arc<Mutex<Vec<cycles::Cycle>>>,
+        linked_telemetry: Arc<Mutex<Vec<Option<i64>>>>,
+    }
+
+    impl cycles::CycleRepository for MockCycleRepository {
+        async fn get_latest_cycle(&self, _device_id: &str) -> Result<Option<cycles::Cycle>, cycles::CycleError> {
+            Ok(self.opened.lock().unwrap().last().cloned())
+        }
+
+        async fn open_cycle(&self, device_id: &str, started_at: chrono::DateTime<chrono::Utc>) -> Result<cycles::Cycle, cycles::CycleError> {
+            let mut opened = self.opened.lock().unwrap();
+            let cycle = cycles::Cycle {
+                id: opened.len() as i64 + 1,
+                device_id: device_id.to_string(),
+                started_at,
+                ended_at: None,
+                end_reason: None,
+            };
+            opened.push(cycle.clone());
+            Ok(cycle)
+        }
+
+        async fn close_cycle(&self, _cycle_id: i64, _ended_at: chrono::DateTime<chrono::Utc>, _reason: cycles::CycleEndReason) -> Result<(), cycles::CycleError> {
+            Ok(())
+        }
+
+        async fn link_reading(
+            &self,
+            _cycle_id: i64,
+            _device_id: &str,
+            telemetry_id: Option<i64>,
+            _reading_time: chrono::DateTime<chrono::Utc>,
+        ) -> Result<(), cycles::CycleError> {
+            self.linked_telemetry.lock().unwrap().push(telemetry_id);
+            Ok(())
+        }
+    }
+
+    fn cycle_tracker() -> Arc<cycles::CycleTracker<MockCycleRepository>> {
+        Arc::new(cycles::CycleTracker::new(MockCycleRepository::default()))
+    }
+
+    // Records every notification instead of sending it anywhere.
+    #[derive(Default)]
+    struct MockNotifier {
+        sent: Mutex<Vec<notifier::Notification>>,
+    }
+
+    impl notifier::Notifier for MockNotifier {
+        async fn notify(&self, notification: &notifier::Notification) -> Result<(), notifier::NotifierError> {
+            self.sent.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alert_sent_when_completion_is_near() {
+        let predictor = Arc::new(WashingPredictor::new(MockDeviceRepository));
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = Arc::new(alerts::AlertManager::new(MockAlertRepository::default(), notifier.clone()));
+        let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+
+        // The mock parameters predict ~170 minutes of drying from the reading's timestamp, so a
+        // reading stamped three hours ago puts the completion time in the past.
+        let timestamp = chrono::Utc::now() - ::chrono::Duration::hours(3);
+        let payload = serde_json::json!({ "timestamp": timestamp.to_rfc3339(), "resistance": 30000.0 });
+        process_telemetry(predictor, alerts, battery, cycle_tracker(), None, "wash-1".to_string(), payload).await;
+
+        let sent = notifier.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].device_id, "wash-1");
+        assert_eq!(sent[0].title, "Washing Complete :)");
+    }
+
+    #[tokio::test]
+    async fn test_repeated_readings_send_one_alert() {
+        let predictor = Arc::new(WashingPredictor::new(MockDeviceRepository));
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = Arc::new(alerts::AlertManager::new(MockAlertRepository::default(), notifier.clone()));
+        let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+
+        let cycle_repo = MockCycleRepository::default();
+        let cycles = Arc::new(cycles::CycleTracker::new(cycle_repo.clone()));
+
+        let start = chrono::Utc::now() - ::chrono::Duration::hours(4);
+        for i in 0..5 {
+            let timestamp = start + ::chrono::Duration::minutes(2 * i);
+            let payload = serde_json::json!({ "timestamp": timestamp.to_rfc3339(), "resistance": 30000.0 });
+            process_telemetry(predictor.clone(), alerts.clone(), battery.clone(), cycles.clone(), Some(StoredTelemetry { id: i + 1, received_at: chrono::Utc::now() }), "wash-1".to_string(), payload).await;
+        }
+
+        assert_eq!(notifier.sent.lock().unwrap().len(), 1);
+        // All readings belong to the one cycle opened by the first reading.
+        assert_eq!(cycle_repo.opened.lock().unwrap().len(), 1);
+        assert_eq!(*cycle_repo.linked_telemetry.lock().unwrap(), vec![Some(1), Some(2), Some(3), Some(4), Some(5)]);
+    }
+
+    #[tokio::test]
+    async fn test_no_alert_when_completion_is_far_away() {
+        let predictor = Arc::new(WashingPredictor::new(MockDeviceRepository));
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = Arc::new(alerts::AlertManager::new(MockAlertRepository::default(), notifier.clone()));
+        let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+
+        let payload = serde_json::json!({ "timestamp": chrono::Utc::now().to_rfc3339(), "resistance": 30000.0 });
+        process_telemetry(predictor, alerts, battery, cycle_tracker(), None, "wash-1".to_string(), payload).await;
+
+        assert!(notifier.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reading_without_timestamp_takes_the_stored_time() {
+        let predictor = Arc::new(WashingPredictor::new(MockDeviceRepository));
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = Arc::new(alerts::AlertManager::new(MockAlertRepository::default(), notifier.clone()));
+        let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+
+        // The stored row carries this time, so the reading must too, or a replay of the stored
+        // rows would not recognise it as the reading being processed.
+        let received_at = chrono::Utc::now() - ::chrono::Duration::seconds(5);
+        let payload = serde_json::json!({ "resistance": 30000.0 });
+        process_telemetry(predictor.clone(), alerts, battery, cycle_tracker(), Some(StoredTelemetry { id: 1, received_at }), "wash-1".to_string(), payload).await;
+
+        assert_eq!(predictor.get_prediction_details("wash-1").unwrap().last_received_time, received_at);
+    }
+
+    #[tokio::test]
+    async fn test_battery_message_updates_battery_state_only() {
+        let predictor = Arc::new(WashingPredictor::new(MockDeviceRepository));
+        let notifier = Arc::new(MockNotifier::default());
+        let alerts = Arc::new(alerts::AlertManager::new(MockAlertRepository::default(), notifier.clone()));
+        let battery = Arc::new(battery::BatteryMonitor::new(notifier.clone()));
+
+        let payload = serde_json::json!({ "battery_voltage": 3.7 });
+        process_telemetry(predictor.clone(), alerts, battery.clone(), cycle_tracker(), None, "wash-1".to_string(), payload).await;
+
+        assert!(notifier.sent.lock().unwrap().is_empty());
+        assert!(predictor.get_estimated_completion_time("wash-1").is_none());
+        assert_eq!(battery.get_state("wash-1").unwrap().voltage, 3.7);
+    }
+}
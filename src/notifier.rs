@@ -0,0 +1,390 @@
+//! Outbound notifications for the washing line monitor.
+//!
+//! Alerts used to be sent by building a `reqwest::Client` inline and POSTing to ntfy.sh.
+//! This module hides that behind the `Notifier` trait so the alert path can be tested with a
+//! mock, and so alerts can be delivered to more than one place at once.
+//!
+//! The backends in use are chosen through the environment:
+//!   NOTIFIERS    comma separated list of `ntfy`, `webhook` and `log`
+//!                (defaults to `ntfy` when NTFY_TOPIC is set; with neither set the server
+//!                refuses to start, so a missing variable does not silently drop every alert)
+//!   NTFY_TOPIC   topic to publish to (required by `ntfy`)
+//!   NTFY_SERVER  ntfy server base URL (defaults to https://ntfy.sh)
+//!   WEBHOOK_URL  URL that receives a JSON POST per notification (required by `webhook`)
+
+use serde::{Deserialize, Serialize};
+
+/// Priority levels understood by ntfy. Other backends pass them through as lowercase strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Min,
+    Low,
+    Default,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Min => "min",
+            Priority::Low => "low",
+            Priority::Default => "default",
+            Priority::High => "high",
+            Priority::Urgent => "urgent",
+        }
+    }
+}
+
+/// A single alert, independent of where it ends up being delivered.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub device_id: String,
+    pub title: String,
+    pub message: String,
+    pub priority: Priority,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotifierError {
+    #[error("HTTP error sending notification: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("{target} rejected notification with status {status}")]
+    Rejected { target: String, status: u16 },
+
+    #[error("notifier configuration error: {0}")]
+    Configuration(String),
+}
+
+// Same pattern as `DeviceRepository`: process_telemetry depends on this trait rather than on
+// reqwest directly, so tests can pass a mock that records what would have been sent.
+//
+// The futures are only ever awaited on concrete types, so the missing `Send` bound on the
+// returned future is not a problem for us.
+#[allow(async_fn_in_trait)]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError>;
+}
+
+/// Publishes to an ntfy topic using ntfy's header based API.
+pub struct NtfyNotifier {
+    client: reqwest::Client,
+    server: String,
+    topic: String,
+}
+
+impl NtfyNotifier {
+    pub fn new(server: impl Into<String>, topic: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            server: server.into().trim_end_matches('/').to_string(),
+            topic: topic.into(),
+        }
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let url = format!("{}/{}", self.server, self.topic);
+        let response = self
+            .client
+            .post(&url)
+            .header("Title", &notification.title)
+            .header("Priority", notification.priority.as_str())
+            .body(notification.message.clone())
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(NotifierError::Rejected {
+                target: url,
+                status: response.status().as_u16(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// POSTs the notification as JSON to an arbitrary URL (Home Assistant, Discord bridges, etc).
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let response = self.client.post(&self.url).json(notification).send().await?;
+
+        if !response.status().is_success() {
+            return Err(NotifierError::Rejected {
+                target: self.url.clone(),
+                status: response.status().as_u16(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Only prints the notification. Useful for development and when no topic is configured.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        println!(
+            "[notification] device={} priority={} title=\"{}\" message=\"{}\"",
+            notification.device_id,
+            notification.priority.as_str(),
+            notification.title,
+            notification.message
+        );
+        Ok(())
+    }
+}
+
+/// One of the concrete backends. An enum is used instead of `Box<dyn Notifier>` because
+/// `async fn` in traits is not object safe.
+pub enum NotifierBackend {
+    Ntfy(NtfyNotifier),
+    Webhook(WebhookNotifier),
+    Log(LogNotifier),
+    #[cfg(test)]
+    Mock(tests::MockBackend),
+}
+
+impl NotifierBackend {
+    fn name(&self) -> &'static str {
+        match self {
+            NotifierBackend::Ntfy(_) => "ntfy",
+            NotifierBackend::Webhook(_) => "webhook",
+            NotifierBackend::Log(_) => "log",
+            #[cfg(test)]
+            NotifierBackend::Mock(_) => "mock",
+        }
+    }
+}
+
+impl Notifier for NotifierBackend {
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        match self {
+            NotifierBackend::Ntfy(n) => n.notify(notification).await,
+            NotifierBackend::Webhook(n) => n.notify(notification).await,
+            NotifierBackend::Log(n) => n.notify(notification).await,
+            #[cfg(test)]
+            NotifierBackend::Mock(n) => n.notify(notification).await,
+        }
+    }
+}
+
+/// Fans a notification out to every configured backend.
+pub struct NotifierSet {
+    backends: Vec<NotifierBackend>,
+}
+
+impl NotifierSet {
+    pub fn new(backends: Vec<NotifierBackend>) -> Self {
+        Self { backends }
+    }
+
+    /// Builds the set of backends from the environment variables described at the top of this file.
+    pub fn from_env() -> Result<Self, NotifierError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// `from_env` with the variables read through `var`. An empty variable counts as unset, as
+    /// docker-compose passes unset variables through as empty strings.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, NotifierError> {
+        let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+        let ntfy_topic = var("NTFY_TOPIC");
+        let names = match (var("NOTIFIERS"), &ntfy_topic) {
+            (Some(names), _) => names,
+            (None, Some(_)) => "ntfy".to_string(),
+            (None, None) => {
+                return Err(NotifierError::Configuration(
+                    "no notifier configured: set NTFY_TOPIC, or NOTIFIERS (NOTIFIERS=log only logs alerts)".to_string(),
+                ));
+            }
+        };
+
+        let mut backends = Vec::new();
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let backend = match name {
+                "ntfy" => {
+                    let topic = ntfy_topic.clone().ok_or_else(|| {
+                        NotifierError::Configuration("NTFY_TOPIC must be set to use the ntfy notifier".to_string())
+                    })?;
+                    let server = var("NTFY_SERVER").unwrap_or_else(|| "https://ntfy.sh".to_string());
+                    NotifierBackend::Ntfy(NtfyNotifier::new(server, topic))
+                }
+                "webhook" => {
+                    let url = var("WEBHOOK_URL").ok_or_else(|| {
+                        NotifierError::Configuration("WEBHOOK_URL must be set to use the webhook notifier".to_string())
+                    })?;
+                    NotifierBackend::Webhook(WebhookNotifier::new(url))
+                }
+                "log" => NotifierBackend::Log(LogNotifier),
+                other => {
+                    return Err(NotifierError::Configuration(format!("unknown notifier '{}'", other)));
+                }
+            };
+            backends.push(backend);
+        }
+
+        if backends.is_empty() {
+            return Err(NotifierError::Configuration("NOTIFIERS did not name any notifier".to_string()));
+        }
+        if backends.iter().all(|backend| matches!(backend, NotifierBackend::Log(_))) {
+            eprintln!("[notifier] WARNING: only the log notifier is configured, alerts will not be delivered anywhere");
+        }
+
+        Ok(Self::new(backends))
+    }
+}
+
+impl Notifier for NotifierSet {
+    /// Every backend is tried even if an earlier one fails, so one broken destination does not
+    /// swallow the alert for the others. Succeeds if any backend delivered the notification, as
+    /// the caller retrying it would send it to those backends again; failures are only logged
+    /// then. If every backend fails, the first error is returned.
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let mut delivered = false;
+        let mut first_error = None;
+        for backend in &self.backends {
+            match backend.notify(notification).await {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    eprintln!(
+                        "[notifier] {} failed to deliver notification for device {}: {}",
+                        backend.name(),
+                        notification.device_id,
+                        e
+                    );
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if !delivered => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification() -> Notification {
+        Notification {
+            device_id: "wash-1".to_string(),
+            title: "Washing Complete :)".to_string(),
+            message: "Device wash-1 is predicted to be dry".to_string(),
+            priority: Priority::Default,
+        }
+    }
+
+    /// Environment variables for `NotifierSet::from_vars`.
+    fn vars(pairs: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| pairs.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_log_notifier_succeeds() {
+        assert!(LogNotifier.notify(&notification()).await.is_ok());
+    }
+
+    /// Counts the notifications it is given, and rejects them all if `fails` is set.
+    #[derive(Default)]
+    pub(crate) struct MockBackend {
+        fails: bool,
+        sent: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Notifier for MockBackend {
+        async fn notify(&self, _notification: &Notification) -> Result<(), NotifierError> {
+            self.sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if self.fails {
+                return Err(NotifierError::Rejected { target: "mock".to_string(), status: 500 });
+            }
+            Ok(())
+        }
+    }
+
+    fn sent(set: &NotifierSet) -> Vec<usize> {
+        set.backends
+            .iter()
+            .map(|backend| match backend {
+                NotifierBackend::Mock(mock) => mock.sent.load(std::sync::atomic::Ordering::SeqCst),
+                _ => 0,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_notifier_set_tries_every_backend() {
+        // The first backend fails, but the one after it must still run, and as it delivered the
+        // notification the caller must not retry it.
+        let set = NotifierSet::new(vec![
+            NotifierBackend::Mock(MockBackend { fails: true, ..Default::default() }),
+            NotifierBackend::Mock(MockBackend::default()),
+        ]);
+        assert!(set.notify(&notification()).await.is_ok());
+        assert_eq!(sent(&set), vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_notifier_set_fails_when_nothing_is_delivered() {
+        let set = NotifierSet::new(vec![
+            NotifierBackend::Mock(MockBackend { fails: true, ..Default::default() }),
+            NotifierBackend::Mock(MockBackend { fails: true, ..Default::default() }),
+        ]);
+        assert!(matches!(set.notify(&notification()).await, Err(NotifierError::Rejected { .. })));
+        assert_eq!(sent(&set), vec![1, 1]);
+    }
+
+    #[test]
+    fn test_missing_configuration_is_an_error() {
+        assert!(matches!(NotifierSet::from_vars(vars(&[])), Err(NotifierError::Configuration(_))));
+
+        let ntfy = NotifierSet::from_vars(vars(&[("NTFY_TOPIC", "washing")])).unwrap();
+        assert!(matches!(ntfy.backends[..], [NotifierBackend::Ntfy(_)]));
+        // Logging only has to be asked for.
+        let log = NotifierSet::from_vars(vars(&[("NOTIFIERS", "log")])).unwrap();
+        assert!(matches!(log.backends[..], [NotifierBackend::Log(_)]));
+    }
+
+    #[test]
+    fn test_empty_variables_count_as_unset() {
+        // What docker-compose passes on when NTFY_TOPIC and WEBHOOK_URL are not set.
+        assert!(matches!(
+            NotifierSet::from_vars(vars(&[("NTFY_TOPIC", ""), ("WEBHOOK_URL", "")])),
+            Err(NotifierError::Configuration(_))
+        ));
+        assert!(matches!(
+            NotifierSet::from_vars(vars(&[("NOTIFIERS", "ntfy"), ("NTFY_TOPIC", " ")])),
+            Err(NotifierError::Configuration(_))
+        ));
+        assert!(matches!(
+            NotifierSet::from_vars(vars(&[("NOTIFIERS", "webhook"), ("WEBHOOK_URL", "")])),
+            Err(NotifierError::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn test_webhook_payload_shape() {
+        let json = serde_json::to_value(notification()).unwrap();
+        assert_eq!(json["device_id"], "wash-1");
+        assert_eq!(json["priority"], "default");
+        assert_eq!(json["title"], "Washing Complete :)");
+    }
+}
@@ -0,0 +1,272 @@
+//! Particle filter over the same state-space model as the EKF.
+//!
+//! Near the end of a cycle the posterior over M and M_c is far from Gaussian: some loads are
+//! nearly dry and some still have a damp core, and the EKF's single linearised estimate jumps
+//! between the two. The particle filter keeps a cloud of weighted state hypotheses instead. Each
+//! particle is moved with `MoistureSensorModel::state_transition` plus the process noise (the
+//! bootstrap proposal) and reweighted by the likelihood of the reading, so the cloud can hold
+//! several modes at once.
+//!
+//! Every particle implies its own remaining time, which gives an empirical distribution of the
+//! completion time. The reported completion is its weighted median and the band its 10th and
+//! 90th percentiles. The number of particles is `particle_count` in the device's parameters.
+
+use crate::drying_model::{
+    forecast_steps, implied_moisture, moisture_sensor_model, predict_steps, DryingModel, ModelKind, RemainingTime,
+};
+use crate::prediction_algorithms::MoistureSensorModel;
+use crate::washing_predictor::{EKFParameters, Forecast, ForecastPoint, PredictorError};
+use chrono::{DateTime, Utc};
+use kalman_filters::{NonlinearSystem, ParticleFilter, ParticleFilterBuilder};
+
+/// Particles used when the parameters do not give a count.
+pub const DEFAULT_PARTICLE_COUNT: usize = 1000;
+/// Fewest particles accepted by the configuration validation.
+pub const MIN_PARTICLE_COUNT: usize = 100;
+/// Most particles accepted by the configuration validation. Every reading moves every particle,
+/// so this bounds the work per reading.
+pub const MAX_PARTICLE_COUNT: usize = 100_000;
+
+/// Weighted `quantile` (0 to 1) of `samples` given as `(value, weight)`. The weights do not have
+/// to sum to one. `None` if there are no samples with weight.
+pub(crate) fn weighted_quantile(samples: &[(f64, f64)], quantile: f64) -> Option<f64> {
+    let mut sorted: Vec<(f64, f64)> = samples.iter().copied().filter(|(_, weight)| *weight > 0.0).collect();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: f64 = sorted.iter().map(|(_, weight)| weight).sum();
+    if sorted.is_empty() || !total.is_finite() {
+        return None;
+    }
+
+    let target = quantile.clamp(0.0, 1.0) * total;
+    let mut cumulative = 0.0;
+    for (value, weight) in &sorted {
+        cumulative += weight;
+        if cumulative >= target {
+            return Some(*value);
+        }
+    }
+    sorted.last().map(|(value, _)| *value)
+}
+
+/// Remaining minutes of one particle. A particle whose moisture is already down to M_c is dry;
+/// one with an invalid state (e.g. a negative k after the process noise) has no estimate.
+fn particle_remaining(state: &[f64]) -> Option<f64> {
+    let (m, k, m_c) = (state[1], state[2], state[4]);
+    if !(m.is_finite() && k.is_finite() && k > 0.0) {
+        return None;
+    }
+    if m <= m_c {
+        return Some(0.0);
+    }
+    let minutes = (m / m_c).ln() / k;
+    minutes.is_finite().then_some(minutes)
+}
+
+/// The bootstrap particle filter over `MoistureSensorModel`.
+pub struct ParticleModel {
+    filter: ParticleFilter<f64>,
+    system: MoistureSensorModel,
+    parameters: EKFParameters, // dt and the process noise for the particles, initial M for re-wetting
+}
+
+impl ParticleModel {
+    pub fn new(parameters: &EKFParameters) -> Result<Self, String> {
+        let n = parameters.initial_state.len();
+        if parameters.initial_covariance.len() != n * n || parameters.process_noise_covariance.len() != n * n {
+            return Err(format!("covariances must be flattened {n}x{n} matrices"));
+        }
+        let [variance] = parameters.measurement_noise_covariance.as_slice() else {
+            return Err("measurement_noise_covariance must have 1 element".to_string());
+        };
+
+        // Particles are drawn per state from the diagonal; correlations are learned from the data.
+        let filter = ParticleFilterBuilder::new(n, parameters.particle_count)
+            .initial_mean(parameters.initial_state.clone())
+            .initial_std(standard_deviations(&parameters.initial_covariance, n))
+            .process_noise_std(standard_deviations(&parameters.process_noise_covariance, n))
+            .measurement_noise_std(vec![variance.max(0.0).sqrt()])
+            .dt(parameters.dt)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(ParticleModel {
+            filter,
+            system: moisture_sensor_model(parameters),
+            parameters: parameters.clone(),
+        })
+    }
+
+    /// Moves every particle through each sub-step of `predict_steps`, jittering it with the
+    /// sub-step's process noise as per-state standard deviations.
+    fn advance(&mut self, elapsed: chrono::Duration) {
+        let Some((steps, step, q)) = predict_steps(&self.parameters, elapsed) else {
+            return;
+        };
+        let system = &self.system;
+        self.filter.dt = step;
+        self.filter.process_noise_std = standard_deviations(&q, self.filter.state_dim);
+        for _ in 0..steps {
+            self.filter.predict(|state, dt| system.state_transition(state, None, dt));
+        }
+    }
+}
+
+fn standard_deviations(covariance: &[f64], n: usize) -> Vec<f64> {
+    (0..n).map(|i| covariance[i * n + i].max(0.0).sqrt()).collect()
+}
+
+impl DryingModel for ParticleModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::Particle
+    }
+
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        self.advance(elapsed);
+        let innovation = resistance - self.filter.mean()[0];
+
+        let sigma = self.filter.measurement_noise_std[0];
+        self.filter
+            .update(&[resistance], |state, measurement| {
+                let residual = (measurement[0] - state[0]) / sigma;
+                let likelihood = (-0.5 * residual * residual).exp();
+                // A particle far off the reading still keeps a sliver of weight, so a reading
+                // every particle missed does not zero all of them.
+                if likelihood.is_finite() { likelihood.max(f64::MIN_POSITIVE) } else { 0.0 }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(innovation)
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        Some(self.filter.mean()[0])
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        let mean = self.filter.mean();
+        mean[0] - mean[5]
+    }
+
+    /// The weighted median and 10th/90th percentiles of the particles' remaining times.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        let samples: Vec<(f64, f64)> = self
+            .filter
+            .particles
+            .iter()
+            .filter_map(|particle| particle_remaining(&particle.state).map(|minutes| (minutes, particle.weight)))
+            .collect();
+        let quantile = |q| weighted_quantile(&samples, q).ok_or(PredictorError::InvalidPrediction);
+        Ok(RemainingTime {
+            minutes: quantile(0.5)?,
+            p10: quantile(0.1)?,
+            p90: quantile(0.9)?,
+        })
+    }
+
+    /// Moves every particle's R to the re-wetted reading and its M to the moisture that reading
+    /// implies under the particle's own tau, M_c and R_offset, keeping the learned parameters.
+    fn rewet(&mut self, resistance: f64) {
+        let initial_m = self.parameters.initial_state[1];
+        for particle in &mut self.filter.particles {
+            particle.state[1] = implied_moisture(&particle.state, resistance, initial_m);
+            particle.state[0] = resistance;
+        }
+    }
+
+    fn state(&self) -> Vec<f64> {
+        self.filter.mean()
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        self.filter.covariance()
+    }
+
+    /// A mean and covariance cannot bring back a multi-modal cloud, so the readings are replayed.
+    fn restore(&mut self, _state: &[f64], _covariance: &[f64]) -> bool {
+        false
+    }
+
+    /// Runs every particle forward through the process model, without further noise, and takes
+    /// the bands from the weighted percentiles of the cloud.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast> {
+        let (steps, step, _) = forecast_steps(&self.parameters, horizon_minutes);
+
+        let mut particles: Vec<(Vec<f64>, f64)> = self
+            .filter
+            .particles
+            .iter()
+            .map(|particle| (particle.state.clone(), particle.weight))
+            .collect();
+        let mut points = Vec::with_capacity(steps + 1);
+        for i in 0..=steps {
+            if i > 0 {
+                for (state, _) in &mut particles {
+                    *state = self.system.state_transition(state, None, step);
+                }
+            }
+            let column = |index: usize| -> Vec<(f64, f64)> {
+                particles.iter().map(|(state, weight)| (state[index], *weight)).collect()
+            };
+            let (resistance, moisture) = (column(0), column(1));
+            let minutes = step * i as f64;
+            points.push(ForecastPoint {
+                time: from + chrono::Duration::milliseconds((minutes * 60_000.0) as i64),
+                minutes,
+                resistance: weighted_quantile(&resistance, 0.5)?,
+                resistance_p10: weighted_quantile(&resistance, 0.1)?.max(0.0),
+                resistance_p90: weighted_quantile(&resistance, 0.9)?,
+                moisture: weighted_quantile(&moisture, 0.5)?,
+                moisture_p10: weighted_quantile(&moisture, 0.1)?.max(0.0),
+                moisture_p90: weighted_quantile(&moisture, 0.9)?,
+            });
+        }
+
+        Some(Forecast {
+            from,
+            step_minutes: step,
+            points,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drying_model::remaining_minutes;
+    use crate::washing_predictor::test_parameters;
+
+    #[test]
+    fn test_weighted_quantile() {
+        let samples = [(3.0, 1.0), (1.0, 1.0), (2.0, 2.0), (10.0, 0.0)];
+        assert_eq!(weighted_quantile(&samples, 0.1), Some(1.0));
+        assert_eq!(weighted_quantile(&samples, 0.5), Some(2.0));
+        assert_eq!(weighted_quantile(&samples, 0.9), Some(3.0));
+        assert_eq!(weighted_quantile(&[(1.0, 0.0)], 0.5), None);
+    }
+
+    #[test]
+    fn test_particle_filter_tracks_drying() {
+        let parameters = test_parameters(ModelKind::Particle);
+        let mut model = ParticleModel::new(&parameters).unwrap();
+        assert_eq!(model.filter.particles.len(), DEFAULT_PARTICLE_COUNT);
+
+        // Readings generated from the model's own initial state, every 2 minutes.
+        let mut truth = parameters.initial_state.clone();
+        for i in 0..30 {
+            let elapsed = if i == 0 { chrono::Duration::zero() } else { chrono::Duration::minutes(2) };
+            if i > 0 {
+                truth = model.system.state_transition(&truth, None, 2.0);
+            }
+            model.ingest(elapsed, truth[0]).unwrap();
+        }
+
+        let remaining = model.remaining().unwrap();
+        let true_remaining = remaining_minutes(&truth).unwrap();
+        assert!((remaining.minutes - true_remaining).abs() < 10.0, "{remaining:?} vs {true_remaining}");
+        assert!(remaining.p10 <= remaining.minutes && remaining.minutes <= remaining.p90);
+        assert!(!model.restore(&model.state(), &model.covariance()));
+
+        let forecast = model.forecast(Utc::now(), 30.0).unwrap();
+        let last = forecast.points.last().unwrap();
+        assert!(last.resistance > forecast.points[0].resistance);
+        assert!(last.resistance_p10 <= last.resistance && last.resistance <= last.resistance_p90);
+    }
+}
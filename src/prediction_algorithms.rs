@@ -183,7 +183,7 @@ mod tests {
         
         let initial_state = ekf.state().to_vec();
         ekf.predict();// Propagate the state forward by dt
-        ekf.update(&vec![35000.0]).unwrap(); 
+        ekf.update(&[35000.0]).unwrap(); 
         let updated_state = ekf.state().to_vec();
         
         println!("Initial state: {:?}", initial_state);
@@ -28,7 +28,7 @@ pub fn is_stable_resistance(resistances: &[f64], stability_threshold: f64) -> bo
     let derivative = (last_value - first_value) / (smoothed_values.len() as f64);
     let normalized_derivative = derivative / first_value;
     println!("stability_threshold: {}, normalized_derivative: {}", stability_threshold, normalized_derivative);
-    return normalized_derivative.abs() < stability_threshold;
+    normalized_derivative.abs() < stability_threshold
 }
 
 #[cfg(test)]
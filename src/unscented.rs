@@ -0,0 +1,360 @@
+//! Unscented Kalman filter over the same state-space model as the EKF.
+//!
+//! Instead of linearising `MoistureSensorModel` with its hand-derived Jacobians, the UKF pushes
+//! a set of sigma points (the mean plus and minus scaled columns of a square root of P) through
+//! `state_transition` and recombines them into the predicted mean and covariance. This captures
+//! the curvature of the `(M - M_c)^-tau` term near completion that the EKF linearises away.
+//!
+//! `kalman_filters` ships a UKF too, but its Cholesky decomposition rejects a covariance with a
+//! zero variance, and the presets hold M_c and R_offset fixed with exactly that. The filter here
+//! uses a square root that allows zero pivots, so fixed states simply get no sigma spread.
+
+use crate::drying_model::{
+    forecast_point, forecast_steps, moisture_sensor_model, predict_steps, remaining_minutes, remaining_with_band,
+    rewet_state, state_is_dry, DryingModel, ModelKind, RemainingTime, Z_90,
+};
+use crate::prediction_algorithms::MoistureSensorModel;
+use crate::washing_predictor::{EKFParameters, Forecast, PredictorError};
+use chrono::{DateTime, Utc};
+use kalman_filters::NonlinearSystem;
+
+/// Sigma point spread. With `alpha = 1` and `kappa = 0` the points sit sqrt(n) standard
+/// deviations from the mean and every weight is positive, apart from the unused centre weight
+/// of the mean.
+const ALPHA: f64 = 1.0;
+/// Prior knowledge of the distribution; 2 is optimal for a Gaussian.
+const BETA: f64 = 2.0;
+const KAPPA: f64 = 0.0;
+
+/// Pivots of the square root smaller than this, relative to the variance, are treated as zero.
+const PIVOT_TOLERANCE: f64 = 1e-12;
+
+/// Sigma points of one distribution with their mean and covariance weights.
+struct SigmaPoints {
+    points: Vec<Vec<f64>>,
+    weights_mean: Vec<f64>,
+    weights_covariance: Vec<f64>,
+}
+
+impl SigmaPoints {
+    /// The 2n + 1 sigma points of the distribution with mean `x` and covariance `p`.
+    fn new(x: &[f64], p: &[f64]) -> Self {
+        let n = x.len();
+        let lambda = ALPHA * ALPHA * (n as f64 + KAPPA) - n as f64;
+        let scale = (n as f64 + lambda).sqrt();
+        let root = lower_square_root(p, n);
+
+        let mut points = Vec::with_capacity(2 * n + 1);
+        points.push(x.to_vec());
+        for sign in [1.0, -1.0] {
+            for column in 0..n {
+                points.push((0..n).map(|row| x[row] + sign * scale * root[row * n + column]).collect());
+            }
+        }
+
+        let weight = 1.0 / (2.0 * (n as f64 + lambda));
+        let mut weights_mean = vec![weight; 2 * n + 1];
+        let mut weights_covariance = weights_mean.clone();
+        weights_mean[0] = lambda / (n as f64 + lambda);
+        weights_covariance[0] = weights_mean[0] + (1.0 - ALPHA * ALPHA + BETA);
+
+        SigmaPoints {
+            points,
+            weights_mean,
+            weights_covariance,
+        }
+    }
+
+    /// Weighted mean and covariance of the points, plus `noise`.
+    fn recombine(&self, noise: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let n = self.points[0].len();
+        let mut mean = vec![0.0; n];
+        for (point, weight) in self.points.iter().zip(&self.weights_mean) {
+            for (m, value) in mean.iter_mut().zip(point) {
+                *m += weight * value;
+            }
+        }
+
+        let mut covariance = noise.to_vec();
+        for (point, weight) in self.points.iter().zip(&self.weights_covariance) {
+            for i in 0..n {
+                for j in 0..n {
+                    covariance[i * n + j] += weight * (point[i] - mean[i]) * (point[j] - mean[j]);
+                }
+            }
+        }
+        symmetrise(&mut covariance, n);
+        (mean, covariance)
+    }
+
+    /// Weighted mean and variance of a scalar function of the points.
+    fn scalar_moments(&self, values: &[f64]) -> (f64, f64) {
+        let mean: f64 = values.iter().zip(&self.weights_mean).map(|(value, weight)| weight * value).sum();
+        let variance = values
+            .iter()
+            .zip(&self.weights_covariance)
+            .map(|(value, weight)| weight * (value - mean).powi(2))
+            .sum();
+        (mean, variance)
+    }
+}
+
+/// Lower triangular L with L L^T = P for a symmetric positive semi-definite P. A column whose
+/// pivot is (numerically) zero is left at zero instead of failing, which is what a state with
+/// zero variance needs.
+fn lower_square_root(p: &[f64], n: usize) -> Vec<f64> {
+    let mut l = vec![0.0; n * n];
+    for j in 0..n {
+        let pivot = p[j * n + j] - (0..j).map(|k| l[j * n + k] * l[j * n + k]).sum::<f64>();
+        if pivot.is_nan() || pivot <= PIVOT_TOLERANCE * p[j * n + j].abs() {
+            continue;
+        }
+        let root = pivot.sqrt();
+        l[j * n + j] = root;
+        for i in j + 1..n {
+            let sum: f64 = (0..j).map(|k| l[i * n + k] * l[j * n + k]).sum();
+            l[i * n + j] = (p[i * n + j] - sum) / root;
+        }
+    }
+    l
+}
+
+fn symmetrise(matrix: &mut [f64], n: usize) {
+    for i in 0..n {
+        for j in i + 1..n {
+            let average = 0.5 * (matrix[i * n + j] + matrix[j * n + i]);
+            matrix[i * n + j] = average;
+            matrix[j * n + i] = average;
+        }
+    }
+}
+
+/// The unscented Kalman filter over `MoistureSensorModel`.
+pub struct UkfModel {
+    system: MoistureSensorModel,
+    x: Vec<f64>,
+    p: Vec<f64>,
+    parameters: EKFParameters, // dt and Q for the sigma-point predicts, R for updates, P for re-wetting
+}
+
+impl UkfModel {
+    pub fn new(parameters: &EKFParameters) -> Result<Self, String> {
+        let n = parameters.initial_state.len();
+        if parameters.initial_covariance.len() != n * n
+            || parameters.process_noise_covariance.len() != n * n
+            || parameters.measurement_noise_covariance.len() != 1
+        {
+            return Err(format!("UKF parameters do not match the state dimension {n}"));
+        }
+        Ok(UkfModel {
+            system: moisture_sensor_model(parameters),
+            x: parameters.initial_state.clone(),
+            p: parameters.initial_covariance.clone(),
+            parameters: parameters.clone(),
+        })
+    }
+
+    /// One unscented predict step of length `dt` with process noise `q`.
+    fn predict(&self, x: &[f64], p: &[f64], dt: f64, q: &[f64]) -> (Vec<f64>, Vec<f64>) {
+        let mut sigma = SigmaPoints::new(x, p);
+        for point in sigma.points.iter_mut() {
+            *point = self.system.state_transition(point, None, dt);
+        }
+        sigma.recombine(q)
+    }
+
+    /// Propagates the mean and covariance through `predict` for each sub-step of `predict_steps`.
+    fn advance(&mut self, elapsed: chrono::Duration) {
+        let Some((steps, step, q)) = predict_steps(&self.parameters, elapsed) else {
+            return;
+        };
+        for _ in 0..steps {
+            let (x, p) = self.predict(&self.x, &self.p, step, &q);
+            self.x = x;
+            self.p = p;
+        }
+    }
+
+    /// Unscented update with a resistance reading. Returns the innovation.
+    fn update(&mut self, resistance: f64) -> Result<f64, String> {
+        let n = self.x.len();
+        let sigma = SigmaPoints::new(&self.x, &self.p);
+        let predicted: Vec<f64> = sigma.points.iter().map(|point| self.system.measurement(point)[0]).collect();
+        let (expected, spread) = sigma.scalar_moments(&predicted);
+        let innovation_variance = spread + self.parameters.measurement_noise_covariance[0];
+        if !innovation_variance.is_finite() || innovation_variance <= 0.0 {
+            return Err(format!("innovation variance {innovation_variance} is not positive"));
+        }
+
+        // Cross-covariance between the state and the predicted measurement.
+        let mut cross = vec![0.0; n];
+        for ((point, z), weight) in sigma.points.iter().zip(&predicted).zip(&sigma.weights_covariance) {
+            for i in 0..n {
+                cross[i] += weight * (point[i] - self.x[i]) * (z - expected);
+            }
+        }
+
+        let innovation = resistance - expected;
+        let gain: Vec<f64> = cross.iter().map(|c| c / innovation_variance).collect();
+        for i in 0..n {
+            self.x[i] += gain[i] * innovation;
+            for j in 0..n {
+                self.p[i * n + j] -= gain[i] * gain[j] * innovation_variance;
+            }
+        }
+        symmetrise(&mut self.p, n);
+        Ok(innovation)
+    }
+}
+
+impl DryingModel for UkfModel {
+    fn kind(&self) -> ModelKind {
+        ModelKind::Ukf
+    }
+
+    fn ingest(&mut self, elapsed: chrono::Duration, resistance: f64) -> Result<f64, String> {
+        self.advance(elapsed);
+        self.update(resistance)
+    }
+
+    fn expected_resistance(&self) -> Option<f64> {
+        Some(self.x[0])
+    }
+
+    fn moisture_resistance(&self) -> f64 {
+        self.x[0] - self.x[5]
+    }
+
+    /// The remaining time of the mean state, with the p10/p90 band from the spread of the
+    /// remaining times of the sigma points. If a sigma point has no valid remaining time (e.g.
+    /// it is already below M_c) the band falls back to the linearised one the EKF uses.
+    fn remaining(&self) -> Result<RemainingTime, PredictorError> {
+        let minutes = remaining_minutes(&self.x)?;
+        let sigma = SigmaPoints::new(&self.x, &self.p);
+        let Ok(times) = sigma.points.iter().map(|point| remaining_minutes(point)).collect::<Result<Vec<_>, _>>() else {
+            return remaining_with_band(&self.x, &self.p);
+        };
+        let (_, variance) = sigma.scalar_moments(&times);
+        let spread = if variance.is_finite() && variance > 0.0 { Z_90 * variance.sqrt() } else { 0.0 };
+        Ok(RemainingTime {
+            minutes,
+            p10: (minutes - spread).max(0.0),
+            p90: minutes + spread,
+        })
+    }
+
+    fn is_dry(&self) -> bool {
+        state_is_dry(&self.x)
+    }
+
+    fn rewet(&mut self, resistance: f64) {
+        rewet_state(&mut self.x, &mut self.p, &self.parameters, resistance);
+    }
+
+    fn state(&self) -> Vec<f64> {
+        self.x.clone()
+    }
+
+    fn covariance(&self) -> Vec<f64> {
+        self.p.clone()
+    }
+
+    fn restore(&mut self, state: &[f64], covariance: &[f64]) -> bool {
+        let n = self.x.len();
+        if state.len() != n || covariance.len() != n * n {
+            return false;
+        }
+        self.x = state.to_vec();
+        self.p = covariance.to_vec();
+        true
+    }
+
+    /// Runs the unscented predict step forward from the current state, so the bands come from
+    /// the propagated sigma points rather than a linearisation.
+    fn forecast(&self, from: DateTime<Utc>, horizon_minutes: f64) -> Option<Forecast> {
+        let (steps, step, q) = forecast_steps(&self.parameters, horizon_minutes);
+
+        let mut x = self.x.clone();
+        let mut p = self.p.clone();
+        let mut points = Vec::with_capacity(steps + 1);
+        for i in 0..=steps {
+            if i > 0 {
+                (x, p) = self.predict(&x, &p, step, &q);
+            }
+            points.push(forecast_point(from, step * i as f64, &x, &p));
+        }
+
+        Some(Forecast {
+            from,
+            step_minutes: step,
+            points,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drying_model::EkfModel;
+    use crate::washing_predictor::test_parameters;
+
+    #[test]
+    fn test_square_root_of_semi_definite_matrix() {
+        // A zero variance, and a correlated pair.
+        let p = vec![
+            4.0, 0.0, 2.0,
+            0.0, 0.0, 0.0,
+            2.0, 0.0, 5.0,
+        ];
+        let l = lower_square_root(&p, 3);
+        for i in 0..3 {
+            for j in 0..3 {
+                let product: f64 = (0..3).map(|k| l[i * 3 + k] * l[j * 3 + k]).sum();
+                assert!((product - p[i * 3 + j]).abs() < 1e-12, "({i}, {j}): {product} != {}", p[i * 3 + j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sigma_points_reproduce_mean_and_covariance() {
+        let parameters = test_parameters(ModelKind::Ukf);
+        let sigma = SigmaPoints::new(&parameters.initial_state, &parameters.initial_covariance);
+        assert_eq!(sigma.points.len(), 13);
+        let (mean, covariance) = sigma.recombine(&[0.0; 36]);
+        for (a, b) in mean.iter().zip(&parameters.initial_state) {
+            assert!((a - b).abs() <= 1e-9 * b.abs());
+        }
+        for (a, b) in covariance.iter().zip(&parameters.initial_covariance) {
+            assert!((a - b).abs() <= 1e-9 * b.abs().max(1e-12));
+        }
+    }
+
+    #[test]
+    fn test_ukf_tracks_drying_like_the_ekf() {
+        let parameters = test_parameters(ModelKind::Ukf);
+        let mut ukf = UkfModel::new(&parameters).unwrap();
+        let mut ekf = EkfModel::new(&parameters).unwrap();
+
+        // Readings generated from the model's own initial state, every 2 minutes.
+        let system = moisture_sensor_model(&parameters);
+        let mut truth = parameters.initial_state.clone();
+        for i in 0..30 {
+            let elapsed = if i == 0 { chrono::Duration::zero() } else { chrono::Duration::minutes(2) };
+            if i > 0 {
+                truth = system.state_transition(&truth, None, 2.0);
+            }
+            ukf.ingest(elapsed, truth[0]).unwrap();
+            ekf.ingest(elapsed, truth[0]).unwrap();
+        }
+
+        let ukf_remaining = ukf.remaining().unwrap();
+        let ekf_remaining = ekf.remaining().unwrap();
+        let true_remaining = remaining_minutes(&truth).unwrap();
+        assert!((ukf_remaining.minutes - true_remaining).abs() < 5.0, "{ukf_remaining:?} vs {true_remaining}");
+        assert!((ukf_remaining.minutes - ekf_remaining.minutes).abs() < 5.0);
+        assert!(ukf_remaining.p10 <= ukf_remaining.minutes && ukf_remaining.minutes <= ukf_remaining.p90);
+
+        let forecast = ukf.forecast(Utc::now(), 30.0).unwrap();
+        assert!(forecast.points.last().unwrap().resistance > forecast.points[0].resistance);
+    }
+}
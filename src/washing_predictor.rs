@@ -4,34 +4,250 @@
 //!
 //!
 
-use crate::prediction_algorithms::MoistureSensorModel;
-use chrono::{DateTime, Utc};
+use crate::drying_model::{self, DryingModel, ModelKind, RemainingTime};
+use crate::ensemble::DEFAULT_ENSEMBLE_MODELS;
+use crate::particle_filter::{DEFAULT_PARTICLE_COUNT, MAX_PARTICLE_COUNT, MIN_PARTICLE_COUNT};
+use chrono::{DateTime, SubsecRound, Utc};
 use dashmap::DashMap;
-use kalman_filters::{ExtendedKalmanFilter, ExtendedKalmanFilterBuilder};
 use sqlx::PgPool;
 use rocket_db_pools::sqlx::{self, Row};
-use thiserror;
 
-#[derive(serde::Deserialize)]
+/// A resistance change larger than this between the filter's estimate and a new reading is
+/// treated as a discontinuity: either a new drying cycle or the washing being re-wetted.
+const RESET_THRESHOLD_OHMS: f64 = 1e6;
+
+/// A drop must be at least this large (and at least `REWET_DROP_FRACTION` of the moisture
+/// dependent part of the resistance) to be considered re-wetting.
+const REWET_MIN_DROP_OHMS: f64 = 5e4;
+const REWET_DROP_FRACTION: f64 = 0.5;
+
+/// Drops later than this after the cycle started are assumed to be a new load rather than rain.
+const REWET_MAX_CYCLE_AGE_HOURS: i64 = 8;
+
+/// Forecasts never reach further than this.
+const MAX_FORECAST_MINUTES: f64 = 24.0 * 60.0;
+/// Horizon used when the caller gives none and the filter has no valid completion estimate.
+const DEFAULT_FORECAST_MINUTES: f64 = 120.0;
+
+/// How far back stored telemetry is replayed to rebuild a filter that was lost. Longer than any
+/// realistic drying cycle, so the replay always reaches back to the current cycle's start.
+const CYCLE_REPLAY_HOURS: i64 = 12;
+
+#[derive(serde::Deserialize, Debug, Clone)]
 pub struct TelemetryData {
     pub timestamp: DateTime<Utc>,
     pub resistance: f64,
 }
-struct EKFEntry {
-    ekf: ExtendedKalmanFilter<f64, MoistureSensorModel>,
-    _start_time: DateTime<Utc>,
+
+/// What a reading meant for the device's drying cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CycleEvent {
+    /// Normal reading, the filter was updated.
+    Continuing,
+    /// A large resistance jump reset the filter and started a new cycle.
+    NewCycle,
+    /// The reading dropped sharply while the washing was still drying. It is held back from the
+    /// filter until the next reading shows whether the drop lasts.
+    SuspectedRewetting,
+    /// The drop lasted: the washing has been re-wetted (rain). The cycle and the learned drying
+    /// parameters are kept, only the resistance and moisture estimates are re-initialised.
+    Rewetting,
+}
+
+impl CycleEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CycleEvent::Continuing => "continuing",
+            CycleEvent::NewCycle => "new_cycle",
+            CycleEvent::SuspectedRewetting => "suspected_rewetting",
+            CycleEvent::Rewetting => "rewetting",
+        }
+    }
+}
+
+/// Predicted completion time with a confidence band derived from the filter covariance.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct CompletionEstimate {
+    pub completion_time: DateTime<Utc>,
+    /// 10th percentile: only a 1 in 10 chance the washing is dry before this.
+    pub p10: DateTime<Utc>,
+    /// 90th percentile: a 9 in 10 chance the washing is dry by this.
+    pub p90: DateTime<Utc>,
+    /// The model the estimate came from: the device's model, or for an ensemble the member
+    /// with the most weight.
+    pub model: ModelKind,
+}
+
+/// Snapshot of a device's filter for debugging and tuning.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PredictionDetails {
+    /// The drying model the device runs
+    pub model: ModelKind,
+    /// State vector [R, M, k, tau, M_c, R_offset], empty for models without one
+    pub state: Vec<f64>,
+    /// Variances of the state vector elements (diagonal of P)
+    pub covariance_diagonal: Vec<f64>,
+    /// Measured minus predicted resistance at the last update, before the update was applied
+    pub last_innovation: Option<f64>,
+    pub cycle_start: DateTime<Utc>,
+    pub last_received_time: DateTime<Utc>,
+    pub update_count: u32,
+    pub rewet_count: u32,
+    /// Remaining drying time as of `last_received_time`. `None` if the state gives no valid
+    /// estimate (e.g. the moisture is already below M_c).
+    pub remaining_minutes: Option<f64>,
+    /// Whether the model considers the washing dry (`DryingModel::is_dry`)
+    pub dry: bool,
+    pub estimate: Option<CompletionEstimate>,
+}
+
+/// One step of a forecast trajectory, with p10/p90 bands from the propagated covariance.
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct ForecastPoint {
+    pub time: DateTime<Utc>,
+    /// Minutes after the last reading
+    pub minutes: f64,
+    pub resistance: f64,
+    pub resistance_p10: f64,
+    pub resistance_p90: f64,
+    pub moisture: f64,
+    pub moisture_p10: f64,
+    pub moisture_p90: f64,
+}
+
+/// Expected resistance and moisture curve from the last reading onwards.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Forecast {
+    pub from: DateTime<Utc>,
+    pub step_minutes: f64,
+    pub points: Vec<ForecastPoint>,
+}
+
+/// Result of feeding a reading into the predictor.
+#[derive(Debug, Clone, Copy)]
+pub struct Prediction {
+    pub estimate: CompletionEstimate,
+    pub cycle_start: DateTime<Utc>,
+    pub event: CycleEvent,
+}
+
+struct ModelEntry {
+    model: Box<dyn DryingModel>,
+    parameters: EKFParameters, // Parameters the model was built from, compared on configuration changes
+    start_time: DateTime<Utc>,
     last_received_time: DateTime<Utc>,
+    pending_drop: Option<DateTime<Utc>>, // Timestamp of a held back drop awaiting confirmation
+    rewet_count: u32, // Number of confirmed re-wetting events during this cycle
+    update_count: u32, // Number of readings applied to the model during this cycle
+    last_innovation: Option<f64>, // Measured minus predicted resistance at the last update
 }
 
-#[derive(serde::Deserialize)]
-struct Wrapper {
-    configuration: EKFParameters,
+/// One prediction as written to the `predictions` table, so the history of the estimate
+/// during a cycle can be inspected afterwards.
+#[derive(Debug, Clone)]
+pub struct PredictionRecord {
+    pub device_id: String,
+    /// Timestamp of the reading that produced the prediction
+    pub reading_time: DateTime<Utc>,
+    pub resistance: f64,
+    pub cycle_start: DateTime<Utc>,
+    pub event: CycleEvent,
+    pub model: ModelKind,
+    pub estimate: CompletionEstimate,
+    pub state: Vec<f64>,
+    pub covariance_diagonal: Vec<f64>,
+}
+
+/// Snapshot of a device's filter, persisted so a restarted server carries on mid-cycle instead
+/// of starting again from the configured initial state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFilterState {
+    /// State vector x: [R, M, k, tau, M_c, R_offset]
+    pub state: Vec<f64>,
+    /// Flattened 6×6 covariance matrix P (row-major)
+    pub covariance: Vec<f64>,
+    pub start_time: DateTime<Utc>,
+    pub last_received_time: DateTime<Utc>,
+    /// Readings applied during the cycle so far
+    pub update_count: u32,
+}
+
+impl StoredFilterState {
+    fn from_entry(entry: &ModelEntry) -> Self {
+        StoredFilterState {
+            state: entry.model.state(),
+            covariance: entry.model.covariance(),
+            start_time: entry.start_time,
+            last_received_time: entry.last_received_time,
+            update_count: entry.update_count,
+        }
+    }
+}
+
+/// When a changed device configuration reaches the device's filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyConfiguration {
+    /// Rebuild the filter now by replaying the stored readings with the new parameters.
+    #[default]
+    Immediately,
+    /// Keep the current filter; the filter of the next cycle is built with the new parameters.
+    NextCycle,
+}
+
+impl ApplyConfiguration {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApplyConfiguration::Immediately => "immediately",
+            ApplyConfiguration::NextCycle => "next_cycle",
+        }
+    }
+}
+
+/// Postgres channel the `devices` trigger in `schema.sql` notifies when a device's
+/// configuration changes.
+pub const CONFIGURATION_CHANNEL: &str = "device_configuration";
+
+/// Session setting the trigger reads `apply` from. Changes made without it (e.g. straight in
+/// the database) are applied immediately.
+pub const APPLY_CONFIGURATION_SETTING: &str = "washing.apply_configuration";
+
+/// How long to wait before listening again after the configuration listener lost its connection.
+const CONFIGURATION_LISTENER_RETRY: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Payload of a `CONFIGURATION_CHANNEL` notification.
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct ConfigurationChange {
+    device_id: String,
+    #[serde(default)]
+    apply: ApplyConfiguration,
+}
+
+/// Preset used by devices whose configuration does not name one.
+pub const DEFAULT_PRESET: &str = "default";
+
+/// Builds a device's EKF parameters from a preset and the device's own `configuration`
+/// object, whose fields override the preset's one by one. Either may be missing, but together
+/// they must provide every field.
+pub fn resolve_ekf_parameters(
+    preset: Option<&serde_json::Value>,
+    overrides: Option<&serde_json::Value>,
+) -> Result<EKFParameters, serde_json::Error> {
+    let mut fields = preset
+        .and_then(|preset| preset.as_object())
+        .cloned()
+        .unwrap_or_default();
+    if let Some(overrides) = overrides.and_then(|overrides| overrides.as_object()) {
+        fields.extend(overrides.iter().map(|(key, value)| (key.clone(), value.clone())));
+    }
+    serde_json::from_value(serde_json::Value::Object(fields))
 }
 
 // pub(crate) makes EKFParameters visible within this crate (including the test submodule)
 // but not to external crates. This is needed so the mock in `mod tests` can construct it.
 // Without pub(crate), the struct and its fields would be private to this module only.
-#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
 pub struct EKFParameters {
     /// Initial 6-element state vector: [R, M, k, tau, M_c, R_offset]
     pub(crate) initial_state: Vec<f64>,
@@ -41,8 +257,258 @@ pub struct EKFParameters {
     pub(crate) process_noise_covariance: Vec<f64>,
     /// 1-element measurement noise covariance R (since we only measure resistance)
     pub(crate) measurement_noise_covariance: Vec<f64>,
-    /// EKF time step in minutes
+    /// Longest EKF predict sub-step in minutes. The real gap between readings is covered in
+    /// sub-steps of at most this length, and `process_noise_covariance` is specified per `dt`.
     pub(crate) dt: f64,
+    /// The drying model the device runs (see `drying_model`). The EKF if not given.
+    #[serde(default)]
+    pub(crate) model: ModelKind,
+    /// Number of particles of the `particle` model. Ignored by the other models.
+    #[serde(default = "default_particle_count")]
+    pub(crate) particle_count: usize,
+    /// Models combined by the `ensemble` model. Ignored by the other models.
+    #[serde(default = "default_ensemble_models")]
+    pub(crate) ensemble_models: Vec<ModelKind>,
+}
+
+fn default_particle_count() -> usize {
+    DEFAULT_PARTICLE_COUNT
+}
+
+fn default_ensemble_models() -> Vec<ModelKind> {
+    DEFAULT_ENSEMBLE_MODELS.to_vec()
+}
+
+/// Number of entries in the state vector `[R, M, k, tau, M_c, R_offset]`.
+const STATE_DIM: usize = 6;
+/// Longest `dt` accepted, in minutes.
+const MAX_DT_MINUTES: f64 = 60.0;
+/// Tolerance of the symmetry and positive semi-definiteness checks, relative to the diagonal.
+const MATRIX_TOLERANCE: f64 = 1e-9;
+
+/// A problem with one field of a device configuration, reported back to the client.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Checks that a flattened `n` x `n` matrix is symmetric and positive semi-definite.
+///
+/// The matrix is first scaled to unit diagonal, since the variances of the state differ by
+/// more than ten orders of magnitude, and then factorised with a Cholesky decomposition that
+/// allows zero pivots. A zero variance must come with zero covariances.
+fn check_covariance(matrix: &[f64], n: usize) -> Result<(), String> {
+    if matrix.iter().any(|value| !value.is_finite()) {
+        return Err("must only contain finite numbers".to_string());
+    }
+    for i in 0..n {
+        for j in 0..i {
+            let (a, b) = (matrix[i * n + j], matrix[j * n + i]);
+            if (a - b).abs() > MATRIX_TOLERANCE * (a.abs() + b.abs()) {
+                return Err(format!("must be symmetric, but entry ({i}, {j}) is {a} and ({j}, {i}) is {b}"));
+            }
+        }
+    }
+
+    let diagonal: Vec<f64> = (0..n).map(|i| matrix[i * n + i]).collect();
+    if let Some(i) = diagonal.iter().position(|variance| *variance < 0.0) {
+        return Err(format!("must be positive semi-definite, but diagonal entry {i} is negative"));
+    }
+    let mut scaled = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            let value = matrix[i * n + j];
+            if diagonal[i] == 0.0 || diagonal[j] == 0.0 {
+                if value != 0.0 {
+                    return Err(format!(
+                        "must be positive semi-definite, but entry ({i}, {j}) is non-zero while a variance it belongs to is zero"
+                    ));
+                }
+            } else {
+                scaled[i * n + j] = value / (diagonal[i] * diagonal[j]).sqrt();
+            }
+        }
+    }
+
+    for k in 0..n {
+        let pivot = scaled[k * n + k];
+        if pivot < -MATRIX_TOLERANCE {
+            return Err("must be positive semi-definite".to_string());
+        }
+        if pivot <= MATRIX_TOLERANCE {
+            if (k + 1..n).any(|i| scaled[i * n + k].abs() > MATRIX_TOLERANCE.sqrt()) {
+                return Err("must be positive semi-definite".to_string());
+            }
+            continue;
+        }
+        for i in k + 1..n {
+            let factor = scaled[i * n + k] / pivot;
+            for j in k + 1..n {
+                scaled[i * n + j] -= factor * scaled[k * n + j];
+            }
+        }
+    }
+    Ok(())
+}
+
+impl EKFParameters {
+    /// Checks the shapes and ranges the filter relies on, so a bad configuration is rejected
+    /// when it is written instead of failing (or panicking) on every reading. Field names are
+    /// relative to the EKF parameters object.
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        if self.initial_state.len() != STATE_DIM {
+            errors.push(FieldError::new(
+                "initial_state",
+                format!("must have {STATE_DIM} elements [R, M, k, tau, M_c, R_offset], got {}", self.initial_state.len()),
+            ));
+        } else if let Some(i) = self.initial_state.iter().position(|value| !value.is_finite()) {
+            errors.push(FieldError::new(format!("initial_state[{i}]"), "must be a finite number"));
+        } else {
+            let state = &self.initial_state;
+            let mut check = |index: usize, name: &str, valid: bool, expected: &str| {
+                if !valid {
+                    errors.push(FieldError::new(
+                        format!("initial_state[{index}]"),
+                        format!("{name} must be {expected}, got {}", state[index]),
+                    ));
+                }
+            };
+            check(0, "R", state[0] > 0.0, "positive");
+            check(1, "M", state[1] > 0.0 && state[1] <= 10.0, "greater than 0 and at most 10");
+            check(2, "k", state[2] > 0.0 && state[2] <= 10.0, "greater than 0 and at most 10 per minute");
+            check(3, "tau", state[3] > 0.0 && state[3] <= 10.0, "greater than 0 and at most 10");
+            check(4, "M_c", state[4] >= 0.0 && state[4] < state[1], "at least 0 and below M");
+            check(5, "R_offset", state[5] >= 0.0, "at least 0");
+        }
+
+        for (field, matrix) in [
+            ("initial_covariance", &self.initial_covariance),
+            ("process_noise_covariance", &self.process_noise_covariance),
+        ] {
+            if matrix.len() != STATE_DIM * STATE_DIM {
+                errors.push(FieldError::new(
+                    field,
+                    format!("must be a flattened {STATE_DIM}x{STATE_DIM} matrix with {} elements, got {}", STATE_DIM * STATE_DIM, matrix.len()),
+                ));
+            } else if let Err(message) = check_covariance(matrix, STATE_DIM) {
+                errors.push(FieldError::new(field, message));
+            }
+        }
+
+        match self.measurement_noise_covariance.as_slice() {
+            [variance] if variance.is_finite() && *variance > 0.0 => {}
+            [variance] => errors.push(FieldError::new(
+                "measurement_noise_covariance",
+                format!("must be a positive variance, got {variance}"),
+            )),
+            other => errors.push(FieldError::new(
+                "measurement_noise_covariance",
+                format!("must have 1 element, got {}", other.len()),
+            )),
+        }
+
+        if !(self.dt.is_finite() && self.dt > 0.0 && self.dt <= MAX_DT_MINUTES) {
+            errors.push(FieldError::new(
+                "dt",
+                format!("must be greater than 0 and at most {MAX_DT_MINUTES} minutes, got {}", self.dt),
+            ));
+        }
+
+        if !(MIN_PARTICLE_COUNT..=MAX_PARTICLE_COUNT).contains(&self.particle_count) {
+            errors.push(FieldError::new(
+                "particle_count",
+                format!("must be between {MIN_PARTICLE_COUNT} and {MAX_PARTICLE_COUNT}, got {}", self.particle_count),
+            ));
+        }
+
+        if self.ensemble_models.is_empty() {
+            errors.push(FieldError::new("ensemble_models", "must name at least one model"));
+        }
+        for (i, kind) in self.ensemble_models.iter().enumerate() {
+            if *kind == ModelKind::Ensemble {
+                errors.push(FieldError::new(
+                    format!("ensemble_models[{i}]"),
+                    "an ensemble cannot contain another ensemble",
+                ));
+            } else if self.ensemble_models[..i].contains(kind) {
+                errors.push(FieldError::new(
+                    format!("ensemble_models[{i}]"),
+                    format!("{} is listed more than once", kind.as_str()),
+                ));
+            }
+        }
+
+        errors
+    }
+}
+
+/// Parameters of the tests' drying cycle (the defaults of the `default` preset) for `model`.
+#[cfg(test)]
+pub(crate) fn test_parameters(model: ModelKind) -> EKFParameters {
+    let diagonal = |values: [f64; STATE_DIM]| {
+        let mut matrix = vec![0.0; STATE_DIM * STATE_DIM];
+        for (i, value) in values.into_iter().enumerate() {
+            matrix[i * STATE_DIM + i] = value;
+        }
+        matrix
+    };
+    EKFParameters {
+        initial_state: vec![30000.0, 0.02, 0.1, 0.81, 1e-9, 29976.33],
+        initial_covariance: diagonal([1.0e1, 1.0e-10, 1.0e-6, 1.0e-6, 0.0, 0.0]),
+        process_noise_covariance: diagonal([1.0e-2, 1.0e-12, 1.0e-8, 1.0e-7, 0.0, 0.0]),
+        measurement_noise_covariance: vec![1.0e6],
+        dt: 2.0,
+        model,
+        particle_count: DEFAULT_PARTICLE_COUNT,
+        ensemble_models: DEFAULT_ENSEMBLE_MODELS.to_vec(),
+    }
+}
+
+/// Validates a device's `configuration` JSON as it would be resolved by
+/// `PostgresDeviceRepository::get_ekf_parameters`. `preset` is the parameters of the preset the
+/// configuration names (or the default preset), `None` if there is no such preset.
+pub fn validate_configuration(configuration: &serde_json::Value, preset: Option<&serde_json::Value>) -> Vec<FieldError> {
+    if !configuration.is_object() {
+        return vec![FieldError::new("configuration", "must be a JSON object")];
+    }
+
+    let mut errors = Vec::new();
+    match &configuration["preset"] {
+        serde_json::Value::Null => {}
+        serde_json::Value::String(name) if preset.is_none() => {
+            errors.push(FieldError::new("configuration.preset", format!("unknown preset '{name}'")));
+        }
+        serde_json::Value::String(_) => {}
+        _ => errors.push(FieldError::new("configuration.preset", "must be the name of a preset")),
+    }
+    let overrides = configuration.get("configuration");
+    if overrides.is_some_and(|overrides| !overrides.is_object()) {
+        errors.push(FieldError::new("configuration.configuration", "must be a JSON object of EKF parameters"));
+    }
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    match resolve_ekf_parameters(preset, overrides) {
+        Ok(parameters) => parameters
+            .validate()
+            .into_iter()
+            .map(|error| FieldError::new(format!("configuration.configuration.{}", error.field), error.message))
+            .collect(),
+        Err(e) => vec![FieldError::new("configuration.configuration", e.to_string())],
+    }
 }
 
 // --- Dependency inversion via a trait ---
@@ -52,8 +518,17 @@ pub struct EKFParameters {
 //
 // `async fn` in traits is stable since Rust 1.75 (AFIT). No `async_trait` crate needed.
 // `Send + Sync` are required so WashingPredictor<R> can be shared across async tasks.
+#[allow(async_fn_in_trait)]
 pub trait DeviceRepository: Send + Sync {
     async fn get_ekf_parameters(&self, device_id: &str) -> Result<EKFParameters, PredictorError>;
+    /// Returns the filter state saved by `save_filter_state`, if there is one.
+    async fn load_filter_state(&self, device_id: &str) -> Result<Option<StoredFilterState>, PredictorError>;
+    async fn save_filter_state(&self, device_id: &str, state: &StoredFilterState) -> Result<(), PredictorError>;
+    async fn delete_filter_state(&self, device_id: &str) -> Result<(), PredictorError>;
+    /// Returns the resistance readings stored in the `CYCLE_REPLAY_HOURS` before `before`,
+    /// oldest first. Replaying them rebuilds the current cycle's filter.
+    async fn get_cycle_telemetry(&self, device_id: &str, before: DateTime<Utc>) -> Result<Vec<TelemetryData>, PredictorError>;
+    async fn record_prediction(&self, record: &PredictionRecord) -> Result<(), PredictorError>;
 }
 
 /// Production implementation: fetches EKF configuration from PostgreSQL.
@@ -67,32 +542,156 @@ impl PostgresDeviceRepository {
     }
 }
 
+/// The resistance reading stored in a `telemetry` row (its `payload` and `timestamp` columns),
+/// or `None` for a message without one, e.g. a battery report. Same rule as
+/// `process_telemetry`: the sensor's timestamp wins over the insert time.
+pub(crate) fn telemetry_from_row(row: &sqlx::postgres::PgRow) -> Result<Option<TelemetryData>, sqlx::Error> {
+    let stored_at: DateTime<Utc> = row.try_get("timestamp")?;
+    let payload: serde_json::Value = row.try_get("payload")?;
+    let Some(resistance) = payload["resistance"].as_f64() else {
+        return Ok(None);
+    };
+    let timestamp = payload["timestamp"]
+        .as_str()
+        .and_then(|s| s.parse::<DateTime<Utc>>().ok())
+        .unwrap_or(stored_at);
+    Ok(Some(TelemetryData { timestamp, resistance }))
+}
+
 impl DeviceRepository for PostgresDeviceRepository {
     async fn get_ekf_parameters(&self, device_id: &str) -> Result<EKFParameters, PredictorError> {
-        let row = sqlx::query("SELECT configuration FROM devices WHERE device_id = $1")
-            .bind(device_id)
-            .fetch_one(&self.pool)
-            .await
-            .map_err(|e| {
-                eprintln!("Unable to retrieve device configuration: {e}");
-                PredictorError::DeviceNotFound(e.to_string())
-            })?;
-
-            let configuration_json: serde_json::Value = row.try_get("configuration")?;
-            println!("Raw configuration JSON for device {}: {}", device_id, configuration_json);
-            let wrapper: Wrapper = serde_json::from_value(configuration_json).map_err(|e| {
-                eprintln!("Unable to parse EKF parameters from database: {e}");
-                PredictorError::Database(sqlx::Error::ColumnDecode {
-                    index: "configuration".to_string(),
-                    source: Box::new(e),
-                })
-            })?;
-
-            let ekf_parameters = wrapper.configuration;
-            
+        // The device's preset, or the default preset if it does not name one.
+        let row = sqlx::query(
+            "SELECT d.configuration, p.parameters AS preset FROM devices d
+            LEFT JOIN model_presets p ON p.name = COALESCE(d.configuration->>'preset', $2)
+            WHERE d.device_id = $1",
+        )
+        .bind(device_id)
+        .bind(DEFAULT_PRESET)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Unable to retrieve device configuration: {e}");
+            PredictorError::DeviceNotFound(e.to_string())
+        })?;
+
+        let configuration_json: serde_json::Value = row.try_get("configuration")?;
+        let preset: Option<serde_json::Value> = row.try_get("preset")?;
+        println!("Raw configuration JSON for device {}: {}", device_id, configuration_json);
+        if preset.is_none()
+            && let Some(name) = configuration_json["preset"].as_str()
+        {
+            eprintln!("Device {} references unknown preset '{}'", device_id, name);
+        }
+
+        let ekf_parameters = resolve_ekf_parameters(preset.as_ref(), configuration_json.get("configuration")).map_err(|e| {
+            eprintln!("Unable to parse EKF parameters from database: {e}");
+            PredictorError::Database(sqlx::Error::ColumnDecode {
+                index: "configuration".to_string(),
+                source: Box::new(e),
+            })
+        })?;
+
         println!("Successfully retrieved EKF parameters for device {}: {:?}", device_id, serde_json::to_string_pretty(&ekf_parameters));
         Ok(ekf_parameters)
     }
+
+    async fn load_filter_state(&self, device_id: &str) -> Result<Option<StoredFilterState>, PredictorError> {
+        let row = sqlx::query(
+            "SELECT state, covariance, start_time, last_received_time, update_count FROM ekf_states WHERE device_id = $1",
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(StoredFilterState {
+                state: row.try_get("state")?,
+                covariance: row.try_get("covariance")?,
+                start_time: row.try_get("start_time")?,
+                last_received_time: row.try_get("last_received_time")?,
+                update_count: row.try_get::<i32, _>("update_count")?.max(0) as u32,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn save_filter_state(&self, device_id: &str, state: &StoredFilterState) -> Result<(), PredictorError> {
+        sqlx::query(
+            "INSERT INTO ekf_states (device_id, state, covariance, start_time, last_received_time, update_count, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, NOW())
+            ON CONFLICT (device_id) DO UPDATE SET
+                state = EXCLUDED.state,
+                covariance = EXCLUDED.covariance,
+                start_time = EXCLUDED.start_time,
+                last_received_time = EXCLUDED.last_received_time,
+                update_count = EXCLUDED.update_count,
+                updated_at = NOW()",
+        )
+        .bind(device_id)
+        .bind(&state.state)
+        .bind(&state.covariance)
+        .bind(state.start_time)
+        .bind(state.last_received_time)
+        .bind(state.update_count as i32)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_filter_state(&self, device_id: &str) -> Result<(), PredictorError> {
+        sqlx::query("DELETE FROM ekf_states WHERE device_id = $1")
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_cycle_telemetry(&self, device_id: &str, before: DateTime<Utc>) -> Result<Vec<TelemetryData>, PredictorError> {
+        // Only resistance readings are wanted, battery messages share the table.
+        let rows = sqlx::query(
+            "SELECT timestamp, payload FROM telemetry
+            WHERE device_id = $1 AND timestamp >= $2 AND payload ? 'resistance'
+            ORDER BY timestamp ASC",
+        )
+        .bind(device_id)
+        .bind(before - chrono::Duration::hours(CYCLE_REPLAY_HOURS))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut readings = Vec::with_capacity(rows.len());
+        for row in &rows {
+            // The reading being processed right now has already been inserted, and carries its
+            // stored time if the payload has none, so `before` skips it.
+            if let Some(reading) = telemetry_from_row(row)?.filter(|reading| reading.timestamp < before) {
+                readings.push(reading);
+            }
+        }
+        readings.sort_by_key(|reading| reading.timestamp);
+        Ok(readings)
+    }
+
+    async fn record_prediction(&self, record: &PredictionRecord) -> Result<(), PredictorError> {
+        sqlx::query(
+            "INSERT INTO predictions (device_id, reading_time, resistance, cycle_start, event, model,
+                completion_time, completion_p10, completion_p90, state, covariance_diagonal)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        )
+        .bind(&record.device_id)
+        .bind(record.reading_time)
+        .bind(record.resistance)
+        .bind(record.cycle_start)
+        .bind(record.event.as_str())
+        .bind(record.model.as_str())
+        .bind(record.estimate.completion_time)
+        .bind(record.estimate.p10)
+        .bind(record.estimate.p90)
+        .bind(&record.state)
+        .bind(&record.covariance_diagonal)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
 }
 
 // WashingPredictor is now generic over R.
@@ -102,7 +701,7 @@ impl DeviceRepository for PostgresDeviceRepository {
 // zero-cost compared to using `Box<dyn DeviceRepository>` (dynamic dispatch / vtable).
 pub struct WashingPredictor<R: DeviceRepository> {
     repo: R,
-    predictor_cache: DashMap<String, EKFEntry>, // Cache for EKF instances keyed by device ID
+    predictor_cache: DashMap<String, ModelEntry>, // Cache for drying models keyed by device ID
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -121,12 +720,22 @@ pub enum PredictorError {
 
     #[error("drying time calculation produced invalid result (NaN/negative)")]
     InvalidPrediction,
+
+    #[error("the drying model has no completion estimate yet")]
+    NoEstimate,
+
+    #[error("reading for device {device_id} at {timestamp} is not newer than the last reading at {last_received_time}")]
+    StaleReading {
+        device_id: String,
+        timestamp: DateTime<Utc>,
+        last_received_time: DateTime<Utc>,
+    },
 }
 
 impl<R: DeviceRepository> WashingPredictor<R> {
     /// Constructor takes any type implementing DeviceRepository.
     /// In production: `WashingPredictor::new(PostgresDeviceRepository::new(pool))`
-    /// In tests:      `WashingPredictor::new(MockDeviceRepository)`
+    /// In tests:      `WashingPredictor::new(MockDeviceRepository::default())`
     pub fn new(repo: R) -> Self {
         WashingPredictor {
             repo,
@@ -138,52 +747,154 @@ impl<R: DeviceRepository> WashingPredictor<R> {
         &self,
         device_id: &str,
         telemetry_data: TelemetryData,
-    ) -> Result<DateTime<Utc>, PredictorError> {
-        // Placeholder for the actual EKF implementation
-        // In a real implementation, this function would initialize the EKF with the MoistureSensorModel,
-        // process the resistance measurements, and return an estimate of the remaining drying time.
-        // for _ in 0..1 {
+    ) -> Result<Prediction, PredictorError> {
+        if !self.predictor_cache.contains_key(device_id) {
+            self.restore_filter(device_id, telemetry_data.timestamp).await?;
+        }
+
+        let reading_time = telemetry_data.timestamp;
+        let resistance = telemetry_data.resistance;
+        let prediction = self.update_filter(device_id, telemetry_data).await?;
+
+        // Save the updated filter so a restart can pick up from here, and record the prediction.
+        // The cache entry is released before the awaits so no DashMap lock is held across them.
+        let snapshot = self.predictor_cache.get(device_id).map(|entry| {
+            let record = PredictionRecord {
+                device_id: device_id.to_string(),
+                reading_time,
+                resistance,
+                cycle_start: prediction.cycle_start,
+                event: prediction.event,
+                model: entry.model.kind(),
+                estimate: prediction.estimate,
+                state: entry.model.state(),
+                covariance_diagonal: covariance_diagonal(entry.model.as_ref()),
+            };
+            (StoredFilterState::from_entry(&entry), record)
+        });
+        if let Some((stored, record)) = snapshot {
+            if let Err(e) = self.repo.save_filter_state(device_id, &stored).await {
+                eprintln!("Unable to save EKF state for device {}: {}", device_id, e);
+            }
+            if let Err(e) = self.repo.record_prediction(&record).await {
+                eprintln!("Unable to record prediction for device {}: {}", device_id, e);
+            }
+        }
+
+        Ok(prediction)
+    }
+
+    /// Rebuilds a device's filter after a restart or cache eviction, so the first prediction is
+    /// as good as if the filter had never gone away. The saved filter state is used if there is
+    /// one, otherwise the telemetry stored for the current cycle is replayed through a fresh
+    /// filter. With neither, the cache is left empty and `update_filter` starts a new cycle.
+    async fn restore_filter(&self, device_id: &str, before: DateTime<Utc>) -> Result<(), PredictorError> {
+        let ekf_parameters = self.repo.get_ekf_parameters(device_id).await?;
+
+        // A saved filter without a reading for more than `CYCLE_REPLAY_HOURS` belongs to a load
+        // that has long been taken in, so the new reading must not carry on its cycle.
+        let stored = self.load_stored_state(device_id).await.filter(|stored| {
+            let fresh = before - stored.last_received_time <= chrono::Duration::hours(CYCLE_REPLAY_HOURS);
+            if !fresh {
+                println!("Discarding the saved state for device {} from {}", device_id, stored.last_received_time);
+            }
+            fresh
+        });
+        if let Some(stored) = stored {
+            let mut entry = self.build_entry(device_id, ekf_parameters.clone(), stored.start_time)?;
+            if entry.model.restore(&stored.state, &stored.covariance) {
+                println!(
+                    "Restored EKF state for device {} (cycle started {})",
+                    device_id, stored.start_time
+                );
+                entry.last_received_time = stored.last_received_time;
+                entry.update_count = stored.update_count;
+                self.predictor_cache.insert(device_id.to_string(), entry);
+                return Ok(());
+            }
+            println!("Saved state for device {} does not fit its {} model", device_id, entry.model.kind().as_str());
+        }
+
+        let history = match self.repo.get_cycle_telemetry(device_id, before).await {
+            Ok(history) => history,
+            Err(e) => {
+                eprintln!("Unable to load stored telemetry for device {}: {}", device_id, e);
+                return Ok(());
+            }
+        };
+        let Some(first) = history.first() else {
+            return Ok(());
+        };
+        let start_time = first.timestamp;
+        self.replay(device_id, ekf_parameters, start_time, history).await
+    }
+
+    /// Replays stored readings, oldest first, through a fresh filter for a cycle that started at
+    /// `start_time`. Replaying through `update_filter` reproduces the live behaviour, including
+    /// resets at cycle boundaries and held back re-wetting drops.
+    async fn replay(
+        &self,
+        device_id: &str,
+        ekf_parameters: EKFParameters,
+        start_time: DateTime<Utc>,
+        history: Vec<TelemetryData>,
+    ) -> Result<(), PredictorError> {
+        println!("Replaying {} stored readings for device {}", history.len(), device_id);
+        let entry = self.build_entry(device_id, ekf_parameters, start_time)?;
+        self.predictor_cache.insert(device_id.to_string(), entry);
+        for reading in history {
+            if let Err(e) = self.update_filter(device_id, reading).await {
+                eprintln!("Skipping stored reading for device {} during replay: {}", device_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds a fresh model of the kind given in the device's configured parameters.
+    fn build_entry(
+        &self,
+        device_id: &str,
+        ekf_parameters: EKFParameters,
+        start_time: DateTime<Utc>,
+    ) -> Result<ModelEntry, PredictorError> {
+        let model = drying_model::build_model(&ekf_parameters).map_err(|message| PredictorError::EkfError {
+            device_id: device_id.to_string(),
+            message,
+        })?;
+
+        // The cycle start is stored as a TIMESTAMPTZ, which only keeps microseconds. Dropping the
+        // rest here keeps it equal to the stored value, e.g. for the `CycleTracker`, when the
+        // reading's time came from `Utc::now()`.
+        let start_time = start_time.trunc_subsecs(6);
+        Ok(ModelEntry {
+            model,
+            parameters: ekf_parameters,
+            start_time,
+            last_received_time: start_time,
+            pending_drop: None,
+            rewet_count: 0,
+            update_count: 0,
+            last_innovation: None,
+        })
+    }
+
+    /// Feeds one reading into the device's cached filter, creating a fresh filter if there is
+    /// none, and returns the new prediction.
+    async fn update_filter(
+        &self,
+        device_id: &str,
+        telemetry_data: TelemetryData,
+    ) -> Result<Prediction, PredictorError> {
         let mut loop_counter = 0;
+        let mut event = CycleEvent::Continuing;
         loop {
             let mut entry = match self.predictor_cache.get_mut(device_id) {
-                Some(mut entry) => { 
-                    // Update the last_received_time for this entry
-                    entry.last_received_time = telemetry_data.timestamp;
-                    entry
-                }
+                Some(entry) => entry,
                 None => {
                     let ekf_parameters =
                         self.repo.get_ekf_parameters(device_id).await?;
-
-                    let system = MoistureSensorModel {
-                        _r: ekf_parameters.initial_state[0],
-                        _m: ekf_parameters.initial_state[1],
-                        _k: ekf_parameters.initial_state[2],
-                        _tau: ekf_parameters.initial_state[3],
-                        _m_c: ekf_parameters.initial_state[4],
-                        _r_offset: ekf_parameters.initial_state[5],
-                    };
-
-                    let ekf = ExtendedKalmanFilterBuilder::new(system)
-                        .initial_state(ekf_parameters.initial_state)
-                        .initial_covariance(ekf_parameters.initial_covariance)
-                        .process_noise(ekf_parameters.process_noise_covariance)
-                        .measurement_noise(ekf_parameters.measurement_noise_covariance)
-                        .dt(ekf_parameters.dt)
-                        .build()
-                        .map_err(|e| PredictorError::EkfError {
-                            device_id: device_id.to_string(),
-                            message: e.to_string(),
-                        })?;
-
-                    self.predictor_cache.insert(
-                        device_id.to_string(),
-                        EKFEntry {
-                            ekf,
-                            _start_time: telemetry_data.timestamp,
-                            last_received_time: telemetry_data.timestamp,
-                        },
-                    );
+                    let entry = self.build_entry(device_id, ekf_parameters, telemetry_data.timestamp)?;
+                    self.predictor_cache.insert(device_id.to_string(), entry);
 
                     self.predictor_cache
                         .get_mut(device_id)
@@ -191,48 +902,135 @@ impl<R: DeviceRepository> WashingPredictor<R> {
                 }
             };
 
-            // Check for large jump in resistance to detect new drying cycle
+            // The filter's state belongs to `last_received_time`, so a reading from before that
+            // (delivered out of order) or a repeat of it (a retried message) cannot be applied.
+            let elapsed = telemetry_data.timestamp - entry.last_received_time;
+            if elapsed < chrono::Duration::zero() || (elapsed.is_zero() && entry.update_count > 0) {
+                return Err(PredictorError::StaleReading {
+                    device_id: device_id.to_string(),
+                    timestamp: telemetry_data.timestamp,
+                    last_received_time: entry.last_received_time,
+                });
+            }
+
             println!(
-                "Current vs new resitance {}: {:?}",
-                entry.ekf.state()[0],
+                "Current vs new resitance {:?}: {:?}",
+                entry.model.expected_resistance(),
                 telemetry_data.resistance
             );
-            if (entry.ekf.state()[0] - telemetry_data.resistance).abs() > 1e6 {
-                //we have a large jump in resistance, which likely indicates a new drying cycle has started. We should reset the EKF for this device.
-                drop(entry); // Drop the mutable reference to the EKF entry before modifying the cache
-                self.predictor_cache.remove(device_id); // evict the existing EKF entry from the cache
-                println!(
-                    "Large jump in resistance detected for device {}. Resetting EKF entry.",
-                    device_id
-                );
-                if loop_counter > 1 { return Err(PredictorError::InvalidPrediction); } // Prevent infinite loop in case of repeated large jumps
-                loop_counter += 1;
-                continue; // Restart the loop to create a new EKF entry for this device}
+
+            // A sharp drop while the washing is still drying is most likely rain. Rain and a new
+            // load both make the resistance fall, so the drop is only classed as re-wetting if the
+            // cycle is recent, the filter did not yet consider the washing dry, and the drop is
+            // still there on the following reading (a single low sample is treated as a glitch).
+            if self.is_rewetting_drop(&entry, &telemetry_data) {
+                match entry.pending_drop {
+                    None => {
+                        println!(
+                            "Sharp resistance drop for device {}. Waiting for the next reading to confirm re-wetting.",
+                            device_id
+                        );
+                        entry.pending_drop = Some(telemetry_data.timestamp);
+                        let estimate = self.estimate_completion(entry.model.remaining()?, entry.model.driver(), &entry.last_received_time)?;
+                        return Ok(Prediction {
+                            estimate,
+                            cycle_start: entry.start_time,
+                            event: CycleEvent::SuspectedRewetting,
+                        });
+                    }
+                    Some(since) => {
+                        println!(
+                            "Resistance drop for device {} has lasted since {}. Treating it as re-wetting.",
+                            device_id, since
+                        );
+                        entry.pending_drop = None;
+                        entry.model.rewet(telemetry_data.resistance);
+                        entry.rewet_count += 1;
+                        event = CycleEvent::Rewetting;
+                    }
+                }
+            } else {
+                entry.pending_drop = None; // Any held back drop did not last
+
+                // Check for large jump in resistance to detect new drying cycle
+                if entry
+                    .model
+                    .expected_resistance()
+                    .is_some_and(|expected| (expected - telemetry_data.resistance).abs() > RESET_THRESHOLD_OHMS)
+                {
+                    //we have a large jump in resistance, which likely indicates a new drying cycle has started. We should reset the EKF for this device.
+                    drop(entry); // Drop the mutable reference to the EKF entry before modifying the cache
+                    self.predictor_cache.remove(device_id); // evict the existing EKF entry from the cache
+                    println!(
+                        "Large jump in resistance detected for device {}. Resetting EKF entry.",
+                        device_id
+                    );
+                    if loop_counter > 1 { return Err(PredictorError::InvalidPrediction); } // Prevent infinite loop in case of repeated large jumps
+                    loop_counter += 1;
+                    event = CycleEvent::NewCycle;
+                    continue; // Restart the loop to create a new EKF entry for this device}
+                }
             }
 
-            // Update the EKF with the new telemetry data
-            entry.ekf.predict();
-            entry
-                .ekf
-                .update(&vec![telemetry_data.resistance])
-                .map_err(|e| PredictorError::EkfError {
+            // Update the model with the new telemetry data. The model first advances over the
+            // real time since the last reading (nothing to advance for a fresh model).
+            let innovation = entry
+                .model
+                .ingest(elapsed, telemetry_data.resistance)
+                .map_err(|message| PredictorError::EkfError {
                     device_id: device_id.to_string(),
-                    message: e.to_string(),
+                    message,
                 })?;
+            entry.last_innovation = Some(innovation);
+            entry.last_received_time = telemetry_data.timestamp;
+            entry.update_count += 1;
+
+            // Estimate the remaining drying time based on the updated model
+            let estimate = self.estimate_completion(entry.model.remaining()?, entry.model.driver(), &telemetry_data.timestamp)?;
+
+            return Ok(Prediction {
+                estimate,
+                cycle_start: entry.start_time,
+                event,
+            });
+        }
+    }
+
+    /// Loads the saved filter state for a device. Errors are logged and treated as no saved
+    /// state; a state that does not fit the device's model (e.g. a corrupt row) is rejected by
+    /// `DryingModel::restore`.
+    async fn load_stored_state(&self, device_id: &str) -> Option<StoredFilterState> {
+        match self.repo.load_filter_state(device_id).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("Unable to load saved EKF state for device {}: {}", device_id, e);
+                None
+            }
+        }
+    }
 
-            // Read the new state estimates
-            let current_state_estimate = entry.ekf.state();
+    /// True if the reading is a sharp drop that happened while the cycle was still drying.
+    fn is_rewetting_drop(&self, entry: &ModelEntry, telemetry_data: &TelemetryData) -> bool {
+        let Some(expected) = entry.model.expected_resistance() else {
+            return false;
+        };
+        let drop = expected - telemetry_data.resistance;
+        let moisture_dependent_resistance = entry.model.moisture_resistance();
+        let significant = drop > RESET_THRESHOLD_OHMS
+            || drop > REWET_MIN_DROP_OHMS.max(REWET_DROP_FRACTION * moisture_dependent_resistance);
+        if !significant {
+            return false;
+        }
 
-            // Estimate the remaining drying time based on the current state estimate
-            let completion_time = self
-                .estimate_drying_time(current_state_estimate, &telemetry_data.timestamp)
-                .map_err(|e| PredictorError::EkfError {
-                    device_id: device_id.to_string(),
-                    message: e.to_string(),
-                })?;
+        let cycle_age = telemetry_data.timestamp - entry.start_time;
+        if cycle_age > chrono::Duration::hours(REWET_MAX_CYCLE_AGE_HOURS) {
+            return false;
+        }
 
-            // For demonstration purposes, we will return a dummy value.
-            return Ok(completion_time); // Dummy value representing 30 minutes remaining
+        // If the filter already expected the washing to be dry by now, the drop is a new load.
+        match self.estimate_drying_time(entry.model.as_ref(), &entry.last_received_time) {
+            Ok(completion_time) => completion_time > telemetry_data.timestamp,
+            Err(_) => false,
         }
     }
 
@@ -241,8 +1039,7 @@ impl<R: DeviceRepository> WashingPredictor<R> {
         device_id: &str) -> Option<DateTime<Utc>> {
             // returns the estimated completion time in UTC for the given device ID 
         if let Some(entry) = self.predictor_cache.get(device_id) {
-            let current_state_estimate = entry.ekf.state();
-            match self.estimate_drying_time(current_state_estimate, &entry.last_received_time) {
+            match self.estimate_drying_time(entry.model.as_ref(), &entry.last_received_time) {
                 Ok(completion_time) => Some(completion_time),
                 Err(e) => { eprintln!("Error estimating drying time for device {}: {}", device_id, e); None }
             }
@@ -252,52 +1049,187 @@ impl<R: DeviceRepository> WashingPredictor<R> {
         }
     }
 
+    /// Forecast trajectory from the device's current filter state. Without a horizon it runs
+    /// until the predicted completion. Returns `None` if the device has no filter, or its model
+    /// cannot forecast.
+    pub fn get_forecast(&self, device_id: &str, horizon_minutes: Option<f64>) -> Option<Forecast> {
+        let entry = self.predictor_cache.get(device_id)?;
+        let horizon = horizon_minutes
+            .or_else(|| entry.model.remaining().ok().map(|remaining| remaining.minutes))
+            .filter(|horizon| horizon.is_finite() && *horizon > 0.0)
+            .unwrap_or(DEFAULT_FORECAST_MINUTES)
+            .min(MAX_FORECAST_MINUTES);
+        entry.model.forecast(entry.last_received_time, horizon)
+    }
+
+    /// The full filter state behind the device's current prediction.
+    pub fn get_prediction_details(&self, device_id: &str) -> Option<PredictionDetails> {
+        let entry = self.predictor_cache.get(device_id)?;
+        let remaining = entry.model.remaining().ok();
+        Some(PredictionDetails {
+            model: entry.model.kind(),
+            state: entry.model.state(),
+            covariance_diagonal: covariance_diagonal(entry.model.as_ref()),
+            last_innovation: entry.last_innovation,
+            cycle_start: entry.start_time,
+            last_received_time: entry.last_received_time,
+            update_count: entry.update_count,
+            rewet_count: entry.rewet_count,
+            remaining_minutes: remaining.map(|remaining| remaining.minutes),
+            dry: entry.model.is_dry(),
+            estimate: remaining.and_then(|remaining| self.estimate_completion(remaining, entry.model.driver(), &entry.last_received_time).ok()),
+        })
+    }
+
     fn estimate_drying_time(
         &self,
-        state_estimate: &[f64],
+        model: &dyn DryingModel,
         current_time: &DateTime<Utc>,
     ) -> Result<DateTime<Utc>, PredictorError> {
-        // state[3] is the CURRENT moisture M(t), not the initial M_0.
-        // We compute how much longer until M decays to M_c:
-        //   M(t) * exp(-k * t_remaining) = M_c
-        //   t_remaining = ln(M(t) / M_c) / k
-        let m = state_estimate[1]; // Current moisture (advances each EKF step)
-        let k = state_estimate[2];
-        let m_c = state_estimate[4];
+        minutes_after(current_time, model.remaining()?.minutes)
+    }
 
-        let t_remaining = -((m_c / m).ln() / k); // Remaining time until moisture reaches M_c
+    /// Turns the remaining time of `model` and its p10/p90 band into times after `current_time`.
+    fn estimate_completion(
+        &self,
+        remaining: RemainingTime,
+        model: ModelKind,
+        current_time: &DateTime<Utc>,
+    ) -> Result<CompletionEstimate, PredictorError> {
+        Ok(CompletionEstimate {
+            completion_time: minutes_after(current_time, remaining.minutes)?,
+            p10: minutes_after(current_time, remaining.p10)?,
+            p90: minutes_after(current_time, remaining.p90)?,
+            model,
+        })
+    }
 
-        if t_remaining.is_nan() || t_remaining < 0.0 {
-            return Err(PredictorError::InvalidPrediction);
+    /// Called after a device's configuration was updated. If the resolved EKF parameters differ
+    /// from the ones the cached filter was built with, the filter is either rebuilt straight away
+    /// (the stored readings are replayed with the new parameters, as after a restart without a
+    /// saved state) or left alone until the next cycle, which always starts from a filter built
+    /// with the current configuration.
+    pub async fn configuration_changed(&self, device_id: &str, apply: ApplyConfiguration) -> Result<(), PredictorError> {
+        let parameters = self.repo.get_ekf_parameters(device_id).await?;
+        let cached = self
+            .predictor_cache
+            .get(device_id)
+            .map(|entry| (entry.parameters == parameters, entry.start_time, entry.last_received_time));
+
+        match (apply, cached) {
+            (_, Some((true, _, _))) => {
+                println!("EKF parameters for device {} are unchanged", device_id);
+            }
+            (ApplyConfiguration::NextCycle, _) => {
+                println!("New EKF parameters for device {} will be used from its next cycle", device_id);
+            }
+            (ApplyConfiguration::Immediately, cached) => {
+                println!("Rebuilding the EKF for device {} with its new parameters", device_id);
+                self.predictor_cache.remove(device_id);
+                self.repo.delete_filter_state(device_id).await?;
+                // Without a cached filter the next reading replays the stored readings anyway.
+                if let Some((_, start_time, last_received_time)) = cached {
+                    // Only the current cycle's readings are replayed, and it keeps its start even
+                    // if the new parameters would place a new load somewhere in them: a
+                    // configuration change must not look like a new cycle to the cycle tracker
+                    // and the alerts.
+                    let history = self
+                        .repo
+                        .get_cycle_telemetry(device_id, last_received_time + chrono::Duration::milliseconds(1))
+                        .await?
+                        .into_iter()
+                        .filter(|reading| reading.timestamp >= start_time)
+                        .collect();
+                    self.replay(device_id, parameters, start_time, history).await?;
+                    if let Some(mut entry) = self.predictor_cache.get_mut(device_id) {
+                        entry.start_time = start_time;
+                    }
+                }
+            }
         }
-
-        let completion_time = *current_time + chrono::Duration::minutes(t_remaining as i64);
-
-        Ok(completion_time)
+        Ok(())
     }
 
+    /// Applies every configuration change notified on `CONFIGURATION_CHANNEL` with
+    /// `configuration_changed`, so changes are picked up whether they were made through the API,
+    /// by another server instance or directly in the database. Runs forever.
+    pub async fn listen_for_configuration_changes(self: std::sync::Arc<Self>, pool: PgPool) {
+        loop {
+            let mut listener = match sqlx::postgres::PgListener::connect_with(&pool).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("[configuration] Failed to connect the listener: {e}");
+                    tokio::time::sleep(CONFIGURATION_LISTENER_RETRY).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(CONFIGURATION_CHANNEL).await {
+                eprintln!("[configuration] Failed to listen on {CONFIGURATION_CHANNEL}: {e}");
+                tokio::time::sleep(CONFIGURATION_LISTENER_RETRY).await;
+                continue;
+            }
 
+            loop {
+                let notification = match listener.recv().await {
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        eprintln!("[configuration] Lost the configuration listener: {e}");
+                        tokio::time::sleep(CONFIGURATION_LISTENER_RETRY).await;
+                        break;
+                    }
+                };
+                let change = match serde_json::from_str::<ConfigurationChange>(notification.payload()) {
+                    Ok(change) => change,
+                    Err(e) => {
+                        eprintln!("[configuration] Ignoring malformed notification {:?}: {e}", notification.payload());
+                        continue;
+                    }
+                };
+                // The configuration is stored either way, so a failure here only delays the new
+                // parameters until the filter is next rebuilt.
+                if let Err(e) = self.configuration_changed(&change.device_id, change.apply).await {
+                    eprintln!("Unable to apply the new configuration to the EKF for device {}: {}", change.device_id, e);
+                }
+            }
+        }
+    }
 
-    fn reset_predictor(&self, device_id: &str) -> Result<(), PredictorError> {
+    /// Forgets the device's filter, including the saved copy, so the next reading starts afresh.
+    async fn reset_predictor(&self, device_id: &str) -> Result<(), PredictorError> {
         let output = self.predictor_cache.remove(device_id);
         if output.is_none() {
             eprintln!("No EKF entry found for device {} to reset", device_id);
             return Err(PredictorError::DeviceNotFound(device_id.to_string()));
         }
-        Ok(())
+        self.repo.delete_filter_state(device_id).await
     }
 
-    fn reset_old_predictors(&self, max_age: chrono::Duration) -> (){
+    /// Forgets the filters that have not had a reading for more than `max_age`, with their saved
+    /// copies.
+    async fn reset_old_predictors(&self, max_age: chrono::Duration) {
         let now = Utc::now();
-        self.predictor_cache.retain(|device_id, entry| {
-            let age = now - entry.last_received_time;
-            if age > max_age {
-                println!("Evicting EKF entry for device {} due to age ({} minutes)", device_id, age.num_minutes());
-                false // Remove this entry from the cache
-            } else {
-                true // Keep this entry in the cache
+        let stale: Vec<(String, chrono::Duration)> = self
+            .predictor_cache
+            .iter()
+            .map(|entry| (entry.key().clone(), now - entry.last_received_time))
+            .filter(|(_, age)| *age > max_age)
+            .collect();
+        for (device_id, age) in stale {
+            println!("Evicting EKF entry for device {} due to age ({} minutes)", device_id, age.num_minutes());
+            if let Err(e) = self.reset_predictor(&device_id).await {
+                eprintln!("Unable to reset the EKF for device {}: {}", device_id, e);
             }
-        });
+        }
+    }
+
+    /// Every `period`, forgets the filters that have not had a reading for `CYCLE_REPLAY_HOURS`,
+    /// so a device that comes back after days starts a new cycle. Runs forever.
+    pub async fn run_eviction(self: std::sync::Arc<Self>, period: std::time::Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.reset_old_predictors(chrono::Duration::hours(CYCLE_REPLAY_HOURS)).await;
+        }
     }
 
     fn _get_cache_size(&self) -> usize {
@@ -307,51 +1239,182 @@ impl<R: DeviceRepository> WashingPredictor<R> {
 
 }
 
+fn covariance_diagonal(model: &dyn DryingModel) -> Vec<f64> {
+    let n = model.state().len();
+    let covariance = model.covariance();
+    (0..n).map(|i| covariance[i * n + i]).collect()
+}
+
+/// `time` plus a (whole) number of minutes, failing instead of panicking when the filter
+/// produces an absurdly long remaining time.
+fn minutes_after(time: &DateTime<Utc>, minutes: f64) -> Result<DateTime<Utc>, PredictorError> {
+    chrono::Duration::try_minutes(minutes as i64)
+        .and_then(|duration| time.checked_add_signed(duration))
+        .ok_or(PredictorError::InvalidPrediction)
+}
+
 #[cfg(test)]
 mod tests {
 
-    use std::fmt::Debug;
-
-use super::*;
+    use super::*;
     use chrono::Utc;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
 
     // --- Mock ---
     //
-    // A unit struct (no fields) that implements DeviceRepository.
-    // It always returns a hardcoded set of EKF parameters, so tests never touch a database.
+    // A struct that implements DeviceRepository.
+    // It always returns a hardcoded set of EKF parameters, so tests never touch a database,
+    // and keeps saved filter states in a map that can be shared with a second predictor to
+    // simulate a server restart. `telemetry` stands in for the stored telemetry table.
     //
     // `async fn` in a trait impl works here because Rust 2024 has stable AFIT.
     // The compiler generates a concrete Future type for each implementation — there's
     // no boxing or heap allocation, unlike the `async_trait` crate approach.
-    struct MockDeviceRepository;
+    #[derive(Default)]
+    struct MockDeviceRepository {
+        saved_states: Arc<Mutex<HashMap<String, StoredFilterState>>>,
+        telemetry: Vec<TelemetryData>,
+        predictions: Mutex<Vec<PredictionRecord>>,
+        // Replaces `test_parameters`, to simulate a configuration update.
+        parameters: Mutex<Option<EKFParameters>>,
+    }
 
     impl DeviceRepository for MockDeviceRepository {
         async fn get_ekf_parameters(
             &self,
             _device_id: &str,
         ) -> Result<EKFParameters, PredictorError> {
-            Ok(EKFParameters {
-                initial_state: vec![30000.0, 0.02, 0.1, 0.81, 1e-9, 29976.33],
-                initial_covariance: vec![
-                    1.0e1, 0.0, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 1.0e-10, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 1.0e-6, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 1.0e-6, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                ],
-                process_noise_covariance: vec![
-                    1.0e-2, 0.0, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 1.0e-12, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 1.0e-8, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 1.0e-7, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                    0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
-                ],
-                measurement_noise_covariance: vec![1.0e6],
-                dt: 2.0,
-            })
+            if let Some(parameters) = self.parameters.lock().unwrap().clone() {
+                return Ok(parameters);
+            }
+            Ok(test_parameters(ModelKind::default()))
+        }
+
+        async fn load_filter_state(&self, device_id: &str) -> Result<Option<StoredFilterState>, PredictorError> {
+            Ok(self.saved_states.lock().unwrap().get(device_id).cloned())
+        }
+
+        async fn save_filter_state(&self, device_id: &str, state: &StoredFilterState) -> Result<(), PredictorError> {
+            self.saved_states.lock().unwrap().insert(device_id.to_string(), state.clone());
+            Ok(())
         }
+
+        async fn delete_filter_state(&self, device_id: &str) -> Result<(), PredictorError> {
+            self.saved_states.lock().unwrap().remove(device_id);
+            Ok(())
+        }
+
+        async fn get_cycle_telemetry(&self, _device_id: &str, before: DateTime<Utc>) -> Result<Vec<TelemetryData>, PredictorError> {
+            Ok(self.telemetry.iter().filter(|reading| reading.timestamp < before).cloned().collect())
+        }
+
+        async fn record_prediction(&self, record: &PredictionRecord) -> Result<(), PredictorError> {
+            self.predictions.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_resolve_ekf_parameters_from_preset() {
+        let preset = serde_json::json!({
+            "initial_state": [30000.0, 0.02, 0.1, 0.81, 1e-9, 29976.33],
+            "initial_covariance": vec![0.0; 36],
+            "process_noise_covariance": vec![0.0; 36],
+            "measurement_noise_covariance": [1.0e6],
+            "dt": 2.0,
+        });
+
+        // No overrides: the preset as is.
+        let parameters = resolve_ekf_parameters(Some(&preset), None).unwrap();
+        assert_eq!(parameters.initial_state[2], 0.1);
+
+        // Overridden fields replace the preset's, the rest is kept.
+        let overrides = serde_json::json!({ "measurement_noise_covariance": [4.0e6], "dt": 1.0 });
+        let parameters = resolve_ekf_parameters(Some(&preset), Some(&overrides)).unwrap();
+        assert_eq!(parameters.measurement_noise_covariance, vec![4.0e6]);
+        assert_eq!(parameters.dt, 1.0);
+        assert_eq!(parameters.initial_state[1], 0.02);
+
+        // A complete configuration works without a preset, a partial one does not.
+        assert!(resolve_ekf_parameters(None, Some(&preset)).is_ok());
+        assert!(resolve_ekf_parameters(None, Some(&overrides)).is_err());
+        assert!(resolve_ekf_parameters(None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_parameters_are_valid() {
+        let parameters = MockDeviceRepository::default().get_ekf_parameters("wash-1").await.unwrap();
+        assert_eq!(parameters.validate(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn test_validate_rejects_bad_parameters() {
+        let valid = MockDeviceRepository::default().get_ekf_parameters("wash-1").await.unwrap();
+        let fields = |parameters: EKFParameters| -> Vec<String> {
+            parameters.validate().into_iter().map(|error| error.field).collect()
+        };
+
+        let mut short_state = valid.clone();
+        short_state.initial_state.truncate(5);
+        assert_eq!(fields(short_state), vec!["initial_state"]);
+
+        let mut negative_k = valid.clone();
+        negative_k.initial_state[2] = -0.1;
+        assert_eq!(fields(negative_k), vec!["initial_state[2]"]);
+
+        let mut short_covariance = valid.clone();
+        short_covariance.initial_covariance.pop();
+        assert_eq!(fields(short_covariance), vec!["initial_covariance"]);
+
+        // Correlation above 1 between R and M.
+        let mut not_psd = valid.clone();
+        not_psd.process_noise_covariance[1] = 1.0e-5;
+        not_psd.process_noise_covariance[6] = 1.0e-5;
+        assert_eq!(fields(not_psd), vec!["process_noise_covariance"]);
+
+        let mut asymmetric = valid.clone();
+        asymmetric.initial_covariance[1] = 1.0e-6;
+        assert_eq!(fields(asymmetric), vec!["initial_covariance"]);
+
+        let mut bad_noise_and_dt = valid.clone();
+        bad_noise_and_dt.measurement_noise_covariance = vec![0.0];
+        bad_noise_and_dt.dt = 0.0;
+        assert_eq!(fields(bad_noise_and_dt), vec!["measurement_noise_covariance", "dt"]);
+
+        let mut few_particles = valid.clone();
+        few_particles.particle_count = 1;
+        assert_eq!(fields(few_particles), vec!["particle_count"]);
+
+        let mut nested_ensemble = valid.clone();
+        nested_ensemble.ensemble_models = vec![ModelKind::Ekf, ModelKind::Ensemble, ModelKind::Ekf];
+        assert_eq!(fields(nested_ensemble), vec!["ensemble_models[1]", "ensemble_models[2]"]);
+
+        // A valid correlation between R and M is accepted.
+        let mut correlated = valid.clone();
+        correlated.initial_covariance[1] = 1.0e-5;
+        correlated.initial_covariance[6] = 1.0e-5;
+        assert_eq!(fields(correlated), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_validate_configuration() {
+        let preset = serde_json::to_value(MockDeviceRepository::default().get_ekf_parameters("wash-1").await.unwrap()).unwrap();
+
+        // An empty configuration uses the (default) preset.
+        assert!(validate_configuration(&serde_json::json!({}), Some(&preset)).is_empty());
+
+        let errors = validate_configuration(&serde_json::json!({ "preset": "silk" }), None);
+        assert_eq!(errors[0].field, "configuration.preset");
+
+        let errors = validate_configuration(&serde_json::json!({ "configuration": { "dt": -1.0 } }), Some(&preset));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "configuration.configuration.dt");
+
+        let errors = validate_configuration(&serde_json::json!({ "configuration": { "initial_state": "wet" } }), Some(&preset));
+        assert_eq!(errors[0].field, "configuration.configuration");
+
+        assert_eq!(validate_configuration(&serde_json::json!([]), Some(&preset))[0].field, "configuration");
     }
 
     #[tokio::test]
@@ -362,7 +1425,7 @@ use super::*;
         };
         // WashingPredictor<MockDeviceRepository> — no database connection needed.
         // The type parameter R is inferred by the compiler from what we pass to ::new().
-        let kf = WashingPredictor::new(MockDeviceRepository);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
 
         let res = kf.predict_drying_time("dootle", dooter).await;
         println!("Prediction result: {:?}", res);
@@ -376,13 +1439,26 @@ use super::*;
         let res2 = kf.predict_drying_time("dootle", telemetry_data_2).await;
         println!("Prediction result after second telemetry: {:?}", res2);
         assert!(res2.is_ok());
-        assert_ne!(res2.unwrap(), res.unwrap()); // The second prediction should indicate a sooner completion time due to the rapid increase in resistance
+        assert_ne!(res2.unwrap().estimate.completion_time, res.unwrap().estimate.completion_time); // The second prediction should indicate a sooner completion time due to the rapid increase in resistance
     }
 
 
+    #[tokio::test]
+    async fn test_cycle_start_is_kept_to_the_microsecond() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        let start = DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap();
+
+        let first = kf.predict_drying_time("wash-1", TelemetryData { timestamp: start, resistance: 30000.0 }).await.unwrap();
+        let stored = DateTime::from_timestamp(1_700_000_000, 123_456_000).unwrap();
+        assert_eq!(first.cycle_start, stored);
+
+        let second = TelemetryData { timestamp: start + chrono::Duration::minutes(2), resistance: 30500.0 };
+        assert_eq!(kf.predict_drying_time("wash-1", second).await.unwrap().cycle_start, stored);
+    }
+
     #[tokio::test]
     async fn test_evict_on_large_jump() {
-        let kf = WashingPredictor::new(MockDeviceRepository);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
 
         let telemetry_data_1 = TelemetryData {
             timestamp: Utc::now(),
@@ -417,8 +1493,9 @@ use super::*;
 
         // Resistance drops back to 30 kΩ — a jump of ~366 kΩ — simulating new wet
         // clothes being hung up. This should evict the existing EKF and create a fresh one.
+        // The reading has to come after the last one, older readings are rejected as stale.
         let telemetry_data_2 = TelemetryData {
-            timestamp: Utc::now(),
+            timestamp: Utc::now() + chrono::Duration::minutes(120),
             resistance: 30000.0,
         };
 
@@ -429,9 +1506,125 @@ use super::*;
         assert!(res2.is_ok());
     }
 
+    /// Readings of a slowly drying load, one every 2 minutes.
+    fn drying_readings(start: DateTime<Utc>, count: i64) -> Vec<TelemetryData> {
+        (0..count)
+            .map(|i| TelemetryData {
+                timestamp: start + chrono::Duration::minutes(2 * i),
+                resistance: 30000.0 + 500.0 * i as f64,
+            })
+            .collect()
+    }
+
+    async fn noisier_parameters(repo: &MockDeviceRepository) -> EKFParameters {
+        let mut parameters = repo.get_ekf_parameters("wash-1").await.unwrap();
+        parameters.measurement_noise_covariance = vec![4.0e6];
+        parameters
+    }
+
+    #[test]
+    fn test_configuration_change_payload() {
+        let change: ConfigurationChange = serde_json::from_str(r#"{"device_id": "wash-1", "apply": "next_cycle"}"#).unwrap();
+        assert_eq!(change, ConfigurationChange { device_id: "wash-1".to_string(), apply: ApplyConfiguration::NextCycle });
+        // A change made straight in the database carries no flag.
+        let change: ConfigurationChange = serde_json::from_str(r#"{"device_id": "wash-1"}"#).unwrap();
+        assert_eq!(change.apply, ApplyConfiguration::Immediately);
+        assert_eq!(ApplyConfiguration::NextCycle.as_str(), "next_cycle");
+    }
+
+    #[tokio::test]
+    async fn test_configuration_change_rebuilds_filter_immediately() {
+        let readings = drying_readings(Utc::now() - chrono::Duration::hours(1), 10);
+        let kf = WashingPredictor::new(MockDeviceRepository { telemetry: readings.clone(), ..Default::default() });
+        for reading in readings.iter().cloned() {
+            kf.predict_drying_time("wash-1", reading).await.unwrap();
+        }
+        let start_time = kf.predictor_cache.get("wash-1").unwrap().start_time;
+
+        let parameters = noisier_parameters(&kf.repo).await;
+        *kf.repo.parameters.lock().unwrap() = Some(parameters.clone());
+        kf.configuration_changed("wash-1", ApplyConfiguration::Immediately).await.unwrap();
+
+        // The filter was rebuilt from all of the cycle's readings with the new parameters.
+        let entry = kf.predictor_cache.get("wash-1").unwrap();
+        assert_eq!(entry.parameters, parameters);
+        assert_eq!(entry.start_time, start_time);
+        assert_eq!(entry.update_count, 10);
+        assert_eq!(entry.last_received_time, readings[9].timestamp);
+        drop(entry);
+        assert!(!kf.repo.saved_states.lock().unwrap().contains_key("wash-1"));
+    }
+
+    #[tokio::test]
+    async fn test_configuration_change_keeps_the_cycle_start() {
+        // Readings of an earlier load are still within the replay window, and differ too little
+        // from the current cycle's for a replay to see a new load between them. The current
+        // cycle started on a fresh filter, as after the earlier load's filter was aged out.
+        let earlier = drying_readings(Utc::now() - chrono::Duration::hours(5), 5);
+        let current = drying_readings((Utc::now() - chrono::Duration::hours(1)).trunc_subsecs(6), 10);
+        let telemetry = earlier.iter().chain(&current).cloned().collect();
+        let kf = WashingPredictor::new(MockDeviceRepository { telemetry, ..Default::default() });
+        let parameters = kf.repo.get_ekf_parameters("wash-1").await.unwrap();
+        let entry = kf.build_entry("wash-1", parameters, current[0].timestamp).unwrap();
+        kf.predictor_cache.insert("wash-1".to_string(), entry);
+        for reading in current.iter().cloned() {
+            kf.predict_drying_time("wash-1", reading).await.unwrap();
+        }
+
+        *kf.repo.parameters.lock().unwrap() = Some(noisier_parameters(&kf.repo).await);
+        kf.configuration_changed("wash-1", ApplyConfiguration::Immediately).await.unwrap();
+
+        let entry = kf.predictor_cache.get("wash-1").unwrap();
+        assert_eq!(entry.start_time, current[0].timestamp);
+        assert_eq!(entry.update_count, 10);
+    }
+
+    #[tokio::test]
+    async fn test_configuration_change_waits_for_next_cycle() {
+        // Old enough that a drop is a new load rather than rain.
+        let start = Utc::now() - chrono::Duration::hours(10);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        for reading in drying_readings(start, 5) {
+            kf.predict_drying_time("wash-1", reading).await.unwrap();
+        }
+        let original = kf.predictor_cache.get("wash-1").unwrap().parameters.clone();
+
+        let parameters = noisier_parameters(&kf.repo).await;
+        *kf.repo.parameters.lock().unwrap() = Some(parameters.clone());
+        kf.configuration_changed("wash-1", ApplyConfiguration::NextCycle).await.unwrap();
+        assert_eq!(kf.predictor_cache.get("wash-1").unwrap().parameters, original);
+        assert!(kf.repo.saved_states.lock().unwrap().contains_key("wash-1"));
+
+        // The load dries, then a new wet load is hung: the filter is reset and picks up the new
+        // parameters.
+        {
+            let mut entry = kf.predictor_cache.get_mut("wash-1").unwrap();
+            let (mut state, covariance) = (entry.model.state(), entry.model.covariance());
+            state[0] = 2.0e6;
+            assert!(entry.model.restore(&state, &covariance));
+        }
+        let new_load = TelemetryData { timestamp: start + chrono::Duration::hours(9), resistance: 30000.0 };
+        let prediction = kf.predict_drying_time("wash-1", new_load).await.unwrap();
+        assert_eq!(prediction.event, CycleEvent::NewCycle);
+        assert_eq!(kf.predictor_cache.get("wash-1").unwrap().parameters, parameters);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_configuration_keeps_filter() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        for reading in drying_readings(Utc::now() - chrono::Duration::hours(1), 5) {
+            kf.predict_drying_time("wash-1", reading).await.unwrap();
+        }
+        let state = kf.predictor_cache.get("wash-1").unwrap().model.state();
+
+        kf.configuration_changed("wash-1", ApplyConfiguration::Immediately).await.unwrap();
+        assert_eq!(kf.predictor_cache.get("wash-1").unwrap().model.state(), state);
+        assert!(kf.repo.saved_states.lock().unwrap().contains_key("wash-1"));
+    }
+
     #[tokio::test]
     async fn test_reset_predictor() {
-        let kf = WashingPredictor::new(MockDeviceRepository);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
 
         let telemetry_data = TelemetryData {
             timestamp: Utc::now(),
@@ -447,7 +1640,7 @@ use super::*;
 
         assert!(res.is_ok());
 
-        kf.reset_predictor("wash-1").unwrap();
+        kf.reset_predictor("wash-1").await.unwrap();
         assert_eq!(kf._get_cache_size(), 0); // Cache should start empty
 
         let telemetry_data = TelemetryData {
@@ -457,13 +1650,15 @@ use super::*;
 
         let res_after_reset = kf.predict_drying_time("wash-1", telemetry_data).await;
         println!("Prediction result after reset: {:?}", res_after_reset);
-        assert!(res_after_reset.unwrap() > res.unwrap());
+        let (res_after_reset, res) = (res_after_reset.unwrap(), res.unwrap());
+        assert!(res_after_reset.estimate.completion_time > res.estimate.completion_time);
+        assert!(res_after_reset.cycle_start > res.cycle_start); // A fresh filter starts a new cycle
 
     }
 
     #[tokio::test]
     async fn test_reset_old_predictors() {
-        let kf = WashingPredictor::new(MockDeviceRepository);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
 
         let telemetry_data = TelemetryData {
             timestamp: Utc::now() - chrono::Duration::minutes(10),
@@ -478,7 +1673,7 @@ use super::*;
         assert!(res.is_ok());
         assert_eq!(kf._get_cache_size(), 1); // Cache should have one entry after prediction
 
-        kf.reset_old_predictors(chrono::Duration::minutes(5));
+        kf.reset_old_predictors(chrono::Duration::minutes(5)).await;
         assert_eq!(kf._get_cache_size(), 0); // Cache should be empty after resetting old predictors
 
         let telemetry_data = TelemetryData {
@@ -488,9 +1683,313 @@ use super::*;
         let res_after_reset = kf.predict_drying_time("wash-2", telemetry_data).await;
         println!("Prediction result after resetting old predictors: {:?}", res_after_reset);
         assert!(res_after_reset.is_ok());
-        assert!(res_after_reset.unwrap() > res.unwrap());
+        assert!(res_after_reset.unwrap().estimate.completion_time > res.unwrap().estimate.completion_time);
 
     }
 
+    // Resistance following R(t) = (M_0 * exp(-k*t) - M_C)^(-tau) + R_0 with the mock parameters.
+    fn model_resistance(t: f64) -> f64 {
+        (0.02_f64 * (-0.1_f64 * t).exp() - 1e-9_f64)
+            .max(1e-9_f64)
+            .powf(-0.81_f64)
+            + 29976.33_f64
+    }
 
+    // Feeds 59 readings two minutes apart (ending around 396 kOhm) and returns the cycle start.
+    async fn dry_for_two_hours(kf: &WashingPredictor<MockDeviceRepository>, device_id: &str, start: DateTime<Utc>) -> DateTime<Utc> {
+        let first = kf
+            .predict_drying_time(device_id, TelemetryData { timestamp: start, resistance: 30000.0 })
+            .await
+            .unwrap();
+        for i in 1..60 {
+            let telemetry_data = TelemetryData {
+                timestamp: start + chrono::Duration::minutes(2 * i),
+                resistance: model_resistance(2.0 * i as f64),
+            };
+            kf.predict_drying_time(device_id, telemetry_data).await.unwrap();
+        }
+        first.cycle_start
+    }
+
+    #[tokio::test]
+    async fn test_sustained_drop_is_rewetting() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        let start = Utc::now();
+        let cycle_start = dry_for_two_hours(&kf, "rain-1", start).await;
+
+        // Rain: the resistance falls back to wet levels and stays there.
+        let first_drop = kf
+            .predict_drying_time("rain-1", TelemetryData { timestamp: start + chrono::Duration::minutes(120), resistance: 30500.0 })
+            .await
+            .unwrap();
+        assert_eq!(first_drop.event, CycleEvent::SuspectedRewetting);
+
+        let confirmed = kf
+            .predict_drying_time("rain-1", TelemetryData { timestamp: start + chrono::Duration::minutes(122), resistance: 30200.0 })
+            .await
+            .unwrap();
+        assert_eq!(confirmed.event, CycleEvent::Rewetting);
+        assert_eq!(confirmed.cycle_start, cycle_start); // The cycle history is kept
+        assert!(confirmed.estimate.completion_time > first_drop.estimate.completion_time); // and the washing has to dry again
+        assert_eq!(kf.predictor_cache.get("rain-1").unwrap().rewet_count, 1);
     }
+
+    #[tokio::test]
+    async fn test_single_low_reading_is_ignored() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        let start = Utc::now();
+        let cycle_start = dry_for_two_hours(&kf, "glitch-1", start).await;
+
+        let glitch = kf
+            .predict_drying_time("glitch-1", TelemetryData { timestamp: start + chrono::Duration::minutes(120), resistance: 30500.0 })
+            .await
+            .unwrap();
+        assert_eq!(glitch.event, CycleEvent::SuspectedRewetting);
+
+        let recovered = kf
+            .predict_drying_time("glitch-1", TelemetryData { timestamp: start + chrono::Duration::minutes(122), resistance: model_resistance(122.0) })
+            .await
+            .unwrap();
+        assert_eq!(recovered.event, CycleEvent::Continuing);
+        assert_eq!(recovered.cycle_start, cycle_start);
+        assert_eq!(kf.predictor_cache.get("glitch-1").unwrap().rewet_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_filter_state_survives_restart() {
+        let start = (Utc::now() - chrono::Duration::hours(3)).trunc_subsecs(6);
+        let repo = MockDeviceRepository::default();
+        let saved_states = repo.saved_states.clone();
+        let kf = WashingPredictor::new(repo);
+        dry_for_two_hours(&kf, "wash-1", start).await;
+
+        // A second predictor sharing the same storage stands in for the restarted server.
+        let restarted = WashingPredictor::new(MockDeviceRepository { saved_states, ..Default::default() });
+        let reading = || TelemetryData {
+            timestamp: start + chrono::Duration::minutes(120),
+            resistance: model_resistance(120.0),
+        };
+        // The restarted server goes first, since the original one saves over the shared state.
+        let restored = restarted.predict_drying_time("wash-1", reading()).await.unwrap();
+        let continued = kf.predict_drying_time("wash-1", reading()).await.unwrap();
+
+        assert_eq!(restored.cycle_start, start);
+        assert_eq!(restored.event, CycleEvent::Continuing);
+        assert_eq!(restored.estimate.completion_time, continued.estimate.completion_time);
+    }
+
+    #[tokio::test]
+    async fn test_stale_saved_state_is_not_restored() {
+        let start = (Utc::now() - chrono::Duration::days(2)).trunc_subsecs(6);
+        let saved_states = Arc::new(Mutex::new(HashMap::new()));
+        let kf = WashingPredictor::new(MockDeviceRepository { saved_states: saved_states.clone(), ..Default::default() });
+        dry_for_two_hours(&kf, "wash-1", start).await;
+
+        // The device comes back two days later, after a restart.
+        let restarted = WashingPredictor::new(MockDeviceRepository { saved_states, ..Default::default() });
+        let now = Utc::now().trunc_subsecs(6);
+        let prediction = restarted
+            .predict_drying_time("wash-1", TelemetryData { timestamp: now, resistance: 30000.0 })
+            .await
+            .unwrap();
+        assert_eq!(prediction.cycle_start, now);
+        assert_eq!(restarted.repo.saved_states.lock().unwrap()["wash-1"].start_time, now);
+    }
+
+    #[tokio::test]
+    async fn test_replays_stored_telemetry_on_cache_miss() {
+        let start = (Utc::now() - chrono::Duration::hours(3)).trunc_subsecs(6);
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        dry_for_two_hours(&kf, "wash-1", start).await;
+
+        // No saved filter state, only the telemetry table, as after an eviction or a lost row.
+        let telemetry: Vec<TelemetryData> = (0..60)
+            .map(|i| TelemetryData {
+                timestamp: start + chrono::Duration::minutes(2 * i),
+                resistance: if i == 0 { 30000.0 } else { model_resistance(2.0 * i as f64) },
+            })
+            .collect();
+        let replayed = WashingPredictor::new(MockDeviceRepository { telemetry, ..Default::default() });
+
+        let reading = || TelemetryData {
+            timestamp: start + chrono::Duration::minutes(120),
+            resistance: model_resistance(120.0),
+        };
+        let warm = replayed.predict_drying_time("wash-1", reading()).await.unwrap();
+        let continued = kf.predict_drying_time("wash-1", reading()).await.unwrap();
+
+        assert_eq!(warm.cycle_start, start);
+        assert_eq!(warm.estimate, continued.estimate);
+    }
+
+    #[tokio::test]
+    async fn test_time_step_follows_reading_gaps() {
+        let start = Utc::now() - chrono::Duration::hours(3);
+        let regular = WashingPredictor::new(MockDeviceRepository::default());
+        let sparse = WashingPredictor::new(MockDeviceRepository::default());
+
+        // One device reports every 2 minutes, the other every 10 minutes. Both see the same
+        // drying curve, so the sparse one should end up with much the same estimate.
+        let mut regular_prediction = None;
+        let mut sparse_prediction = None;
+        for i in 0..=30 {
+            let t = 4.0 * i as f64;
+            let reading = TelemetryData {
+                timestamp: start + chrono::Duration::minutes(4 * i),
+                resistance: if i == 0 { 30000.0 } else { model_resistance(t) },
+            };
+            regular_prediction = Some(regular.predict_drying_time("wash-1", reading.clone()).await.unwrap());
+            if i % 5 == 0 {
+                sparse_prediction = Some(sparse.predict_drying_time("wash-1", reading).await.unwrap());
+            }
+        }
+
+        let (regular_prediction, sparse_prediction) = (regular_prediction.unwrap(), sparse_prediction.unwrap());
+        let difference = (regular_prediction.estimate.completion_time - sparse_prediction.estimate.completion_time).num_minutes().abs();
+        assert!(difference <= 10, "estimates differ by {} minutes", difference);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_and_repeated_readings_are_rejected() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        let start = Utc::now();
+        let reading = |minutes: i64| TelemetryData {
+            timestamp: start + chrono::Duration::minutes(minutes),
+            resistance: model_resistance(minutes as f64),
+        };
+
+        kf.predict_drying_time("wash-1", reading(0)).await.unwrap();
+        let before = kf.predict_drying_time("wash-1", reading(10)).await.unwrap();
+
+        let late = kf.predict_drying_time("wash-1", reading(4)).await;
+        assert!(matches!(late, Err(PredictorError::StaleReading { .. })));
+        let repeated = kf.predict_drying_time("wash-1", reading(10)).await;
+        assert!(matches!(repeated, Err(PredictorError::StaleReading { .. })));
+
+        // The rejected readings left the filter untouched.
+        assert_eq!(kf.get_estimated_completion_time("wash-1"), Some(before.estimate.completion_time));
+    }
+
+    #[tokio::test]
+    async fn test_completion_band_surrounds_estimate() {
+        let start = Utc::now() - chrono::Duration::hours(3);
+        let fresh = WashingPredictor::new(MockDeviceRepository::default());
+        let first = fresh
+            .predict_drying_time("wash-1", TelemetryData { timestamp: start, resistance: 30000.0 })
+            .await
+            .unwrap()
+            .estimate;
+        assert!(first.p10 < first.completion_time && first.completion_time < first.p90);
+
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        dry_for_two_hours(&kf, "wash-1", start).await;
+        let later = kf.get_prediction_details("wash-1").unwrap().estimate.unwrap();
+        assert!(later.p10 < later.completion_time && later.completion_time < later.p90);
+    }
+
+    #[tokio::test]
+    async fn test_prediction_details() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        assert!(kf.get_prediction_details("wash-1").is_none());
+
+        let start = (Utc::now() - chrono::Duration::hours(3)).trunc_subsecs(6);
+        dry_for_two_hours(&kf, "wash-1", start).await;
+        let details = kf.get_prediction_details("wash-1").unwrap();
+
+        assert_eq!(details.state.len(), 6);
+        assert_eq!(details.covariance_diagonal.len(), 6);
+        assert!(details.covariance_diagonal.iter().all(|variance| *variance >= 0.0));
+        assert!(details.last_innovation.is_some());
+        assert_eq!(details.cycle_start, start);
+        assert_eq!(details.last_received_time, start + chrono::Duration::minutes(118));
+        assert_eq!(details.update_count, 60);
+        let remaining = details.remaining_minutes.unwrap();
+        let estimate = details.estimate.unwrap();
+        assert_eq!(estimate.completion_time, details.last_received_time + chrono::Duration::minutes(remaining as i64));
+    }
+
+    #[tokio::test]
+    async fn test_forecast_runs_until_completion() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        assert!(kf.get_forecast("wash-1", None).is_none());
+
+        let start = Utc::now() - chrono::Duration::hours(3);
+        dry_for_two_hours(&kf, "wash-1", start).await;
+        let details = kf.get_prediction_details("wash-1").unwrap();
+        let forecast = kf.get_forecast("wash-1", None).unwrap();
+
+        assert_eq!(forecast.from, details.last_received_time);
+        let first = forecast.points.first().unwrap();
+        let last = forecast.points.last().unwrap();
+        assert_eq!(first.resistance, details.state[0]);
+        assert!((last.minutes - details.remaining_minutes.unwrap()).abs() < 1e-6);
+        // The washing dries along the way: moisture falls, resistance rises, bands widen.
+        assert!(last.moisture < first.moisture);
+        assert!(last.resistance > first.resistance);
+        assert!(last.resistance_p90 - last.resistance_p10 > first.resistance_p90 - first.resistance_p10);
+        for point in &forecast.points {
+            assert!(point.resistance_p10 <= point.resistance && point.resistance <= point.resistance_p90);
+        }
+
+        let short = kf.get_forecast("wash-1", Some(10.0)).unwrap();
+        assert_eq!(short.points.len(), 6); // 0, 2, 4, 6, 8 and 10 minutes with dt = 2
+    }
+
+    #[tokio::test]
+    async fn test_every_prediction_is_recorded() {
+        let kf = WashingPredictor::new(MockDeviceRepository::default());
+        let start = (Utc::now() - chrono::Duration::hours(3)).trunc_subsecs(6);
+        dry_for_two_hours(&kf, "wash-1", start).await;
+
+        let predictions = kf.repo.predictions.lock().unwrap();
+        assert_eq!(predictions.len(), 60);
+        assert_eq!(predictions[0].reading_time, start);
+        assert_eq!(predictions[0].resistance, 30000.0);
+        assert_eq!(predictions[59].reading_time, start + chrono::Duration::minutes(118));
+        assert!(predictions.iter().all(|record| record.cycle_start == start && record.state.len() == 6));
+
+        let details = kf.get_prediction_details("wash-1").unwrap();
+        assert_eq!(predictions[59].state, details.state);
+        assert_eq!(Some(predictions[59].estimate), details.estimate);
+    }
+
+    #[tokio::test]
+    async fn test_plateau_model_reports_dry_once_stable() {
+        let repo = MockDeviceRepository::default();
+        let mut parameters = repo.get_ekf_parameters("wash-1").await.unwrap();
+        parameters.model = ModelKind::Plateau;
+        *repo.parameters.lock().unwrap() = Some(parameters);
+        let kf = WashingPredictor::new(repo);
+        let start = (Utc::now() - chrono::Duration::hours(3)).trunc_subsecs(6);
+
+        // While the resistance is still rising the heuristic has no estimate.
+        for reading in drying_readings(start, 10) {
+            let result = kf.predict_drying_time("wash-1", reading).await;
+            assert!(matches!(result, Err(PredictorError::NoEstimate)));
+        }
+        let details = kf.get_prediction_details("wash-1").unwrap();
+        assert_eq!(details.model, ModelKind::Plateau);
+        assert!(details.state.is_empty());
+        assert_eq!(details.remaining_minutes, None);
+        assert_eq!(details.update_count, 10);
+        assert!(kf.get_forecast("wash-1", None).is_none());
+
+        // Once the resistance levels off the washing is dry as of the latest reading.
+        let flat = |i: i64| TelemetryData {
+            timestamp: start + chrono::Duration::minutes(20 + 2 * i),
+            resistance: 34500.0,
+        };
+        let first = kf.predict_drying_time("wash-1", flat(0)).await;
+        assert!(matches!(first, Err(PredictorError::NoEstimate)));
+        let mut last = None;
+        for i in 1..10 {
+            last = kf.predict_drying_time("wash-1", flat(i)).await.ok();
+        }
+        let prediction = last.unwrap();
+        assert_eq!(prediction.event, CycleEvent::Continuing);
+        assert_eq!(prediction.cycle_start, start);
+        assert_eq!(prediction.estimate.completion_time, flat(9).timestamp);
+        assert_eq!(prediction.estimate.p90, flat(9).timestamp);
+        assert_eq!(kf.repo.predictions.lock().unwrap().last().unwrap().model, ModelKind::Plateau);
+    }
+}